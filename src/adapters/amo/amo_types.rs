@@ -1,8 +1,7 @@
+use crate::adapters::mailer::data_types::DealCard;
 use chrono::NaiveDateTime;
 use serde::Deserialize;
 use std::fmt::{Display, Formatter};
-use std::ops::Add;
-use std::time::Duration;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Leads {
@@ -31,10 +30,56 @@ pub struct Lead {
     pub id: u64,
     pub name: String,
     pub created_at: i64,
+    #[serde(default)]
+    pub responsible_user_id: i64,
     pub custom_fields_values: Vec<CustomField>,
+    /// Filled with `with=contacts`
+    #[serde(default)]
+    pub _embedded: LeadEmbedded,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct LeadEmbedded {
+    #[serde(default)]
+    pub contacts: Vec<LeadContact>,
+}
+
+/// Only the id comes with the lead, names are read from `/contacts`
+#[derive(Deserialize, Debug, Clone)]
+pub struct LeadContact {
+    pub id: u64,
+    #[serde(default)]
+    pub is_main: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Contacts {
+    pub _embedded: ContactsEmbedded,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContactsEmbedded {
+    pub contacts: Vec<Contact>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Contact {
+    pub id: u64,
+    #[serde(default)]
+    pub name: String,
 }
 
 impl Lead {
+    /// The buyer is the main contact of the lead
+    pub fn main_contact(&self) -> Option<u64> {
+        let contacts = &self._embedded.contacts;
+        contacts
+            .iter()
+            .find(|c| c.is_main)
+            .or(contacts.first())
+            .map(|c| c.id)
+    }
+
     pub fn val_to_str(&self, field_name: &str) -> String {
         let field_opt = self
             .custom_fields_values
@@ -95,7 +140,7 @@ impl From<FlexibleType> for String {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Deal {
     pub deal_id: u64,
     pub project: String,
@@ -105,28 +150,32 @@ pub struct Deal {
     pub facing: String,
     pub days_limit: i32,
     pub created_on: NaiveDateTime,
+    pub responsible_id: i64,
+    /// `days_limit` comes from the rules table, AmoCRM had no value
+    pub default_limit: bool,
+    /// Name of the main contact, empty when the lead has none
+    pub buyer: String,
 }
 
 impl Display for Deal {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let facing = if self.property_type.eq("property") {
-            format!("Тип отделки: {}\n", self.facing)
-        } else {
-            "".to_string()
-        };
-        write!(
-            f,
-            "Сделка: {}\nПроект: {}\n{}\n{} № {}\n{}Дата регистрации: {}\nПередать объект до: {}\n",
-            self.deal_id,
-            self.project,
-            self.house,
-            self.property_type,
-            self.property_num,
-            facing,
-            self.created_on.format("%d.%m.%Y"),
-            self.created_on
-                .add(Duration::from_secs(86400 * self.days_limit as u64))
-                .format("%d.%m.%Y")
-        )
+        writeln!(f, "{}", DealCard::new(self.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_main_contact() {
+        let lead = r#"{"id": 1, "name": "", "created_at": 0, "custom_fields_values": [],
+            "_embedded": {"contacts": [{"id": 5, "is_main": false}, {"id": 7, "is_main": true}]}}"#;
+        let lead: Lead = serde_json::from_str(lead).unwrap();
+        assert_eq!(lead.main_contact(), Some(7));
+
+        let lead = r#"{"id": 1, "name": "", "created_at": 0, "custom_fields_values": []}"#;
+        let lead: Lead = serde_json::from_str(lead).unwrap();
+        assert_eq!(lead.main_contact(), None);
     }
 }
@@ -1,15 +1,20 @@
 use crate::adapters::amo::amo_types::FlexibleType::Str;
-use crate::adapters::amo::amo_types::{CustomField, Deal, Leads, Val};
+use crate::adapters::amo::amo_types::{Contacts, CustomField, Deal, Leads, Val};
 pub(crate) use crate::adapters::amo::error::{Error, Result};
 use crate::bot_interface::PROJECTS;
+use crate::clock::{from_timestamp, to_local};
 use crate::config::config;
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
-use log::{debug, info};
+use crate::model::rules::Rules;
+use log::{debug, error, info};
 use reqwest::{Client, StatusCode};
+use std::collections::HashMap;
 
 pub mod amo_types;
 mod error;
 
+/// Contacts read by one `/contacts` request, the API limit is 250
+const CONTACTS_PAGE: usize = 250;
+
 pub struct AmoClient {
     account_id: &'static str,
     token: &'static str,
@@ -27,9 +32,13 @@ impl AmoClient {
     fn base_url(&self) -> String {
         format!("https://{}.amocrm.ru/api/v4/", self.account_id)
     }
-    pub(crate) async fn get_funnel_leads(&self, funnel_id: i64) -> Result<Vec<Deal>> {
+    pub(crate) async fn get_funnel_leads(
+        &self,
+        funnel_id: i64,
+        rules: &Rules,
+    ) -> Result<Vec<Deal>> {
         let url = format!(
-            "{}leads?filter[statuses][0][pipeline_id]={}&filter[statuses][0][status_id]={}",
+            "{}leads?with=contacts&filter[statuses][0][pipeline_id]={}&filter[statuses][0][status_id]={}",
             self.base_url(),
             self.pipeline_id(),
             funnel_id
@@ -44,7 +53,7 @@ impl AmoClient {
         }
         let mut data = response.json::<Leads>().await?;
         let mut next = data._links.next.take();
-        let mut leads = self.extract_dkp_deals(data);
+        let mut leads = self.extract_dkp_deals(data, rules);
 
         while next.is_some() {
             let url = next.take().unwrap().href;
@@ -59,7 +68,7 @@ impl AmoClient {
                 StatusCode::OK => {
                     let mut data = response.json::<Leads>().await?;
                     next = data._links.next.take();
-                    let leads_in_while = self.extract_dkp_deals(data);
+                    let leads_in_while = self.extract_dkp_deals(data, rules);
                     leads.extend(leads_in_while);
                 }
                 StatusCode::NO_CONTENT => {
@@ -73,9 +82,60 @@ impl AmoClient {
                 }
             }
         }
-        Ok(leads)
+
+        let contact_ids: Vec<u64> = leads.iter().filter_map(|(_, c)| *c).collect();
+        // the act is printed with a blank buyer line rather than the sync failing
+        let names = self
+            .get_contact_names(&contact_ids)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch lead contacts: {e}");
+                HashMap::new()
+            });
+        Ok(leads
+            .into_iter()
+            .map(|(mut deal, contact)| {
+                if let Some(name) = contact.and_then(|id| names.get(&id)) {
+                    deal.buyer = name.trim().to_string();
+                }
+                deal
+            })
+            .collect())
+    }
+
+    async fn get_contact_names(&self, ids: &[u64]) -> Result<HashMap<u64, String>> {
+        let mut names = HashMap::new();
+        for chunk in ids.chunks(CONTACTS_PAGE) {
+            let filter: String = chunk
+                .iter()
+                .map(|id| format!("&filter[id][]={id}"))
+                .collect();
+            let url = format!("{}contacts?limit={CONTACTS_PAGE}{filter}", self.base_url());
+            debug!("fetch {url}");
+            let response = Client::new()
+                .get(url)
+                .header("Authorization", format!("Bearer {}", self.token()))
+                .send()
+                .await?;
+            match response.status() {
+                StatusCode::OK => {
+                    let data = response.json::<Contacts>().await?;
+                    names.extend(data._embedded.contacts.into_iter().map(|c| (c.id, c.name)));
+                }
+                StatusCode::NO_CONTENT => {}
+                status_code => {
+                    return Err(Error::Funnels(format!(
+                        "Fetch contacts response status: {:?}",
+                        status_code
+                    )));
+                }
+            }
+        }
+        Ok(names)
     }
-    fn extract_dkp_deals(&self, leads: Leads) -> Vec<Deal> {
+
+    /// DKP deals with the id of their main contact
+    fn extract_dkp_deals(&self, leads: Leads, rules: &Rules) -> Vec<(Deal, Option<u64>)> {
         leads
             ._embedded
             .leads
@@ -109,8 +169,8 @@ impl AmoClient {
 
                 let sold_at = l.val_to_str("Дата продажи для отчета");
                 let ts = sold_at.parse::<i64>().unwrap_or(0);
-                let created_on = ts_to_date(ts);
-                debug!("Sold date: {}", created_on.format("%d.%m.%Y"));
+                let created_on = from_timestamp(ts);
+                debug!("Sold date: {}", to_local(&created_on).format("%d.%m.%Y"));
 
                 let facing = l.val_to_str("Вид отделки квартиры");
                 debug!("Отделка: {}", facing);
@@ -122,8 +182,13 @@ impl AmoClient {
                 debug!("Номер помещения: {}", property_num);
                 debug!("================================");
 
-                let days_limit = self.deal_days_limit(days, &project);
-                Deal {
+                let default_limit = days <= 0;
+                let days_limit = if default_limit {
+                    rules.days_limit_for(&project, &property_type, &facing)
+                } else {
+                    days
+                };
+                let deal = Deal {
                     deal_id: l.id,
                     project,
                     house,
@@ -132,7 +197,11 @@ impl AmoClient {
                     facing,
                     days_limit,
                     created_on,
-                }
+                    responsible_id: l.responsible_user_id,
+                    default_limit,
+                    buyer: String::new(),
+                };
+                (deal, l.main_contact())
             })
             .collect::<Vec<_>>()
     }
@@ -143,15 +212,4 @@ impl AmoClient {
     fn token(&self) -> &str {
         self.token
     }
-
-    fn deal_days_limit(&self, days: i32, project: &str) -> i32 {
-        let default_days_limit = if project == PROJECTS[0] { 60 } else { 30 };
-        if days > 0 { days } else { default_days_limit }
-    }
-}
-
-pub fn ts_to_date(ts: i64) -> NaiveDateTime {
-    let date = Utc.timestamp_opt(ts, 0).unwrap();
-    let local_date: DateTime<Local> = DateTime::from(date);
-    local_date.naive_local()
 }
@@ -1,12 +1,16 @@
 use crate::adapters::amo::amo_types::Deal;
+use crate::adapters::mailer::Body;
+use crate::calendar::deadline;
+use crate::clock::to_local;
 use crate::model::deal::DealData;
+use crate::model::kpi::{Kpi, KpiStats};
+use crate::model::report::ManagementReport;
 use askama::Template;
-use chrono::NaiveDateTime;
-use std::ops::Add;
-use std::time::Duration;
+use chrono::{NaiveDate, NaiveDateTime};
 
 #[derive(Debug, Clone)]
 pub struct DealInfo {
+    pub deal_id: u64,
     pub project: String,
     pub house: String,
     pub property_type: String,
@@ -14,60 +18,104 @@ pub struct DealInfo {
     pub facing: String,
     pub reg_date: String,
     pub exp_date: String,
+    /// Same dates for spreadsheets
+    pub reg_on: NaiveDate,
+    pub exp_on: NaiveDate,
+    pub days_limit: i32,
+    pub transferred_on: Option<NaiveDate>,
+    pub buyer: String,
 }
 
 impl DealInfo {
     fn from_deal(
         created_on: &NaiveDateTime,
-        days_limit: u64,
+        exp_date: NaiveDate,
         project: &str,
         house: &str,
         property_type: &str,
         property_num: i32,
         facing: &str,
     ) -> Self {
-        let reg_date = created_on.format("%d.%m.%Y").to_string();
-        let exp_date = created_on
-            .add(Duration::from_secs(86400 * days_limit))
-            .format("%d.%m.%Y")
-            .to_string();
+        let reg_on = to_local(created_on).date();
         Self {
+            deal_id: 0,
             project: project.to_string(),
             house: house.to_string(),
             property_type: property_type.to_string(),
             property_num,
             facing: facing.to_string(),
-            reg_date,
-            exp_date,
+            reg_date: reg_on.format("%d.%m.%Y").to_string(),
+            exp_date: exp_date.format("%d.%m.%Y").to_string(),
+            reg_on,
+            exp_on: exp_date,
+            days_limit: 0,
+            transferred_on: None,
+            buyer: String::new(),
         }
     }
 }
 
 impl From<&Deal> for DealInfo {
     fn from(d: &Deal) -> Self {
-        DealInfo::from_deal(
-            &d.created_on,
-            d.days_limit as u64,
-            &d.project,
-            &d.house,
-            &d.property_type,
-            d.property_num,
-            &d.facing,
-        )
+        DealInfo {
+            deal_id: d.deal_id,
+            days_limit: d.days_limit,
+            buyer: d.buyer.clone(),
+            ..DealInfo::from_deal(
+                &d.created_on,
+                deadline(&d.created_on, d.days_limit, &d.project, &d.property_type),
+                &d.project,
+                &d.house,
+                &d.property_type,
+                d.property_num,
+                &d.facing,
+            )
+        }
     }
 }
 
 impl From<DealData> for DealInfo {
     fn from(d: DealData) -> Self {
-        DealInfo::from_deal(
-            &d.created_on,
-            d.days_limit as u64,
-            &d.project,
-            &d.house,
-            &d.property_type,
-            d.property_num,
-            &d.facing,
-        )
+        DealInfo {
+            deal_id: d.deal_id,
+            days_limit: d.days_limit,
+            transferred_on: d.transferred_on.map(|t| to_local(&t).date()),
+            buyer: d.buyer.clone(),
+            ..DealInfo::from_deal(
+                &d.created_on,
+                d.exp_date(),
+                &d.project,
+                &d.house,
+                &d.property_type,
+                d.property_num,
+                &d.facing,
+            )
+        }
+    }
+}
+
+/// Email rendered to html with a plain-text alternative from the same data
+pub trait MailBody: Template {
+    fn text(&self) -> askama::Result<String>;
+
+    fn body(&self) -> askama::Result<Body> {
+        Ok(Body {
+            html: self.render()?,
+            text: self.text()?,
+        })
+    }
+}
+
+/// Deal as the bot shows it, emails include the same lines
+#[derive(Template)]
+#[template(path = "deal_card.txt")]
+pub struct DealCard {
+    deal: DealInfo,
+}
+
+impl DealCard {
+    pub fn new(deal: DealInfo) -> Self {
+        Self { deal }
     }
 }
 
@@ -84,41 +132,272 @@ impl<'a> DkpObjects<'a> {
     }
 }
 
+#[derive(Template)]
+#[template(path = "objects.txt")]
+struct DkpObjectsText<'a, 'b> {
+    mail: &'b DkpObjects<'a>,
+}
+
+impl MailBody for DkpObjects<'_> {
+    fn text(&self) -> askama::Result<String> {
+        DkpObjectsText { mail: self }.render()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeadlineInfo {
+    pub deal: DealInfo,
+    pub remaining: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TierGroup {
+    pub title: String,
+    pub deals: Vec<DeadlineInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeadlineSection {
+    pub title: String,
+    pub tiers: Vec<TierGroup>,
+}
+
+#[derive(Template)]
+#[template(path = "deadline_tmpl.html")]
+pub struct DkpDeadline<'a> {
+    header: &'a str,
+    sections: Vec<DeadlineSection>,
+}
+
+impl<'a> DkpDeadline<'a> {
+    pub fn new(header: &'a str, sections: Vec<DeadlineSection>) -> Self {
+        Self { header, sections }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "deadline.txt")]
+struct DkpDeadlineText<'a, 'b> {
+    mail: &'b DkpDeadline<'a>,
+}
+
+impl MailBody for DkpDeadline<'_> {
+    fn text(&self) -> askama::Result<String> {
+        DkpDeadlineText { mail: self }.render()
+    }
+}
+
 #[derive(Template)]
 #[template(path = "stat_tmpl.html")]
 pub struct DkpStat<'a> {
     header: &'a str,
-    format_apartments: usize,
-    format_pantries: usize,
-    format_parking: usize,
-    city_apartments: usize,
-    city_pantries: usize,
+    projects: Vec<ProjectStat>,
+    kpi: Vec<KpiTable>,
+    /// Content-IDs of the inline chart images
+    charts: Vec<&'static str>,
 }
 
 impl<'a> DkpStat<'a> {
     pub fn new(
         header: &'a str,
-        format_apartments: usize,
-        format_pantries: usize,
-        format_parking: usize,
-        city_apartments: usize,
-        city_pantries: usize,
+        projects: Vec<ProjectStat>,
+        kpi: Vec<KpiTable>,
+        charts: Vec<&'static str>,
+    ) -> Self {
+        Self {
+            header,
+            projects,
+            kpi,
+            charts,
+        }
+    }
+}
+
+/// Charts are left out, they exist as images only
+#[derive(Template)]
+#[template(path = "stat.txt")]
+struct DkpStatText<'a, 'b> {
+    mail: &'b DkpStat<'a>,
+}
+
+impl MailBody for DkpStat<'_> {
+    fn text(&self) -> askama::Result<String> {
+        DkpStatText { mail: self }.render()
+    }
+}
+
+/// New sales, transfers, deadlines and statistics in one daily email
+#[derive(Template)]
+#[template(path = "digest_tmpl.html")]
+pub struct DkpDigest<'a> {
+    header: &'a str,
+    new_sales: Vec<DealInfo>,
+    transferred: Vec<DealInfo>,
+    deadlines: Vec<TierGroup>,
+    projects: Vec<ProjectStat>,
+    kpi: Vec<KpiTable>,
+    charts: Vec<&'static str>,
+}
+
+impl<'a> DkpDigest<'a> {
+    pub fn new(
+        header: &'a str,
+        new_sales: Vec<DealInfo>,
+        transferred: Vec<DealInfo>,
+        deadlines: Vec<TierGroup>,
+        projects: Vec<ProjectStat>,
+        kpi: Vec<KpiTable>,
+        charts: Vec<&'static str>,
     ) -> Self {
         Self {
             header,
-            format_apartments,
-            format_pantries,
-            format_parking,
-            city_apartments,
-            city_pantries,
+            new_sales,
+            transferred,
+            deadlines,
+            projects,
+            kpi,
+            charts,
         }
     }
 }
 
-pub struct StatNumbers {
-    pub format_apartments: usize,
-    pub format_pantries: usize,
-    pub format_parking: usize,
-    pub city_apartments: usize,
-    pub city_pantries: usize,
+#[derive(Template)]
+#[template(path = "digest.txt")]
+struct DkpDigestText<'a, 'b> {
+    mail: &'b DkpDigest<'a>,
+}
+
+impl MailBody for DkpDigest<'_> {
+    fn text(&self) -> askama::Result<String> {
+        DkpDigestText { mail: self }.render()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KpiRow {
+    pub label: String,
+    pub transferred: usize,
+    pub median_days: String,
+    pub p90_days: String,
+    pub on_time_pct: String,
+    pub overdue: usize,
+    pub avg_overdue_days: String,
+}
+
+impl From<&KpiStats> for KpiRow {
+    fn from(k: &KpiStats) -> Self {
+        let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+        Self {
+            label: k.label.clone(),
+            transferred: k.transferred,
+            median_days: or_dash(k.median_days.map(|v| format!("{v:.0}"))),
+            p90_days: or_dash(k.p90_days.map(|v| v.to_string())),
+            on_time_pct: or_dash(k.on_time_pct.map(|v| format!("{v:.0}%"))),
+            overdue: k.overdue,
+            avg_overdue_days: or_dash(k.avg_overdue_days.map(|v| format!("{v:.1}"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KpiTable {
+    pub title: String,
+    pub rows: Vec<KpiRow>,
+}
+
+impl KpiTable {
+    pub fn from_kpi(kpi: &Kpi) -> Vec<KpiTable> {
+        let table = |title: &str, rows: &[KpiStats]| KpiTable {
+            title: title.to_string(),
+            rows: rows.iter().map(Into::into).collect(),
+        };
+        vec![
+            table("Итого", std::slice::from_ref(&kpi.total)),
+            table("По проектам", &kpi.by_project),
+            table("По типам объектов", &kpi.by_type),
+            table("По месяцам передачи", &kpi.by_month),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HouseStat {
+    pub house: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeStat {
+    pub property_type: String,
+    pub total: usize,
+    /// Change against the previous snapshot, e.g. `(+5)`
+    pub delta: String,
+    pub houses: Vec<HouseStat>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectStat {
+    pub project: String,
+    pub total: usize,
+    pub delta: String,
+    pub types: Vec<TypeStat>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportRow {
+    pub title: String,
+    pub total: usize,
+    /// In the order of `ReportSummary::projects`
+    pub counts: Vec<usize>,
+}
+
+/// Category counts of a management report, in total and per project
+#[derive(Debug, Clone)]
+pub struct ReportSummary {
+    pub projects: Vec<String>,
+    pub rows: Vec<ReportRow>,
+}
+
+impl ReportSummary {
+    pub fn from_report(report: &ManagementReport) -> Self {
+        let projects = report.projects();
+        let rows = report
+            .categories()
+            .iter()
+            .map(|c| ReportRow {
+                title: c.title.to_string(),
+                total: c.deals.len(),
+                counts: projects
+                    .iter()
+                    .map(|p| c.deals.iter().filter(|d| &d.project == p).count())
+                    .collect(),
+            })
+            .collect();
+        Self { projects, rows }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "report_tmpl.html")]
+pub struct DkpReport<'a> {
+    header: &'a str,
+    summary: ReportSummary,
+}
+
+impl<'a> DkpReport<'a> {
+    pub fn new(header: &'a str, summary: ReportSummary) -> Self {
+        Self { header, summary }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "report.txt")]
+struct DkpReportText<'a, 'b> {
+    mail: &'b DkpReport<'a>,
+}
+
+impl MailBody for DkpReport<'_> {
+    fn text(&self) -> askama::Result<String> {
+        DkpReportText { mail: self }.render()
+    }
 }
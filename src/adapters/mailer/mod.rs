@@ -1,28 +1,106 @@
 use crate::Result;
 use crate::adapters::amo::amo_types::Deal;
-use crate::adapters::mailer::data_types::{DealInfo, DkpStat, StatNumbers};
+use crate::adapters::mailer::data_types::{
+    DeadlineSection, DealInfo, DkpDeadline, DkpDigest, DkpReport, DkpStat, KpiTable, MailBody,
+    ProjectStat, ReportSummary,
+};
+use crate::chart::Chart;
+use crate::clock::local_now;
 use crate::config::config;
+use crate::error::Error;
+use crate::layout::{DEFAULT_LAYOUT, REPORT_LAYOUT, layouts};
+use crate::model::Db;
+use crate::model::deal::DealData;
+use crate::model::digest::Digest;
+use crate::model::forecast::Forecast;
+use crate::model::kpi::Kpi;
+use crate::model::report::ManagementReport;
+use crate::model::subscription::MailKind;
 use crate::xlsx::Xlsx;
-use askama::Template;
+use chrono::{DateTime, NaiveDate};
+use chrono_tz::Tz;
 use data_types::DkpObjects;
 use log::info;
-use mail_send::SmtpClientBuilder;
 use mail_send::mail_builder::MessageBuilder;
+use mail_send::mail_builder::headers::raw::Raw;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub mod data_types;
+pub mod smtp;
 
 pub struct Email {
+    from: String,
     receivers: Vec<(String, String)>,
 }
 
+/// Html body with its plain-text alternative
+#[derive(Debug, Clone)]
+pub struct Body {
+    pub html: String,
+    pub text: String,
+}
+
+/// Rendered message with its SMTP envelope
+#[derive(Debug, Clone)]
+pub struct OutgoingMail {
+    pub subject: String,
+    pub mail_from: String,
+    pub rcpt_to: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+/// File attached to an email
+#[derive(Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub content_type: &'static str,
+    pub content: Vec<u8>,
+}
+
+impl Attachment {
+    pub fn xlsx(content: Vec<u8>) -> Self {
+        Self {
+            name: "report.xlsx".to_string(),
+            content_type: "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            content,
+        }
+    }
+
+    pub fn pdf(name: String, content: Vec<u8>) -> Self {
+        Self {
+            name,
+            content_type: "application/pdf",
+            content,
+        }
+    }
+}
+
 impl Email {
     pub fn new() -> Self {
-        let receivers = Email::get_receivers();
-        Self { receivers }
+        Email::to(Email::get_receivers())
+    }
+
+    pub fn to(receivers: Vec<(String, String)>) -> Self {
+        Self {
+            from: config().FROM.clone(),
+            receivers,
+        }
+    }
+
+    /// Management reports go to their own list, the daily one when it is not set
+    pub fn for_reports() -> Self {
+        let receivers = match config().REPORT_RECEIVERS.trim() {
+            "" => Email::get_receivers(),
+            list => Email::parse_receivers(list),
+        };
+        Email::to(receivers)
     }
 
     fn get_receivers() -> Vec<(String, String)> {
-        let config = config().RECEIVERS.clone();
+        Email::parse_receivers(&config().RECEIVERS)
+    }
+
+    fn parse_receivers(config: &str) -> Vec<(String, String)> {
         let rec = config
             .split(';')
             .map(|s| {
@@ -36,86 +114,214 @@ impl Email {
         rec
     }
 
-    pub async fn new_objects_notification(&self, deals: &[Deal]) -> Result<()> {
+    /// Rendered but not queued, `sync` stores it together with the new deals
+    pub fn new_objects_mail(&self, deals: &[Deal]) -> Result<OutgoingMail> {
         let subject = "Новые сделки по ДКП";
         let content: Vec<DealInfo> = deals.iter().map(Into::into).collect();
-        let today = chrono::Local::now().format("%d.%m.%Y %H:%M");
+        let today = local_now().format("%d.%m.%Y %H:%M");
         let header = format!("Новые объекты по ДКП на {today}");
         let tpl = DkpObjects::new(&header, content);
-        self.send(subject, tpl.render()?, None).await?;
-        Ok(())
+        self.build(MailKind::NewDeals, subject, tpl.body()?, None, &[])
     }
 
-    pub async fn deadline_notification(&self, deals: Vec<DealInfo>) -> Result<()> {
-        let subject = "Дедлайн по передаче объектов по ДКП";
-        let today = chrono::Local::now().format("%d.%m.%Y %H:%M");
-        let header = format!("Дедлайн по передаче объектов на {today}");
-        let tmpl = DkpObjects::new(&header, deals);
-        self.send(subject, tmpl.render()?, None).await?;
+    /// One subject a day, the emails of the day are threaded
+    pub async fn deadline_notification(&self, sections: Vec<DeadlineSection>) -> Result<()> {
+        let now = local_now();
+        let subject = format!(
+            "Дедлайн по передаче объектов по ДКП на {}",
+            now.format("%d.%m.%Y")
+        );
+        let header = format!(
+            "Дедлайн по передаче объектов на {}",
+            now.format("%d.%m.%Y %H:%M")
+        );
+        let tmpl = DkpDeadline::new(&header, sections);
+        self.send(MailKind::Deadline, &subject, tmpl.body()?, None, &[])
+            .await?;
         Ok(())
     }
 
-    pub async fn stat_notification(&self, deals: Vec<DealInfo>, s: StatNumbers) -> Result<()> {
+    pub async fn stat_notification(
+        &self,
+        deals: Vec<DealInfo>,
+        projects: Vec<ProjectStat>,
+        kpi: &Kpi,
+        forecast: &Forecast,
+        charts: &[Chart],
+    ) -> Result<()> {
         let subject = "Статистика по объектам ДКП";
-        let today = chrono::Local::now().format("%d.%m.%Y %H:%M");
+        let today = local_now().format("%d.%m.%Y %H:%M");
         let header =
             format!("Агрегированная информация (статистика) по всем объектам ДКП на {today}");
 
         let tmpl = DkpStat::new(
             &header,
-            s.format_apartments,
-            s.format_pantries,
-            s.format_parking,
-            s.city_apartments,
-            s.city_pantries,
+            projects,
+            KpiTable::from_kpi(kpi),
+            charts.iter().map(|c| c.cid).collect(),
         );
-        let attach = Xlsx::create(deals)?;
-        self.send(subject, tmpl.render()?, Some(attach)).await?;
+        let layout = layouts().get(&config().STAT_LAYOUT, DEFAULT_LAYOUT)?;
+        let attach = Attachment::xlsx(Xlsx::create(deals, Some(forecast), layout)?);
+        self.send(MailKind::Stat, subject, tmpl.body()?, Some(attach), charts)
+            .await?;
         Ok(())
     }
 
-    pub async fn send(
+    /// Rendered but not queued, the digest is stored together with the period it covers
+    pub fn digest_mail(
         &self,
-        subject: &str,
-        payload: String,
-        attach: Option<Vec<u8>>,
-    ) -> Result<()> {
-        let username = config().LOGIN.clone();
-        let secret = config().PASSWORD.clone();
+        digest: &Digest,
+        projects: Vec<ProjectStat>,
+        kpi: &Kpi,
+        charts: &[Chart],
+    ) -> Result<OutgoingMail> {
+        let subject = format!(
+            "Ежедневная сводка по ДКП на {}",
+            local_now().format("%d.%m.%Y")
+        );
+        let header = format!("Ежедневная сводка по ДКП за {}", digest.period_text());
+        let info = |deals: &[DealData]| deals.iter().cloned().map(Into::into).collect();
+        let tmpl = DkpDigest::new(
+            &header,
+            info(&digest.new_sales),
+            info(&digest.transferred),
+            digest.tier_groups(),
+            projects,
+            KpiTable::from_kpi(kpi),
+            charts.iter().map(|c| c.cid).collect(),
+        );
+        let layout = layouts().get(&config().DIGEST_LAYOUT, DEFAULT_LAYOUT)?;
+        let attach = Attachment::xlsx(Xlsx::create_digest(digest, layout)?);
+        self.build(
+            MailKind::Digest,
+            &subject,
+            tmpl.body()?,
+            Some(attach),
+            charts,
+        )
+    }
 
-        let creds = mail_send::Credentials::new(&username, &secret);
+    pub async fn report_notification(&self, report: &ManagementReport) -> Result<()> {
+        let subject = format!("{} по ДКП за {}", report.kind.title(), report.period_text());
+        let header = format!(
+            "{} по объектам ДКП за {}",
+            report.kind.title(),
+            report.period_text()
+        );
+        let tmpl = DkpReport::new(&header, ReportSummary::from_report(report));
+        let layout = layouts().get(report.kind.layout(), REPORT_LAYOUT)?;
+        let attach = Attachment::xlsx(Xlsx::create_report(report, layout)?);
+        self.send(
+            report.kind.into(),
+            &subject,
+            tmpl.body()?,
+            Some(attach),
+            &[],
+        )
+        .await?;
+        Ok(())
+    }
 
-        let mut mailer = SmtpClientBuilder::new(&config().SMTP_SERVER, 587)?
-            .implicit_tls(false)
-            .credentials(creds)
-            .connect()
+    pub async fn act_notification(&self, deal: &DealData, act: Attachment) -> Result<()> {
+        let subject = format!(
+            "Акт приёма-передачи: {}, {}, № {}",
+            deal.project, deal.house, deal.property_num
+        );
+        let header = format!("Акт приёма-передачи по сделке {}", deal.deal_id);
+        let tpl = DkpObjects::new(&header, vec![deal.clone().into()]);
+        self.send(MailKind::Act, &subject, tpl.body()?, Some(act), &[])
             .await?;
+        Ok(())
+    }
 
-        let message = if let Some(attach) = attach {
-            MessageBuilder::new()
-                .from(("ДКП бот", config().FROM.as_str()))
-                .to(self.receivers.clone())
-                .subject(subject)
-                .html_body(payload)
-                .attachment(
-                    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
-                    "report.xlsx",
-                    attach,
-                )
-        } else {
-            MessageBuilder::new()
-                .from(("ДКП бот", config().FROM.as_str()))
-                .to(self.receivers.clone())
-                .subject(subject)
-                .html_body(payload)
-        };
+    fn domain(&self) -> &str {
+        match self.from.rsplit_once('@') {
+            Some((_, domain)) if !domain.is_empty() => domain,
+            _ => "localhost",
+        }
+    }
 
-        mailer.send(message).await?;
-        info!("Email sent");
+    pub fn build(
+        &self,
+        kind: MailKind,
+        subject: &str,
+        body: Body,
+        attach: Option<Attachment>,
+        charts: &[Chart],
+    ) -> Result<OutgoingMail> {
+        let now = local_now();
+        let domain = self.domain();
+        let mut message = MessageBuilder::new()
+            .from(("ДКП бот", self.from.as_str()))
+            .to(self.receivers.clone())
+            .subject(subject)
+            .message_id(message_id(kind, &now, domain))
+            .header("List-Id", Raw::new(list_id(kind, domain)))
+            .html_body(body.html)
+            .text_body(body.text);
+        if let Some(root) = thread_root(kind, now.date_naive(), domain) {
+            message = message.in_reply_to(root.clone()).references(root);
+        }
+        if let Some(attach) = attach {
+            message = message.attachment(attach.content_type, attach.name, attach.content);
+        }
+        // referenced from the html as <img src="cid:...">
+        for chart in charts {
+            message = message.inline("image/png", chart.cid, chart.png.as_slice());
+        }
+
+        Ok(OutgoingMail {
+            subject: subject.to_string(),
+            mail_from: self.from.clone(),
+            rcpt_to: self
+                .receivers
+                .iter()
+                .map(|(_, email)| email.clone())
+                .collect(),
+            body: message
+                .write_to_vec()
+                .map_err(|e| Error::AppErr(format!("Failed to build email: {e}")))?,
+        })
+    }
+
+    /// Queued in the outbox, the outbox worker delivers it
+    pub async fn send(
+        &self,
+        kind: MailKind,
+        subject: &str,
+        body: Body,
+        attach: Option<Attachment>,
+        charts: &[Chart],
+    ) -> Result<()> {
+        let mail = self.build(kind, subject, body, attach, charts)?;
+        let id = Db::new().await.enqueue_email(&mail).await?;
+        info!("Email queued: {id}");
         Ok(())
     }
 }
 
+/// Unique within the process even for emails built in the same microsecond
+fn message_id(kind: MailKind, now: &DateTime<Tz>, domain: &str) -> String {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}.{}.{}@{domain}",
+        kind.as_str(),
+        now.format("%Y%m%d%H%M%S%6f"),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Lets the recipients filter every kind of email separately
+fn list_id(kind: MailKind, domain: &str) -> String {
+    format!("<{}.dkp.{domain}>", kind.as_str())
+}
+
+/// Deadline emails of a day reply to the same root id, so clients show them as one thread
+fn thread_root(kind: MailKind, today: NaiveDate, domain: &str) -> Option<String> {
+    (kind == MailKind::Deadline)
+        .then(|| format!("{}.{}@{domain}", kind.as_str(), today.format("%Y%m%d")))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -124,9 +330,14 @@ mod test {
     #[tokio::test]
     async fn mail_send_test() {
         let email = Email::new();
-        let payload = "<div>Hello World</div>".to_owned();
+        let body = Body {
+            html: "<div>Hello World</div>".to_owned(),
+            text: "Hello World".to_owned(),
+        };
         let subject = "Тестовое сообщение от бота";
-        let send_result = email.send(subject, payload, None).await;
+        let send_result = email
+            .send(MailKind::NewDeals, subject, body, None, &[])
+            .await;
 
         match send_result {
             Ok(_) => {
@@ -138,6 +349,103 @@ mod test {
         }
     }
 
+    fn deal(facing: &str) -> Deal {
+        DealData::builder()
+            .id(0, 7123)
+            .property_num(42)
+            .facing(facing)
+            .build_lead()
+    }
+
+    fn email() -> Email {
+        Email {
+            from: "bot@example.com".to_string(),
+            receivers: vec![("Отдел".to_string(), "transfer@example.com".to_string())],
+        }
+    }
+
+    #[test]
+    fn test_multipart() {
+        let mail = email().new_objects_mail(&[deal("Чистовая")]).unwrap();
+        let raw = String::from_utf8_lossy(&mail.body).to_string();
+        assert!(raw.contains("multipart/alternative"));
+        assert!(raw.contains("text/plain"));
+        assert!(raw.contains("text/html"));
+        assert!(raw.contains("Message-ID: <new."));
+        assert!(raw.contains("List-Id: <new.dkp.example.com>"));
+        assert!(!raw.contains("In-Reply-To"));
+
+        let tpl = DkpObjects::new("Новые объекты", vec![(&deal("")).into()]);
+        assert!(tpl.text().unwrap().contains(
+            "Сделка: 7123\nПроект: ЖК Формат\nДом 1\nТип объекта: Квартира\n№ 42\nДата регистрации"
+        ));
+    }
+
+    #[test]
+    fn test_deadline_thread() {
+        let deadline = |remaining: &str| DeadlineSection {
+            title: "Новые".to_string(),
+            tiers: vec![data_types::TierGroup {
+                title: "Осталось 3 дня".to_string(),
+                deals: vec![data_types::DeadlineInfo {
+                    deal: (&deal("Чистовая")).into(),
+                    remaining: remaining.to_string(),
+                }],
+            }],
+        };
+        let tpl = DkpDeadline::new("Дедлайн", vec![deadline("Осталось 3 дн.")]);
+        assert!(
+            tpl.text()
+                .unwrap()
+                .contains("Тип отделки: Чистовая\nДата регистрации")
+        );
+
+        let build = || {
+            email()
+                .build(
+                    MailKind::Deadline,
+                    "Дедлайн",
+                    tpl.body().unwrap(),
+                    None,
+                    &[],
+                )
+                .unwrap()
+        };
+        let (first, second) = (build(), build());
+        let header = |mail: &OutgoingMail, name: &str| {
+            String::from_utf8_lossy(&mail.body)
+                .lines()
+                .find_map(|l| l.strip_prefix(name).map(str::to_string))
+                .unwrap()
+        };
+        let root = format!(" <deadline.{}@example.com>", local_now().format("%Y%m%d"));
+        assert_eq!(header(&first, "In-Reply-To:"), root);
+        assert_eq!(header(&second, "References:"), root);
+        assert_ne!(
+            header(&first, "Message-ID:"),
+            header(&second, "Message-ID:")
+        );
+    }
+
+    #[test]
+    fn test_digest_text() {
+        let kpi = Kpi::compute(&[], &[], crate::clock::today());
+        let tpl = DkpDigest::new(
+            "Сводка",
+            vec![(&deal("Чистовая")).into()],
+            vec![],
+            vec![],
+            vec![],
+            KpiTable::from_kpi(&kpi),
+            vec![],
+        );
+        let body = tpl.body().unwrap();
+        assert!(body.html.contains("Новые продажи: 1"));
+        assert!(body.text.contains("Новые продажи: 1\n\nСделка: 7123\n"));
+        assert!(body.text.contains("Передано: 0\n"));
+        assert!(body.text.contains("Сроки передачи\n"));
+    }
+
     #[test]
     fn test_receivers() {
         let receivers = Email::get_receivers();
@@ -0,0 +1,205 @@
+use crate::Result;
+use crate::adapters::mailer::OutgoingMail;
+use crate::config::config;
+use mail_send::smtp::message::Message;
+use mail_send::{Credentials, SmtpClientBuilder};
+use std::str::FromStr;
+
+/// How the connection to the SMTP server is protected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SmtpTls {
+    /// TLS from the first byte, usually port 465
+    Implicit,
+    /// Plain connection upgraded with STARTTLS, usually port 587
+    #[default]
+    StartTls,
+    /// No encryption, for a relay on localhost or in a private network
+    None,
+}
+
+impl FromStr for SmtpTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "implicit" | "tls" | "ssl" => Ok(SmtpTls::Implicit),
+            "starttls" => Ok(SmtpTls::StartTls),
+            "none" | "plain" => Ok(SmtpTls::None),
+            other => Err(format!("Unknown SMTP TLS mode {other}")),
+        }
+    }
+}
+
+/// Connection settings, an empty login sends without authentication
+pub struct Smtp {
+    pub server: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub login: String,
+    pub password: String,
+}
+
+impl Smtp {
+    pub fn from_config() -> Self {
+        Self {
+            server: config().SMTP_SERVER.clone(),
+            port: config().SMTP_PORT,
+            tls: config().SMTP_TLS,
+            login: config().LOGIN.clone(),
+            password: config().PASSWORD.clone(),
+        }
+    }
+
+    pub async fn send(&self, mail: &OutgoingMail) -> Result<()> {
+        let message = Message::new(
+            mail.mail_from.as_str(),
+            mail.rcpt_to.iter().map(String::as_str),
+            mail.body.as_slice(),
+        );
+        let mut builder = SmtpClientBuilder::new(self.server.as_str(), self.port)?;
+        if !self.login.is_empty() {
+            builder = builder.credentials(Credentials::new(
+                self.login.as_str(),
+                self.password.as_str(),
+            ));
+        }
+        match self.tls {
+            SmtpTls::None => builder.connect_plain().await?.send(message).await?,
+            tls => {
+                builder
+                    .implicit_tls(tls == SmtpTls::Implicit)
+                    .connect()
+                    .await?
+                    .send(message)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use mail_send::mail_builder::MessageBuilder;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Minimal SMTP server without STARTTLS, returns the commands of one session
+    pub async fn fake_server() -> (u16, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let mut commands = vec![];
+            write.write_all(b"220 relay ESMTP\r\n").await.unwrap();
+            let mut in_data = false;
+            while let Ok(Some(line)) = lines.next_line().await {
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        write.write_all(b"250 2.0.0 queued\r\n").await.unwrap();
+                    }
+                    continue;
+                }
+                let verb = line.split(' ').next().unwrap_or_default().to_uppercase();
+                commands.push(verb.clone());
+                let reply: &[u8] = match verb.as_str() {
+                    "EHLO" => b"250-relay\r\n250-AUTH PLAIN\r\n250 8BITMIME\r\n",
+                    "AUTH" => b"235 2.7.0 accepted\r\n",
+                    "DATA" => {
+                        in_data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT" => b"221 bye\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                if write.write_all(reply).await.is_err() {
+                    break;
+                }
+            }
+            commands
+        });
+        (port, handle)
+    }
+
+    pub fn smtp(port: u16, tls: SmtpTls, login: &str) -> Smtp {
+        Smtp {
+            server: "127.0.0.1".to_string(),
+            port,
+            tls,
+            login: login.to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    pub fn message() -> OutgoingMail {
+        let body = MessageBuilder::new()
+            .from(("ДКП бот", "bot@example.com"))
+            .to(vec![("Отдел", "transfer@example.com")])
+            .subject("Тест")
+            .text_body("Hello")
+            .write_to_vec()
+            .unwrap();
+        OutgoingMail {
+            subject: "Тест".to_string(),
+            mail_from: "bot@example.com".to_string(),
+            rcpt_to: vec!["transfer@example.com".to_string()],
+            body,
+        }
+    }
+
+    #[test]
+    fn test_parse_tls() {
+        assert_eq!("Implicit".parse(), Ok(SmtpTls::Implicit));
+        assert_eq!("starttls".parse(), Ok(SmtpTls::StartTls));
+        assert_eq!("none".parse(), Ok(SmtpTls::None));
+        assert!("tls1.3".parse::<SmtpTls>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_relay() {
+        let (port, server) = fake_server().await;
+        smtp(port, SmtpTls::None, "")
+            .send(&message())
+            .await
+            .unwrap();
+        let commands = server.await.unwrap();
+        assert_eq!(commands, vec!["EHLO", "MAIL", "RCPT", "DATA"]);
+    }
+
+    #[tokio::test]
+    async fn test_plain_with_credentials() {
+        let (port, server) = fake_server().await;
+        smtp(port, SmtpTls::None, "bot")
+            .send(&message())
+            .await
+            .unwrap();
+        let commands = server.await.unwrap();
+        assert_eq!(commands, vec!["EHLO", "AUTH", "MAIL", "RCPT", "DATA"]);
+    }
+
+    #[tokio::test]
+    async fn test_tls_required() {
+        // never falls back to clear text when the server offers no STARTTLS
+        let (port, server) = fake_server().await;
+        assert!(
+            smtp(port, SmtpTls::StartTls, "bot")
+                .send(&message())
+                .await
+                .is_err()
+        );
+        assert!(!server.await.unwrap().contains(&"AUTH".to_string()));
+
+        let (port, server) = fake_server().await;
+        assert!(
+            smtp(port, SmtpTls::Implicit, "bot")
+                .send(&message())
+                .await
+                .is_err()
+        );
+        assert!(!server.await.unwrap().contains(&"MAIL".to_string()));
+    }
+}
@@ -1,20 +1,49 @@
+use crate::chart::get_charts;
+use crate::clock::{self, to_local};
 use crate::config::config;
-use crate::model::deal::{get_house_numbers, get_property_numbers, prepare_response};
+use crate::ics;
+use crate::import::import_deals;
+use crate::model::Db;
+use crate::model::act::{ActAction, get_act, send_act};
+use crate::model::alert::{Actor, AlertAction, parse_agreed_date, snooze_until};
+use crate::model::archive::search_archived_deals;
+use crate::model::deadline::managers;
+use crate::model::deal::{deal_card, get_house_numbers, get_property_numbers, prepare_response};
+use crate::model::export::{
+    ANY_CHOICE, EXPORT_USAGE, ExportFilter, ExportFormat, ExportStatus, FORMAT_CHOICES,
+    STATUS_CHOICES, export,
+};
+use crate::model::forecast::{self, get_forecast};
+use crate::model::kpi::get_kpi;
+use crate::model::outbox::get_outbox_text;
+use crate::model::rules::{changes_report, parse_rule};
+use crate::model::snapshot::trend_report;
+use crate::model::stat::{StatPath, StatView, get_stat_view};
+use crate::model::subscription::{Projects, parse_subscription, subscriptions_text};
 use crate::model::sync::sync;
+use crate::sender::{send_msg_to_chat, truncate_message};
+use cron::Schedule;
 use log::info;
+use std::collections::HashMap;
 use std::error::Error;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
 use teloxide::dispatching::dialogue::InMemStorage;
 use teloxide::dispatching::{DpHandlerDescription, dialogue};
 use teloxide::dptree::case;
 use teloxide::macros::BotCommands;
+use teloxide::net::Download;
 use teloxide::prelude::*;
-use teloxide::types::{ChatKind, KeyboardButton, KeyboardMarkup, KeyboardRemove, ReplyMarkup};
+use teloxide::types::{
+    ChatKind, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaPhoto,
+    KeyboardButton, KeyboardMarkup, KeyboardRemove, ReplyMarkup, User,
+};
 
 type HandlerResult = Result<(), Box<dyn Error + Send + Sync>>;
 type MyDialogue = Dialogue<State, InMemStorage<State>>;
 
 pub const PROJECTS: [&str; 2] = ["DNS Сити", "ЖК Формат"];
-const PROPERTY_TYPES: [&str; 3] = ["Квартира", "Кладовка", "Машиноместо"];
+pub const PROPERTY_TYPES: [&str; 3] = ["Квартира", "Кладовка", "Машиноместо"];
 #[derive(Clone, Default)]
 pub enum State {
     #[default]
@@ -32,6 +61,19 @@ pub enum State {
         property_type: String,
         house: String,
     },
+    ChooseExportProject,
+    ChooseExportType {
+        filter: ExportFilter,
+    },
+    ChooseExportHouse {
+        filter: ExportFilter,
+    },
+    ChooseExportStatus {
+        filter: ExportFilter,
+    },
+    ChooseExportFormat {
+        filter: ExportFilter,
+    },
 }
 
 #[derive(BotCommands, Clone)]
@@ -41,6 +83,34 @@ pub enum BotCommand {
     Start,
     /// Запрос данных в AmoCRM
     Sync,
+    /// Поиск в архиве: /archive номер, сделка или дом
+    Archive(String),
+    /// Правила срока передачи по умолчанию
+    Rules,
+    /// Добавить правило: /addrule позиция проект|тип|отделка|дней
+    AddRule(String),
+    /// Удалить правило: /delrule номер
+    DelRule(String),
+    /// Прогноз передач по неделям
+    Calendar,
+    /// Сроки передачи в формате .ics
+    Ics,
+    /// Показатели передачи: сроки и доля в срок
+    Kpi,
+    /// Изменения за неделю и месяц
+    Trend,
+    /// Статистика по проектам, типам и домам с графиками
+    Stat,
+    /// Выгрузка объектов с фильтрами в xlsx, csv или json
+    Export(String),
+    /// Неотправленные письма
+    Outbox,
+    /// Подписки на письма
+    Subscribers,
+    /// Подписать адрес: /subscribe адрес|имя|письма|проекты
+    Subscribe(String),
+    /// Отписать адрес: /unsubscribe адрес
+    Unsubscribe(String),
 }
 
 pub fn bot_handler() -> Handler<'static, HandlerResult, DpHandlerDescription> {
@@ -49,7 +119,49 @@ pub fn bot_handler() -> Handler<'static, HandlerResult, DpHandlerDescription> {
             Update::filter_message()
                 .filter_command::<BotCommand>()
                 .branch(case![BotCommand::Sync].endpoint(sync_handler))
-                .branch(case![BotCommand::Start].endpoint(start)),
+                .branch(case![BotCommand::Start].endpoint(start))
+                .branch(case![BotCommand::Archive(query)].endpoint(archive_handler))
+                .branch(case![BotCommand::Rules].endpoint(rules_handler))
+                .branch(case![BotCommand::AddRule(rule)].endpoint(add_rule_handler))
+                .branch(case![BotCommand::DelRule(id)].endpoint(del_rule_handler))
+                .branch(case![BotCommand::Calendar].endpoint(calendar_handler))
+                .branch(case![BotCommand::Ics].endpoint(ics_handler))
+                .branch(case![BotCommand::Kpi].endpoint(kpi_handler))
+                .branch(case![BotCommand::Trend].endpoint(trend_handler))
+                .branch(case![BotCommand::Stat].endpoint(stat_handler))
+                .branch(case![BotCommand::Export(args)].endpoint(export_handler))
+                .branch(case![BotCommand::Outbox].endpoint(outbox_handler))
+                .branch(case![BotCommand::Subscribers].endpoint(subscribers_handler))
+                .branch(case![BotCommand::Subscribe(args)].endpoint(subscribe_handler))
+                .branch(case![BotCommand::Unsubscribe(email)].endpoint(unsubscribe_handler)),
+        )
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message| msg.document().is_some())
+                .endpoint(import_handler),
+        )
+        .branch(
+            Update::filter_callback_query()
+                .filter_map(|q: CallbackQuery| q.data.as_deref().and_then(forecast::parse_callback))
+                .endpoint(calendar_callback),
+        )
+        .branch(
+            Update::filter_callback_query()
+                .filter_map(|q: CallbackQuery| q.data.as_deref().and_then(StatPath::parse_callback))
+                .endpoint(stat_callback),
+        )
+        .branch(
+            Update::filter_callback_query()
+                .filter_map(|q: CallbackQuery| {
+                    q.data.as_deref().and_then(ActAction::parse_callback)
+                })
+                .endpoint(act_callback),
+        )
+        .branch(Update::filter_callback_query().endpoint(alert_callback))
+        .branch(
+            Update::filter_message()
+                .filter_map(|msg: Message| pending_reschedule(&msg))
+                .endpoint(receive_agreed_date),
         )
         .branch(
             Update::filter_message()
@@ -69,6 +181,13 @@ pub fn bot_handler() -> Handler<'static, HandlerResult, DpHandlerDescription> {
                         house,
                     }]
                     .endpoint(receive_property_number),
+                )
+                .branch(case![State::ChooseExportProject].endpoint(receive_export_project))
+                .branch(case![State::ChooseExportType { filter }].endpoint(receive_export_type))
+                .branch(case![State::ChooseExportHouse { filter }].endpoint(receive_export_house))
+                .branch(case![State::ChooseExportStatus { filter }].endpoint(receive_export_status))
+                .branch(
+                    case![State::ChooseExportFormat { filter }].endpoint(receive_export_format),
                 ),
         )
 }
@@ -116,6 +235,652 @@ async fn make_house_kbd(project: &str, property_type: &str) -> Option<KeyboardMa
     }
 }
 
+pub fn alert_keyboard(deal: i32) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![
+        vec![
+            InlineKeyboardButton::callback("Принято", AlertAction::Ack.callback_data(deal)),
+            InlineKeyboardButton::callback(
+                "Отложить на 1 день",
+                AlertAction::Snooze.callback_data(deal),
+            ),
+        ],
+        vec![InlineKeyboardButton::callback(
+            "Перенос согласован (новая дата)",
+            AlertAction::Reschedule.callback_data(deal),
+        )],
+    ])
+}
+
+fn act_keyboard(deal: i32) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback(
+            "📄 Акт приёма-передачи",
+            ActAction::Download.callback_data(deal),
+        ),
+        InlineKeyboardButton::callback("✉️ Акт на почту", ActAction::Email.callback_data(deal)),
+    ]])
+}
+
+/// Deal card with the transfer act buttons
+async fn send_deal_card(
+    bot: &Bot,
+    chat: ChatId,
+    project: &str,
+    property_type: &str,
+    house: &str,
+    number: i32,
+) -> HandlerResult {
+    let (card, deal) = prepare_response(project, property_type, house, number).await;
+    let request = bot.send_message(chat, card);
+    match deal {
+        Some(deal) => request.reply_markup(act_keyboard(deal)).await?,
+        None => request.await?,
+    };
+    Ok(())
+}
+
+fn calendar_keyboard(week: usize, weeks: usize) -> InlineKeyboardMarkup {
+    let mut row = vec![];
+    if week > 0 {
+        row.push(InlineKeyboardButton::callback(
+            "◀️ Пред. неделя",
+            forecast::callback_data(week - 1),
+        ));
+    }
+    if week + 1 < weeks {
+        row.push(InlineKeyboardButton::callback(
+            "След. неделя ▶️",
+            forecast::callback_data(week + 1),
+        ));
+    }
+    InlineKeyboardMarkup::new(vec![row])
+}
+
+async fn calendar_handler(bot: Bot, msg: Message) -> HandlerResult {
+    let ChatKind::Private(_) = msg.chat.kind else {
+        return Ok(());
+    };
+    if !can_view_stat(msg.from.as_ref()) {
+        bot.send_message(msg.chat.id, "Нет доступа к статистике")
+            .await?;
+        return Ok(());
+    }
+    match get_forecast().await {
+        Ok(forecast) => {
+            bot.send_message(msg.chat.id, forecast.week_text(0))
+                .reply_markup(calendar_keyboard(0, forecast.weeks()))
+                .await?;
+        }
+        Err(e) => {
+            bot.send_message(msg.chat.id, "Ошибка чтения данных")
+                .await?;
+            let admin_id = ChatId(config().ADMIN_ID);
+            bot.send_message(admin_id, e.to_string()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn kpi_handler(bot: Bot, msg: Message) -> HandlerResult {
+    let ChatKind::Private(_) = msg.chat.kind else {
+        return Ok(());
+    };
+    if !can_view_stat(msg.from.as_ref()) {
+        bot.send_message(msg.chat.id, "Нет доступа к статистике")
+            .await?;
+        return Ok(());
+    }
+    match get_kpi().await {
+        Ok(kpi) => send_msg_to_chat(&bot, msg.chat.id.0, &kpi.to_string()).await,
+        Err(e) => {
+            bot.send_message(msg.chat.id, "Ошибка чтения данных")
+                .await?;
+            let admin_id = ChatId(config().ADMIN_ID);
+            bot.send_message(admin_id, e.to_string()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn trend_handler(bot: Bot, msg: Message) -> HandlerResult {
+    let ChatKind::Private(_) = msg.chat.kind else {
+        return Ok(());
+    };
+    if !can_view_stat(msg.from.as_ref()) {
+        bot.send_message(msg.chat.id, "Нет доступа к статистике")
+            .await?;
+        return Ok(());
+    }
+    match trend_report().await {
+        Ok(report) => send_msg_to_chat(&bot, msg.chat.id.0, &report).await,
+        Err(e) => {
+            bot.send_message(msg.chat.id, "Ошибка чтения данных")
+                .await?;
+            let admin_id = ChatId(config().ADMIN_ID);
+            bot.send_message(admin_id, e.to_string()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn can_view_stat(user: Option<&User>) -> bool {
+    user.is_some_and(|user| {
+        let id = user.id.0 as i64;
+        id == config().ADMIN_ID || config().STAT_USERS.contains(&id)
+    })
+}
+
+/// Alert recipients besides the stat users: the director and the managers
+fn can_handle_alerts(user: &User) -> bool {
+    let id = user.id.0 as i64;
+    can_view_stat(Some(user))
+        || id == config().DIRECTOR_ID
+        || managers().values().any(|&manager| manager == id)
+}
+
+fn stat_keyboard(view: &StatView) -> InlineKeyboardMarkup {
+    let mut keyboard: Vec<Vec<InlineKeyboardButton>> = view
+        .buttons
+        .chunks(2)
+        .map(|row| {
+            row.iter()
+                .map(|(label, path)| InlineKeyboardButton::callback(label, path.callback_data()))
+                .collect()
+        })
+        .collect();
+    if let Some(back) = &view.back {
+        keyboard.push(vec![InlineKeyboardButton::callback(
+            "⬅️ Назад",
+            back.callback_data(),
+        )]);
+    }
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+async fn stat_handler(bot: Bot, msg: Message) -> HandlerResult {
+    let ChatKind::Private(_) = msg.chat.kind else {
+        return Ok(());
+    };
+    if !can_view_stat(msg.from.as_ref()) {
+        bot.send_message(msg.chat.id, "Нет доступа к статистике")
+            .await?;
+        return Ok(());
+    }
+    match get_stat_view(&StatPath::default()).await {
+        Ok(view) => {
+            bot.send_message(msg.chat.id, truncate_message(&view.text))
+                .reply_markup(stat_keyboard(&view))
+                .await?;
+        }
+        Err(e) => {
+            bot.send_message(msg.chat.id, "Ошибка чтения данных")
+                .await?;
+            let admin_id = ChatId(config().ADMIN_ID);
+            bot.send_message(admin_id, e.to_string()).await?;
+            return Ok(());
+        }
+    }
+    match get_charts(&Projects::all()).await {
+        Ok(charts) => {
+            let media = charts.into_iter().map(|c| {
+                InputMedia::Photo(
+                    InputMediaPhoto::new(InputFile::memory(c.png).file_name(c.cid))
+                        .caption(c.title),
+                )
+            });
+            bot.send_media_group(msg.chat.id, media).await?;
+        }
+        Err(e) => {
+            let admin_id = ChatId(config().ADMIN_ID);
+            bot.send_message(admin_id, format!("Ошибка построения графиков: {e}"))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn export_handler(
+    bot: Bot,
+    dialogue: MyDialogue,
+    msg: Message,
+    args: String,
+) -> HandlerResult {
+    let ChatKind::Private(_) = msg.chat.kind else {
+        return Ok(());
+    };
+    if !can_view_stat(msg.from.as_ref()) {
+        bot.send_message(msg.chat.id, "Нет доступа к выгрузке")
+            .await?;
+        return Ok(());
+    }
+    // filters are picked with the same keyboards as /start
+    if args.trim().is_empty() {
+        bot.send_message(msg.chat.id, format!("{EXPORT_USAGE}\n\nВыберите проект"))
+            .reply_markup(with_any_choice(make_kbd(1)))
+            .await?;
+        dialogue.update(State::ChooseExportProject).await?;
+        return Ok(());
+    }
+    let filter = match ExportFilter::parse(&args) {
+        Ok(filter) => filter,
+        Err(e) => {
+            bot.send_message(msg.chat.id, format!("Ошибка: {e}\n{EXPORT_USAGE}"))
+                .await?;
+            return Ok(());
+        }
+    };
+    send_export(&bot, msg.chat.id, &filter).await
+}
+
+async fn send_export(bot: &Bot, chat: ChatId, filter: &ExportFilter) -> HandlerResult {
+    match export(filter).await {
+        Ok(Some((name, content))) => {
+            bot.send_document(chat, InputFile::memory(content).file_name(name))
+                .reply_markup(ReplyMarkup::KeyboardRemove(KeyboardRemove::new()))
+                .await?;
+        }
+        Ok(None) => {
+            bot.send_message(chat, "Нет объектов по заданным фильтрам")
+                .reply_markup(ReplyMarkup::KeyboardRemove(KeyboardRemove::new()))
+                .await?;
+        }
+        Err(e) => {
+            bot.send_message(chat, "Ошибка выгрузки данных")
+                .reply_markup(ReplyMarkup::KeyboardRemove(KeyboardRemove::new()))
+                .await?;
+            let admin_id = ChatId(config().ADMIN_ID);
+            bot.send_message(admin_id, e.to_string()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn with_any_choice(keyboard: KeyboardMarkup) -> KeyboardMarkup {
+    keyboard.append_row(vec![KeyboardButton::new(ANY_CHOICE)])
+}
+
+fn choice_kbd(labels: &[&str]) -> KeyboardMarkup {
+    let keyboard: Vec<Vec<KeyboardButton>> = labels
+        .chunks(3)
+        .map(|row| {
+            row.iter()
+                .map(|&label| KeyboardButton::new(label))
+                .collect()
+        })
+        .collect();
+    KeyboardMarkup::new(keyboard).resize_keyboard()
+}
+
+/// `Some(None)` for "all", `None` when the text is not one of `choices`
+fn export_choice(msg: &Message, choices: &[&str]) -> Option<Option<String>> {
+    match msg.text()? {
+        ANY_CHOICE => Some(None),
+        text if choices.iter().any(|c| c == &text) => Some(Some(text.to_string())),
+        _ => None,
+    }
+}
+
+async fn ask_export_status(
+    bot: &Bot,
+    dialogue: MyDialogue,
+    chat: ChatId,
+    filter: ExportFilter,
+) -> HandlerResult {
+    bot.send_message(chat, "Статус объектов?")
+        .reply_markup(with_any_choice(choice_kbd(&STATUS_CHOICES)))
+        .await?;
+    dialogue
+        .update(State::ChooseExportStatus { filter })
+        .await?;
+    Ok(())
+}
+
+async fn receive_export_project(bot: Bot, dialogue: MyDialogue, msg: Message) -> HandlerResult {
+    let Some(project) = export_choice(&msg, &PROJECTS) else {
+        bot.send_message(msg.chat.id, "Сделайте выбор кнопками")
+            .await?;
+        return Ok(());
+    };
+    bot.send_message(msg.chat.id, "Квартиры или кладовки?")
+        .reply_markup(with_any_choice(make_kbd(2)))
+        .await?;
+    let filter = ExportFilter {
+        project,
+        ..Default::default()
+    };
+    dialogue.update(State::ChooseExportType { filter }).await?;
+    Ok(())
+}
+
+async fn receive_export_type(
+    bot: Bot,
+    dialogue: MyDialogue,
+    mut filter: ExportFilter, // Available from `State::ChooseExportType`.
+    msg: Message,
+) -> HandlerResult {
+    let Some(property_type) = export_choice(&msg, &PROPERTY_TYPES) else {
+        bot.send_message(msg.chat.id, "Сделайте выбор кнопками")
+            .await?;
+        return Ok(());
+    };
+    filter.property_type = property_type;
+    // houses are listed per project and type
+    let houses = match (&filter.project, &filter.property_type) {
+        (Some(project), Some(property_type)) => make_house_kbd(project, property_type).await,
+        _ => None,
+    };
+    match houses {
+        Some(keyboard) => {
+            bot.send_message(msg.chat.id, "Выберите номер дома")
+                .reply_markup(with_any_choice(keyboard))
+                .await?;
+            dialogue.update(State::ChooseExportHouse { filter }).await?;
+        }
+        None => ask_export_status(&bot, dialogue, msg.chat.id, filter).await?,
+    }
+    Ok(())
+}
+
+async fn receive_export_house(
+    bot: Bot,
+    dialogue: MyDialogue,
+    mut filter: ExportFilter, // Available from `State::ChooseExportHouse`.
+    msg: Message,
+) -> HandlerResult {
+    let houses = get_house_numbers(
+        filter.project.as_deref().unwrap_or_default(),
+        filter.property_type.as_deref().unwrap_or_default(),
+    )
+    .await;
+    let houses: Vec<&str> = houses.iter().map(String::as_str).collect();
+    let Some(house) = export_choice(&msg, &houses) else {
+        bot.send_message(msg.chat.id, "Сделайте выбор кнопками")
+            .await?;
+        return Ok(());
+    };
+    filter.house = house;
+    ask_export_status(&bot, dialogue, msg.chat.id, filter).await
+}
+
+async fn receive_export_status(
+    bot: Bot,
+    dialogue: MyDialogue,
+    mut filter: ExportFilter, // Available from `State::ChooseExportStatus`.
+    msg: Message,
+) -> HandlerResult {
+    let Some(status) = export_choice(&msg, &STATUS_CHOICES) else {
+        bot.send_message(msg.chat.id, "Сделайте выбор кнопками")
+            .await?;
+        return Ok(());
+    };
+    filter.status = status.as_deref().and_then(ExportStatus::parse);
+    bot.send_message(msg.chat.id, "Формат файла?")
+        .reply_markup(choice_kbd(&FORMAT_CHOICES))
+        .await?;
+    dialogue
+        .update(State::ChooseExportFormat { filter })
+        .await?;
+    Ok(())
+}
+
+async fn receive_export_format(
+    bot: Bot,
+    dialogue: MyDialogue,
+    mut filter: ExportFilter, // Available from `State::ChooseExportFormat`.
+    msg: Message,
+) -> HandlerResult {
+    let Some(format) = msg.text().and_then(ExportFormat::parse) else {
+        bot.send_message(msg.chat.id, "Сделайте выбор кнопками")
+            .await?;
+        return Ok(());
+    };
+    filter.format = format;
+    dialogue.exit().await?;
+    send_export(&bot, msg.chat.id, &filter).await
+}
+
+async fn stat_callback(bot: Bot, q: CallbackQuery, path: StatPath) -> HandlerResult {
+    if !can_view_stat(Some(&q.from)) {
+        bot.answer_callback_query(q.id.clone())
+            .text("Нет доступа к статистике")
+            .await?;
+        return Ok(());
+    }
+    bot.answer_callback_query(q.id.clone()).await?;
+    let Some(msg) = q.regular_message() else {
+        return Ok(());
+    };
+    let view = get_stat_view(&path).await?;
+    bot.edit_message_text(msg.chat.id, msg.id, truncate_message(&view.text))
+        .reply_markup(stat_keyboard(&view))
+        .await?;
+
+    Ok(())
+}
+
+async fn ics_handler(bot: Bot, msg: Message) -> HandlerResult {
+    let ChatKind::Private(_) = msg.chat.kind else {
+        return Ok(());
+    };
+    if !can_view_stat(msg.from.as_ref()) {
+        bot.send_message(msg.chat.id, "Нет доступа к календарю")
+            .await?;
+        return Ok(());
+    }
+    match ics::read_feed().await {
+        Ok(content) => {
+            bot.send_document(
+                msg.chat.id,
+                InputFile::memory(content).file_name("deadlines.ics"),
+            )
+            .await?;
+        }
+        Err(e) => {
+            bot.send_message(msg.chat.id, "Ошибка чтения данных")
+                .await?;
+            let admin_id = ChatId(config().ADMIN_ID);
+            bot.send_message(admin_id, e.to_string()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn calendar_callback(bot: Bot, q: CallbackQuery, week: usize) -> HandlerResult {
+    if !can_view_stat(Some(&q.from)) {
+        bot.answer_callback_query(q.id.clone())
+            .text("Нет доступа к статистике")
+            .await?;
+        return Ok(());
+    }
+    bot.answer_callback_query(q.id.clone()).await?;
+    let Some(msg) = q.regular_message() else {
+        return Ok(());
+    };
+    let forecast = get_forecast().await?;
+    bot.edit_message_text(msg.chat.id, msg.id, forecast.week_text(week))
+        .reply_markup(calendar_keyboard(week, forecast.weeks()))
+        .await?;
+
+    Ok(())
+}
+
+async fn act_callback(
+    bot: Bot,
+    q: CallbackQuery,
+    (action, deal): (ActAction, i32),
+) -> HandlerResult {
+    // the email goes to every act subscriber, not to the one who pressed the button
+    if !can_view_stat(Some(&q.from)) {
+        bot.answer_callback_query(q.id.clone())
+            .text("Нет доступа к актам")
+            .await?;
+        return Ok(());
+    }
+    bot.answer_callback_query(q.id.clone()).await?;
+    let Some(msg) = q.regular_message() else {
+        return Ok(());
+    };
+    let result = match action {
+        ActAction::Download => match get_act(deal).await {
+            Ok((_, act)) => {
+                bot.send_document(
+                    msg.chat.id,
+                    InputFile::memory(act.content).file_name(act.name),
+                )
+                .await?;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+        ActAction::Email => match send_act(deal).await {
+            Ok(sent) => {
+                let reply = if sent > 0 {
+                    "Акт поставлен в очередь на отправку"
+                } else {
+                    "Нет подписчиков на акты по проекту сделки"
+                };
+                bot.send_message(msg.chat.id, reply).await?;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+    };
+    if let Err(e) = result {
+        bot.send_message(msg.chat.id, "Ошибка формирования акта")
+            .await?;
+        let admin_id = ChatId(config().ADMIN_ID);
+        bot.send_message(admin_id, e.to_string()).await?;
+    }
+
+    Ok(())
+}
+
+/// Reschedules waiting for the date by chat and user: the group is shared,
+/// a dialogue state of the whole chat would be taken over by the next user
+static RESCHEDULES: LazyLock<Mutex<HashMap<(ChatId, UserId), i32>>> =
+    LazyLock::new(Default::default);
+
+fn pending_reschedule(msg: &Message) -> Option<i32> {
+    let user = msg.from.as_ref()?.id;
+    RESCHEDULES.lock().ok()?.get(&(msg.chat.id, user)).copied()
+}
+
+fn set_pending_reschedule(chat: ChatId, user: UserId, deal: Option<i32>) {
+    if let Ok(mut pending) = RESCHEDULES.lock() {
+        match deal {
+            Some(deal) => pending.insert((chat, user), deal),
+            None => pending.remove(&(chat, user)),
+        };
+    }
+}
+
+async fn alert_callback(bot: Bot, q: CallbackQuery) -> HandlerResult {
+    if !can_handle_alerts(&q.from) {
+        bot.answer_callback_query(q.id.clone())
+            .text("Нет доступа к уведомлениям")
+            .await?;
+        return Ok(());
+    }
+    bot.answer_callback_query(q.id.clone()).await?;
+    let Some((action, deal)) = q.data.as_deref().and_then(AlertAction::parse_callback) else {
+        return Ok(());
+    };
+    let Some(msg) = q.regular_message() else {
+        return Ok(());
+    };
+
+    let user_name = q.from.full_name();
+    let actor = Actor {
+        user_id: q.from.id.0 as i64,
+        user_name: &user_name,
+    };
+    let alert_text = msg.text().unwrap_or_default();
+    let db = Db::new().await;
+
+    match action {
+        AlertAction::Ack => {
+            let reason = "Принято в работу";
+            db.acknowledge_alert(deal, reason, &actor).await?;
+            bot.edit_message_text(
+                msg.chat.id,
+                msg.id,
+                format!("{alert_text}\n\n✅ {reason}: {user_name}"),
+            )
+            .await?;
+        }
+        AlertAction::Snooze => {
+            let schedule = Schedule::from_str(&config().DEADLINE_SCHEDULE)?;
+            let until = snooze_until(&schedule, clock::local_now());
+            let reason = format!("Отложено до {}", to_local(&until).format("%d.%m.%Y %H:%M"));
+            db.snooze_alert(deal, until, &reason, &actor).await?;
+            bot.edit_message_text(
+                msg.chat.id,
+                msg.id,
+                format!("{alert_text}\n\n⏸ {reason}: {user_name}"),
+            )
+            .await?;
+        }
+        AlertAction::Reschedule => {
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "{user_name}, введите согласованную дату передачи и причину переноса:\nдд.мм.гггг причина"
+                ),
+            )
+            .await?;
+            set_pending_reschedule(msg.chat.id, q.from.id, Some(deal));
+        }
+    }
+
+    Ok(())
+}
+
+async fn receive_agreed_date(
+    bot: Bot,
+    deal: i32, // Available from `pending_reschedule`.
+    msg: Message,
+) -> HandlerResult {
+    let Some(from) = msg.from.as_ref() else {
+        return Ok(());
+    };
+    // the reason is the point of the audit trail, a bare date is asked again
+    match parse_agreed_date(msg.text().unwrap_or_default(), clock::today()) {
+        Some((new_date, reason)) => {
+            let user_name = from.full_name();
+            let actor = Actor {
+                user_id: from.id.0 as i64,
+                user_name: &user_name,
+            };
+            let db = Db::new().await;
+            db.reschedule_deadline(deal, new_date, reason, &actor)
+                .await?;
+            ics::refresh().await;
+            let card = deal_card(&db.get_deal_by_id(deal).await?);
+            bot.send_message(
+                msg.chat.id,
+                format!("Новая дата передачи сохранена ({user_name})\n\n{card}"),
+            )
+            .await?;
+            set_pending_reschedule(msg.chat.id, from.id, None);
+        }
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "Шаблон: дд.мм.гггг причина, дата не раньше сегодняшней, причина обязательна",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
 async fn sync_handler(bot: Bot, msg: Message) -> HandlerResult {
     if let ChatKind::Private(_) = msg.chat.kind {
         bot.send_message(msg.chat.id, "Начат поиск новых сделок...".to_string())
@@ -145,6 +910,216 @@ async fn sync_handler(bot: Bot, msg: Message) -> HandlerResult {
     Ok(())
 }
 
+async fn archive_handler(bot: Bot, msg: Message, query: String) -> HandlerResult {
+    if let ChatKind::Private(_) = msg.chat.kind {
+        let query = query.trim();
+        if query.is_empty() {
+            bot.send_message(msg.chat.id, "Шаблон: /archive номер помещения")
+                .await?;
+            return Ok(());
+        }
+        match search_archived_deals(query).await {
+            Ok(cards) if cards.is_empty() => {
+                bot.send_message(msg.chat.id, "В архиве ничего не найдено")
+                    .await?;
+            }
+            Ok(cards) => {
+                for card in cards {
+                    bot.send_message(msg.chat.id, card).await?;
+                }
+            }
+            Err(e) => {
+                bot.send_message(msg.chat.id, "Ошибка чтения данных")
+                    .await?;
+                let admin_id = ChatId(config().ADMIN_ID);
+                bot.send_message(admin_id, e.to_string()).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_admin(msg: &Message) -> bool {
+    matches!(msg.chat.kind, ChatKind::Private(_))
+        && msg
+            .from
+            .as_ref()
+            .is_some_and(|user| user.id.0 as i64 == config().ADMIN_ID)
+}
+
+async fn outbox_handler(bot: Bot, msg: Message) -> HandlerResult {
+    if !is_admin(&msg) {
+        return Ok(());
+    }
+    let reply = match get_outbox_text().await {
+        Ok(text) => text,
+        Err(e) => format!("Ошибка чтения очереди писем: {e}"),
+    };
+    send_msg_to_chat(&bot, msg.chat.id.0, &reply).await;
+    Ok(())
+}
+
+async fn subscribers_handler(bot: Bot, msg: Message) -> HandlerResult {
+    if !is_admin(&msg) {
+        return Ok(());
+    }
+    let db = Db::new().await;
+    let reply = match db.list_subscriptions().await {
+        Ok(subscriptions) => subscriptions_text(&subscriptions),
+        Err(e) => format!("Ошибка чтения подписок: {e}"),
+    };
+    send_msg_to_chat(&bot, msg.chat.id.0, &reply).await;
+    Ok(())
+}
+
+async fn subscribe_handler(bot: Bot, msg: Message, args: String) -> HandlerResult {
+    if !is_admin(&msg) {
+        return Ok(());
+    }
+    let subscription = match parse_subscription(&args) {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            bot.send_message(msg.chat.id, e).await?;
+            return Ok(());
+        }
+    };
+    let db = Db::new().await;
+    let reply = match db.save_subscription(&subscription).await {
+        Ok(()) => match db.list_subscriptions().await {
+            Ok(subscriptions) => {
+                format!(
+                    "Подписка сохранена\n\n{}",
+                    subscriptions_text(&subscriptions)
+                )
+            }
+            Err(e) => format!("Подписка сохранена, но список не прочитан: {e}"),
+        },
+        Err(e) => format!("Подписка не сохранена: {e}"),
+    };
+    send_msg_to_chat(&bot, msg.chat.id.0, &reply).await;
+    Ok(())
+}
+
+async fn unsubscribe_handler(bot: Bot, msg: Message, email: String) -> HandlerResult {
+    if !is_admin(&msg) {
+        return Ok(());
+    }
+    if email.trim().is_empty() {
+        bot.send_message(msg.chat.id, "Шаблон: /unsubscribe адрес из /subscribers")
+            .await?;
+        return Ok(());
+    }
+    let db = Db::new().await;
+    let reply = match db.delete_subscription(&email).await {
+        Ok(true) => format!("Подписка {} удалена", email.trim()),
+        Ok(false) => format!("Подписка {} не найдена", email.trim()),
+        Err(e) => format!("Подписка не удалена: {e}"),
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
+
+async fn rules_handler(bot: Bot, msg: Message) -> HandlerResult {
+    if !is_admin(&msg) {
+        return Ok(());
+    }
+    let db = Db::new().await;
+    let reply = match db.list_rules().await {
+        Ok(rules) => rules.to_string(),
+        Err(e) => format!("Ошибка чтения правил: {e}"),
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
+
+async fn add_rule_handler(bot: Bot, msg: Message, rule: String) -> HandlerResult {
+    if !is_admin(&msg) {
+        return Ok(());
+    }
+    let rule = match parse_rule(&rule) {
+        Ok(rule) => rule,
+        Err(e) => {
+            bot.send_message(msg.chat.id, e).await?;
+            return Ok(());
+        }
+    };
+    let db = Db::new().await;
+    if let Err(e) = db.add_rule(&rule).await {
+        bot.send_message(msg.chat.id, format!("Правило не добавлено: {e}"))
+            .await?;
+        return Ok(());
+    }
+    recalculate_limits(&bot, msg.chat.id, &db, "Правило добавлено").await
+}
+
+async fn del_rule_handler(bot: Bot, msg: Message, id: String) -> HandlerResult {
+    if !is_admin(&msg) {
+        return Ok(());
+    }
+    let Ok(id) = id.trim().parse::<i64>() else {
+        bot.send_message(msg.chat.id, "Шаблон: /delrule номер правила из /rules")
+            .await?;
+        return Ok(());
+    };
+    let db = Db::new().await;
+    match db.delete_rule(id).await {
+        Ok(true) => recalculate_limits(&bot, msg.chat.id, &db, "Правило удалено").await,
+        Ok(false) => {
+            bot.send_message(msg.chat.id, format!("Правило #{id} не найдено"))
+                .await?;
+            Ok(())
+        }
+        Err(e) => {
+            bot.send_message(msg.chat.id, format!("Правило не удалено: {e}"))
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+async fn recalculate_limits(bot: &Bot, chat_id: ChatId, db: &Db, header: &str) -> HandlerResult {
+    let reply = match db.apply_rules().await {
+        Ok(changes) => {
+            ics::refresh().await;
+            format!("{header}\n{}", changes_report(&changes))
+        }
+        Err(e) => format!("{header}, но пересчёт сроков не выполнен: {e}"),
+    };
+    send_msg_to_chat(bot, chat_id.0, &reply).await;
+    Ok(())
+}
+
+async fn import_handler(bot: Bot, msg: Message) -> HandlerResult {
+    let Some(doc) = msg.document() else {
+        return Ok(());
+    };
+    if !is_admin(&msg) {
+        return Ok(());
+    }
+
+    let file_name = doc.file_name.clone().unwrap_or_default();
+    info!("[import_handler] {file_name}");
+    bot.send_message(msg.chat.id, format!("Импорт сделок из {file_name}..."))
+        .await?;
+
+    let file = bot.get_file(doc.file.id.clone()).await?;
+    let mut buf: Vec<u8> = vec![];
+    bot.download_file(&file.path, &mut buf).await?;
+
+    let db = Db::new().await;
+    let reply = match import_deals(&db, &file_name, &buf).await {
+        Ok(report) => {
+            ics::refresh().await;
+            report.to_string()
+        }
+        Err(e) => format!("Импорт не выполнен: {e}"),
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+
+    Ok(())
+}
+
 async fn start(bot: Bot, dialogue: MyDialogue, msg: Message) -> HandlerResult {
     if let Some(text) = msg.text()
         && text.starts_with("/start")
@@ -258,8 +1233,8 @@ async fn receive_house_number(
                             .await?;
                     } else {
                         let number = *numbers.first().unwrap();
-                        let report = prepare_response(&project, &property_type, house, number).await;
-                        bot.send_message(msg.chat.id, report).await?;
+                        send_deal_card(&bot, msg.chat.id, &project, &property_type, house, number)
+                            .await?;
                         bot.send_message(msg.chat.id, "Чтобы начать сначала,\n нажмите /start")
                             .reply_markup(ReplyMarkup::KeyboardRemove(KeyboardRemove::new()))
                             .await?;
@@ -297,8 +1272,8 @@ async fn receive_property_number(
             Ok(number) => {
                 let objects = get_property_numbers(&project, &property_type, &house).await;
                 if objects.contains(&number) {
-                    let report = prepare_response(&project, &property_type, &house, number).await;
-                    bot.send_message(msg.chat.id, report).await?;
+                    send_deal_card(&bot, msg.chat.id, &project, &property_type, &house, number)
+                        .await?;
                     if objects.len() == 1 {
                         bot.send_message(msg.chat.id, "Чтобы начать сначала,\n нажмите /start")
                             .reply_markup(ReplyMarkup::KeyboardRemove(KeyboardRemove::new()))
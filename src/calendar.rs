@@ -0,0 +1,259 @@
+use crate::Result;
+use crate::clock::to_local;
+use crate::config::config;
+use crate::error::Error;
+use chrono::{Datelike, Days, NaiveDate, NaiveDateTime, Weekday};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+static INSTANCE: OnceLock<Calendar> = OnceLock::new();
+
+/// Loads `CALENDAR_FILE` and `DAY_COUNTING`, called at startup so a broken file stops the bot
+pub fn init_calendar() -> Result<()> {
+    let cal = Calendar::load(&config().CALENDAR_FILE, &config().DAY_COUNTING)?;
+    INSTANCE
+        .set(cal)
+        .map_err(|_| Error::AppErr("Calendar is already loaded".to_string()))
+}
+
+/// Weekends only with calendar days counting until `init_calendar`
+pub fn calendar() -> &'static Calendar {
+    INSTANCE.get_or_init(Calendar::default)
+}
+
+/// Transfer deadline shared by deal cards, emails, xlsx and the deadline search
+pub fn deadline(
+    created_on: &NaiveDateTime,
+    days_limit: i32,
+    project: &str,
+    property_type: &str,
+) -> NaiveDate {
+    calendar().deadline(created_on, days_limit, project, property_type)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DayCounting {
+    #[default]
+    Calendar,
+    Business,
+}
+
+#[derive(Debug, Default)]
+pub struct Calendar {
+    holidays: HashSet<NaiveDate>,
+    workdays: HashSet<NaiveDate>,
+    counting: HashMap<String, DayCounting>,
+}
+
+impl Calendar {
+    fn load(path: &str, counting: &str) -> Result<Calendar> {
+        let mut cal = Calendar {
+            counting: parse_counting(counting)?,
+            ..Default::default()
+        };
+        if !path.is_empty() {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| Error::AppErr(format!("Failed to read {path}: {e}")))?;
+            cal.parse_days(&content)?;
+            info!(
+                "Production calendar loaded: {} holidays, {} working weekends",
+                cal.holidays.len(),
+                cal.workdays.len()
+            );
+        }
+        Ok(cal)
+    }
+
+    /// One date per line: `2025-01-01 holiday` or `2025-11-01 workday`, `#` starts a comment
+    fn parse_days(&mut self, content: &str) -> Result<()> {
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let wrong_line = || Error::AppErr(format!("Calendar line {}: {line}", idx + 1));
+            let (date, kind) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(wrong_line)?;
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| wrong_line())?;
+            match kind.trim() {
+                "holiday" => self.holidays.insert(date),
+                "workday" => self.workdays.insert(date),
+                _ => return Err(wrong_line()),
+            };
+        }
+        Ok(())
+    }
+
+    /// `created_on` is UTC, days are counted from the local sale date
+    pub fn deadline(
+        &self,
+        created_on: &NaiveDateTime,
+        days_limit: i32,
+        project: &str,
+        property_type: &str,
+    ) -> NaiveDate {
+        self.add_days(
+            to_local(created_on).date(),
+            days_limit,
+            self.counting_for(project, property_type),
+        )
+    }
+
+    pub fn is_working_day(&self, date: NaiveDate) -> bool {
+        if self.workdays.contains(&date) {
+            return true;
+        }
+        if self.holidays.contains(&date) {
+            return false;
+        }
+        !matches!(date.weekday(), Weekday::Sat | Weekday::Sun)
+    }
+
+    /// The most specific rule wins: "project/type", then type, then project
+    pub fn counting_for(&self, project: &str, property_type: &str) -> DayCounting {
+        [
+            format!("{project}/{property_type}"),
+            property_type.to_string(),
+            project.to_string(),
+        ]
+        .iter()
+        .find_map(|key| self.counting.get(key).copied())
+        .unwrap_or_default()
+    }
+
+    pub fn add_days(&self, start: NaiveDate, days: i32, counting: DayCounting) -> NaiveDate {
+        let days = days.max(0) as u64;
+        match counting {
+            DayCounting::Calendar => start.checked_add_days(Days::new(days)).unwrap_or(start),
+            DayCounting::Business => {
+                let mut date = start;
+                let mut left = days;
+                while left > 0 {
+                    let Some(next) = date.succ_opt() else {
+                        break;
+                    };
+                    date = next;
+                    if self.is_working_day(date) {
+                        left -= 1;
+                    }
+                }
+                date
+            }
+        }
+    }
+
+    /// Inverse of `add_days`: the limit that reaches `end` from `start`.
+    /// An `end` on a day off counts up to the working day before it
+    pub fn days_between(&self, start: NaiveDate, end: NaiveDate, counting: DayCounting) -> i32 {
+        match counting {
+            DayCounting::Calendar => (end - start).num_days() as i32,
+            DayCounting::Business => start
+                .iter_days()
+                .skip(1)
+                .take_while(|d| *d <= end)
+                .filter(|d| self.is_working_day(*d))
+                .count() as i32,
+        }
+    }
+}
+
+/// Parses `DAY_COUNTING`, e.g. `DNS Сити=business;ЖК Формат/Кладовка=calendar`
+fn parse_counting(raw: &str) -> Result<HashMap<String, DayCounting>> {
+    raw.split(';')
+        .filter(|r| !r.trim().is_empty())
+        .map(|r| {
+            let (key, mode) = r
+                .split_once('=')
+                .ok_or(Error::ConfigWrongFormat("DAY_COUNTING"))?;
+            let mode = match mode.trim() {
+                "calendar" => DayCounting::Calendar,
+                "business" => DayCounting::Business,
+                _ => return Err(Error::ConfigWrongFormat("DAY_COUNTING")),
+            };
+            Ok((key.trim().to_string(), mode))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::date;
+
+    fn test_calendar() -> Calendar {
+        let mut cal = Calendar {
+            counting: parse_counting("DNS Сити=business;DNS Сити/Кладовка=calendar").unwrap(),
+            ..Default::default()
+        };
+        cal.parse_days(
+            "# 2025\n2025-11-01 workday\n2025-11-03 holiday\n2025-11-04 holiday # Day of Unity\n",
+        )
+        .unwrap();
+        cal
+    }
+
+    #[test]
+    fn test_business_days() {
+        let cal = test_calendar();
+        // Fri 31.10 + 3 working days: Sat 01.11 is a working day, 03.11 and 04.11 are holidays
+        let res = cal.add_days(date(2025, 10, 31), 3, DayCounting::Business);
+        assert_eq!(res, date(2025, 11, 6));
+        let res = cal.add_days(date(2025, 10, 31), 3, DayCounting::Calendar);
+        assert_eq!(res, date(2025, 11, 3));
+
+        let start = date(2025, 10, 31);
+        assert_eq!(
+            cal.days_between(start, date(2025, 11, 6), DayCounting::Business),
+            3
+        );
+        assert_eq!(
+            cal.days_between(start, date(2025, 11, 4), DayCounting::Business),
+            1
+        );
+        assert_eq!(
+            cal.days_between(start, date(2025, 11, 6), DayCounting::Calendar),
+            6
+        );
+    }
+
+    #[test]
+    fn test_counting_rules() {
+        let cal = test_calendar();
+        assert_eq!(
+            cal.counting_for("DNS Сити", "Квартира"),
+            DayCounting::Business
+        );
+        assert_eq!(
+            cal.counting_for("DNS Сити", "Кладовка"),
+            DayCounting::Calendar
+        );
+        assert_eq!(
+            cal.counting_for("ЖК Формат", "Квартира"),
+            DayCounting::Calendar
+        );
+        assert!(parse_counting("DNS Сити=weekly").is_err());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let cal = test_calendar();
+        let created = crate::clock::from_local(&date(2025, 10, 31).and_hms_opt(12, 0, 0).unwrap());
+        assert_eq!(
+            cal.deadline(&created, 3, "DNS Сити", "Квартира"),
+            date(2025, 11, 6)
+        );
+        assert_eq!(
+            cal.deadline(&created, 3, "ЖК Формат", "Квартира"),
+            date(2025, 11, 3)
+        );
+    }
+
+    #[test]
+    fn test_wrong_calendar_line() {
+        let mut cal = Calendar::default();
+        assert!(cal.parse_days("01.01.2025 holiday").is_err());
+        assert!(cal.parse_days("2025-01-01 vacation").is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use crate::Result;
+use crate::clock::{self, to_local};
+use crate::config::config;
+use crate::error::Error;
+use crate::model::Db;
+use crate::model::deal::DealData;
+use crate::model::snapshot::Snapshot;
+use crate::model::subscription::Projects;
+use chrono::{Datelike, Days, NaiveDate};
+use plotters::prelude::*;
+use plotters::style::{FontStyle, register_font};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 400;
+const FONT: &str = "sans-serif";
+/// Weeks of history and of upcoming deadlines shown on charts
+const CHART_WEEKS: usize = 12;
+
+/// PNG image, `cid` is the Content-ID it is embedded into the email with
+pub struct Chart {
+    pub cid: &'static str,
+    pub title: &'static str,
+    pub png: Vec<u8>,
+}
+
+/// Numbers behind the charts, weeks are labelled by their Monday
+#[derive(Debug, Clone, Default)]
+pub struct ChartData {
+    pub backlog: Vec<(NaiveDate, i64)>,
+    pub transfers: Vec<(String, usize)>,
+    pub deadlines: Vec<(String, usize)>,
+}
+
+impl ChartData {
+    pub fn build(
+        snapshots: &[Snapshot],
+        transferred: &[DealData],
+        undone: &[DealData],
+        today: NaiveDate,
+    ) -> ChartData {
+        ChartData {
+            backlog: backlog(snapshots),
+            transfers: transfers_per_week(transferred, today),
+            deadlines: deadlines_per_week(undone, today),
+        }
+    }
+}
+
+fn monday(date: NaiveDate) -> NaiveDate {
+    date - Days::new(date.weekday().num_days_from_monday() as u64)
+}
+
+fn week_label(monday: NaiveDate) -> String {
+    monday.format("%d.%m").to_string()
+}
+
+/// Deals in work per snapshot date, all projects together
+fn backlog(snapshots: &[Snapshot]) -> Vec<(NaiveDate, i64)> {
+    let mut days: BTreeMap<NaiveDate, i64> = BTreeMap::new();
+    for s in snapshots {
+        *days.entry(s.snapshot_date).or_default() += s.in_work;
+    }
+    days.into_iter().collect()
+}
+
+/// Transfers of the last weeks up to the current one
+fn transfers_per_week(transferred: &[DealData], today: NaiveDate) -> Vec<(String, usize)> {
+    let first = monday(today) - Days::new((CHART_WEEKS as u64 - 1) * 7);
+    let mut weeks: BTreeMap<NaiveDate, usize> = first
+        .iter_weeks()
+        .take(CHART_WEEKS)
+        .map(|w| (w, 0))
+        .collect();
+    for d in transferred {
+        let Some(transferred_on) = d.transferred_on else {
+            continue;
+        };
+        if let Some(count) = weeks.get_mut(&monday(to_local(&transferred_on).date())) {
+            *count += 1;
+        }
+    }
+    weeks
+        .into_iter()
+        .map(|(w, count)| (week_label(w), count))
+        .collect()
+}
+
+/// Upcoming deadlines by week, overdue and later ones get their own bars
+fn deadlines_per_week(undone: &[DealData], today: NaiveDate) -> Vec<(String, usize)> {
+    let first = monday(today);
+    let mut counts = vec![0; CHART_WEEKS];
+    let (mut overdue, mut later) = (0, 0);
+    for d in undone {
+        let exp_date = d.exp_date();
+        if exp_date < today {
+            overdue += 1;
+            continue;
+        }
+        let week = ((exp_date - first).num_days() / 7) as usize;
+        match counts.get_mut(week) {
+            Some(count) => *count += 1,
+            None => later += 1,
+        }
+    }
+
+    let mut bars = vec![("Просроч.".to_string(), overdue)];
+    bars.extend(
+        first
+            .iter_weeks()
+            .zip(counts)
+            .map(|(w, count)| (week_label(w), count)),
+    );
+    bars.push(("Позже".to_string(), later));
+    bars
+}
+
+/// Labels are drawn with a TTF file, no system font lookup is needed on a headless server
+fn load_font(path: &str) -> Result<()> {
+    static LOADED: OnceLock<std::result::Result<(), String>> = OnceLock::new();
+    LOADED
+        .get_or_init(|| {
+            let bytes = std::fs::read(path)
+                .map_err(|e| format!("Failed to read chart font {path}: {e}"))?;
+            register_font(FONT, FontStyle::Normal, Box::leak(bytes.into_boxed_slice()))
+                .map_err(|_| format!("Invalid chart font {path}"))
+        })
+        .clone()
+        .map_err(Error::AppErr)
+}
+
+/// Font of charts and PDF tests, the tests are skipped on systems without it
+#[cfg(test)]
+pub fn test_font() -> Option<&'static str> {
+    const PATH: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+    if std::path::Path::new(PATH).exists() {
+        Some(PATH)
+    } else {
+        eprintln!("skipped: test font {PATH} is missing");
+        None
+    }
+}
+
+fn plot_err(e: impl std::fmt::Display) -> Error {
+    Error::AppErr(format!("Failed to draw chart: {e}"))
+}
+
+/// Draws into an RGB buffer and encodes it as PNG
+fn render_png(draw: impl FnOnce(&mut [u8]) -> Result<()>) -> Result<Vec<u8>> {
+    let mut pixels = vec![0u8; (WIDTH * HEIGHT * 3) as usize];
+    draw(&mut pixels)?;
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, WIDTH, HEIGHT);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(png)
+}
+
+fn line_chart(title: &str, points: &[(NaiveDate, i64)]) -> Result<Vec<u8>> {
+    render_png(|pixels| {
+        let root = BitMapBackend::with_buffer(pixels, (WIDTH, HEIGHT)).into_drawing_area();
+        root.fill(&WHITE).map_err(plot_err)?;
+        let max = points.iter().map(|(_, v)| *v).max().unwrap_or_default();
+        let mut chart = ChartBuilder::on(&root)
+            .caption(title, (FONT, 20))
+            .margin(15)
+            .x_label_area_size(30)
+            .y_label_area_size(40)
+            .build_cartesian_2d(0..points.len().max(2) - 1, 0..max + max / 10 + 1)
+            .map_err(plot_err)?;
+        chart
+            .configure_mesh()
+            .x_labels(points.len().clamp(2, 10))
+            .x_label_formatter(&|idx| {
+                points
+                    .get(*idx)
+                    .map(|(d, _)| d.format("%d.%m").to_string())
+                    .unwrap_or_default()
+            })
+            .label_style((FONT, 12))
+            .draw()
+            .map_err(plot_err)?;
+        let series = points.iter().enumerate().map(|(idx, (_, v))| (idx, *v));
+        chart
+            .draw_series(LineSeries::new(series.clone(), BLUE.stroke_width(2)))
+            .map_err(plot_err)?;
+        chart
+            .draw_series(series.map(|p| Circle::new(p, 3, BLUE.filled())))
+            .map_err(plot_err)?;
+        root.present().map_err(plot_err)?;
+        Ok(())
+    })
+}
+
+fn bar_chart(title: &str, bars: &[(String, usize)]) -> Result<Vec<u8>> {
+    render_png(|pixels| {
+        let root = BitMapBackend::with_buffer(pixels, (WIDTH, HEIGHT)).into_drawing_area();
+        root.fill(&WHITE).map_err(plot_err)?;
+        let max = bars.iter().map(|(_, v)| *v).max().unwrap_or_default();
+        let mut chart = ChartBuilder::on(&root)
+            .caption(title, (FONT, 20))
+            .margin(15)
+            .x_label_area_size(30)
+            .y_label_area_size(40)
+            .build_cartesian_2d(
+                (0..bars.len().max(1) - 1).into_segmented(),
+                0..max + max / 10 + 1,
+            )
+            .map_err(plot_err)?;
+        chart
+            .configure_mesh()
+            .disable_x_mesh()
+            .x_labels(bars.len().max(2))
+            .x_label_formatter(&|segment| match segment {
+                SegmentValue::CenterOf(idx) => {
+                    bars.get(*idx).map(|(l, _)| l.clone()).unwrap_or_default()
+                }
+                _ => String::new(),
+            })
+            .label_style((FONT, 12))
+            .draw()
+            .map_err(plot_err)?;
+        chart
+            .draw_series(
+                Histogram::vertical(&chart)
+                    .style(BLUE.mix(0.7).filled())
+                    .margin(6)
+                    .data(bars.iter().enumerate().map(|(idx, (_, v))| (idx, *v))),
+            )
+            .map_err(plot_err)?;
+        root.present().map_err(plot_err)?;
+        Ok(())
+    })
+}
+
+/// `font` is the TTF file of titles and labels
+pub fn render(data: &ChartData, font: &str) -> Result<Vec<Chart>> {
+    load_font(font)?;
+    Ok(vec![
+        Chart {
+            cid: "backlog",
+            title: "Объекты в работе",
+            png: line_chart("Объекты в работе", &data.backlog)?,
+        },
+        Chart {
+            cid: "transfers",
+            title: "Передано по неделям",
+            png: bar_chart("Передано по неделям", &data.transfers)?,
+        },
+        Chart {
+            cid: "deadlines",
+            title: "Сроки передачи по неделям",
+            png: bar_chart("Сроки передачи по неделям", &data.deadlines)?,
+        },
+    ])
+}
+
+/// Charts of the covered projects
+pub async fn get_charts(projects: &Projects) -> Result<Vec<Chart>> {
+    let db = Db::new().await;
+    let today = clock::today();
+    let from = today - Days::new(CHART_WEEKS as u64 * 7);
+    let snapshots = projects.filter(&db.snapshots_between(from, today).await?, |s| &s.project);
+    let transferred = projects.filter(&db.transferred_deals().await?, |d| &d.project);
+    let undone = projects.filter(&db.get_all_undone_deals().await?, |d| &d.project);
+    render(
+        &ChartData::build(&snapshots, &transferred, &undone, today),
+        &config().CHART_FONT,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::date;
+
+    fn deal(created: NaiveDate, transferred: Option<NaiveDate>) -> DealData {
+        DealData::builder()
+            .created(created)
+            .transferred(transferred)
+            .build()
+    }
+
+    #[test]
+    fn test_chart_data() {
+        // Wednesday
+        let today = date(2025, 11, 5);
+        let snapshot = |d: NaiveDate, property_type: &str, in_work: i64| Snapshot {
+            snapshot_date: d,
+            project: "ЖК Формат".to_string(),
+            property_type: property_type.to_string(),
+            in_work,
+            ..Default::default()
+        };
+        let snapshots = vec![
+            snapshot(date(2025, 11, 4), "Квартира", 10),
+            snapshot(date(2025, 11, 4), "Кладовка", 2),
+            snapshot(date(2025, 11, 5), "Квартира", 9),
+        ];
+        let transferred = vec![
+            deal(date(2025, 10, 1), Some(date(2025, 11, 3))),
+            deal(date(2025, 10, 1), Some(date(2025, 11, 5))),
+            deal(date(2025, 10, 1), Some(date(2025, 10, 29))),
+            deal(date(2024, 10, 1), Some(date(2024, 10, 29))),
+        ];
+        let undone = vec![
+            // deadline 31.10 is overdue
+            deal(date(2025, 10, 1), None),
+            // 06.11 - the current week
+            deal(date(2025, 10, 7), None),
+            deal(date(2026, 1, 7), None),
+        ];
+        let data = ChartData::build(&snapshots, &transferred, &undone, today);
+
+        assert_eq!(
+            data.backlog,
+            vec![(date(2025, 11, 4), 12), (date(2025, 11, 5), 9)]
+        );
+        assert_eq!(data.transfers.len(), CHART_WEEKS);
+        assert_eq!(data.transfers[CHART_WEEKS - 1], ("03.11".to_string(), 2));
+        assert_eq!(data.transfers[CHART_WEEKS - 2], ("27.10".to_string(), 1));
+        assert_eq!(data.deadlines.len(), CHART_WEEKS + 2);
+        assert_eq!(data.deadlines[0].1, 1);
+        assert_eq!(data.deadlines[1], ("03.11".to_string(), 1));
+        assert_eq!(data.deadlines[CHART_WEEKS + 1], ("Позже".to_string(), 1));
+    }
+
+    #[test]
+    fn test_render() {
+        let Some(font) = test_font() else {
+            return;
+        };
+        let data = ChartData {
+            backlog: vec![(date(2025, 11, 4), 12)],
+            transfers: vec![("27.10".to_string(), 1), ("03.11".to_string(), 2)],
+            deadlines: vec![],
+        };
+        let charts = render(&data, font).unwrap();
+        assert_eq!(charts.len(), 3);
+        for chart in charts {
+            assert!(chart.png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        }
+    }
+}
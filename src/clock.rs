@@ -0,0 +1,139 @@
+use crate::Result;
+use crate::config::config;
+use crate::error::Error;
+use chrono::{DateTime, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::sync::OnceLock;
+
+static TZ: OnceLock<Tz> = OnceLock::new();
+
+/// Takes `TIMEZONE`, called at startup before any worker is scheduled
+pub fn init_clock() -> Result<()> {
+    TZ.set(config().TIMEZONE)
+        .map_err(|_| Error::AppErr("Timezone is already set".to_string()))
+}
+
+/// Business timezone, UTC until `init_clock`. Timestamps are stored in UTC
+/// and converted for display, scheduling and deadline math.
+pub fn tz() -> Tz {
+    *TZ.get_or_init(|| Tz::UTC)
+}
+
+/// Current moment in the form it is stored in the database
+pub fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+pub fn local_now() -> DateTime<Tz> {
+    Utc::now().with_timezone(&tz())
+}
+
+pub fn today() -> NaiveDate {
+    local_now().date_naive()
+}
+
+pub fn to_local(utc: &NaiveDateTime) -> NaiveDateTime {
+    to_local_in(&tz(), utc)
+}
+
+pub fn from_local(local: &NaiveDateTime) -> NaiveDateTime {
+    from_local_in(&tz(), local)
+}
+
+pub fn from_timestamp(ts: i64) -> NaiveDateTime {
+    DateTime::from_timestamp(ts, 0)
+        .unwrap_or_default()
+        .naive_utc()
+}
+
+/// Time to sleep until the next fire of a worker schedule
+pub fn until_next(schedule: &Schedule) -> Option<std::time::Duration> {
+    until_next_in(schedule, &tz(), Utc::now())
+}
+
+#[cfg(test)]
+pub fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(y, m, d).unwrap()
+}
+
+fn to_local_in(tz: &Tz, utc: &NaiveDateTime) -> NaiveDateTime {
+    tz.from_utc_datetime(utc).naive_local()
+}
+
+/// Wall time repeated when clocks go back takes the first occurrence,
+/// wall time skipped when clocks go forward is moved past the gap
+pub fn from_local_in(tz: &Tz, local: &NaiveDateTime) -> NaiveDateTime {
+    match tz.from_local_datetime(local) {
+        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt.naive_utc(),
+        LocalResult::None => from_local_in(tz, &(*local + Duration::hours(1))),
+    }
+}
+
+fn until_next_in(schedule: &Schedule, tz: &Tz, now: DateTime<Utc>) -> Option<std::time::Duration> {
+    let next = schedule.after(&now.with_timezone(tz)).next()?;
+    (next.with_timezone(&Utc) - now).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_local_time_across_dst() {
+        let berlin = chrono_tz::Europe::Berlin;
+        // clocks go forward at 02:00 CET on 30.03.2025
+        assert_eq!(
+            to_local_in(&berlin, &dt("2025-03-30 00:59")),
+            dt("2025-03-30 01:59")
+        );
+        assert_eq!(
+            to_local_in(&berlin, &dt("2025-03-30 01:00")),
+            dt("2025-03-30 03:00")
+        );
+        // 02:30 does not exist that night
+        assert_eq!(
+            from_local_in(&berlin, &dt("2025-03-30 02:30")),
+            dt("2025-03-30 01:30")
+        );
+        // clocks go back at 03:00 CEST on 26.10.2025, 02:30 happens twice
+        assert_eq!(
+            from_local_in(&berlin, &dt("2025-10-26 02:30")),
+            dt("2025-10-26 00:30")
+        );
+        assert_eq!(
+            from_local_in(&berlin, &dt("2025-10-26 09:00")),
+            dt("2025-10-26 08:00")
+        );
+    }
+
+    #[test]
+    fn test_local_date_of_utc_timestamp() {
+        let moscow = chrono_tz::Europe::Moscow;
+        // a deal sold late in the evening belongs to the next day in UTC+3
+        let sold = from_timestamp(dt("2025-10-25 22:30").and_utc().timestamp());
+        assert_eq!(
+            to_local_in(&moscow, &sold).date(),
+            NaiveDate::from_ymd_opt(2025, 10, 26).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_schedule_across_dst() {
+        let berlin = chrono_tz::Europe::Berlin;
+        let schedule = Schedule::from_str("0 0 9 * * * *").unwrap();
+        // 10:00 CET on the day before the switch, next 09:00 is already CEST
+        let now = dt("2025-03-29 09:00").and_utc();
+        let wait = until_next_in(&schedule, &berlin, now).unwrap();
+        assert_eq!(wait.as_secs(), 22 * 3600);
+        // 10:00 CEST on the day before clocks go back, next 09:00 is CET
+        let now = dt("2025-10-25 08:00").and_utc();
+        let wait = until_next_in(&schedule, &berlin, now).unwrap();
+        assert_eq!(wait.as_secs(), 24 * 3600);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::Result;
+use crate::adapters::mailer::smtp::SmtpTls;
 use crate::error::Error;
+use chrono::Weekday;
+use chrono_tz::Tz;
 use dotenvy::dotenv;
 use std::env;
 use std::str::FromStr;
@@ -19,9 +22,15 @@ pub fn config() -> &'static Config {
 #[allow(non_snake_case)]
 pub struct Config {
     pub FUNNEL: i64,
+    // -- IANA timezone of the business, e.g. Europe/Moscow, required: the server zone is not assumed
+    pub TIMEZONE: Tz,
     // --TG
     pub ADMIN_ID: i64,
     pub TG_GROUP_ID: i64,
+    pub DIRECTOR_ID: i64,
+    pub MANAGERS: String,
+    // -- Telegram users allowed to view /stat besides the admin
+    pub STAT_USERS: Vec<i64>,
     // -- DB
     pub DB_URL: String,
     // -- AmoCRM
@@ -37,13 +46,50 @@ pub struct Config {
     // -- Schedule for workers
     pub SCHEDULE: String,
     pub DEADLINE_SCHEDULE: String,
+    // -- Deadline escalation
+    pub DEADLINE_TIERS: String,
+    pub RENOTIFY_DAYS: i64,
+    // -- Production calendar
+    pub CALENDAR_FILE: String,
+    pub DAY_COUNTING: String,
     // -- Mailer
     pub SMTP_SERVER: String,
     pub SMTP_PORT: u16,
+    pub SMTP_TLS: SmtpTls,
     pub FROM: String,
     pub LOGIN: String,
     pub PASSWORD: String,
+    // -- Outbox: delivery check interval, attempts before giving up and before alerting the admin
+    pub OUTBOX_INTERVAL_SECS: u64,
+    pub OUTBOX_MAX_ATTEMPTS: i64,
+    pub OUTBOX_ALERT_AFTER: i64,
     pub RECEIVERS: String,
+    // -- Weekly and monthly reports, an empty schedule disables the report
+    pub WEEKLY_REPORT_SCHEDULE: String,
+    pub MONTHLY_REPORT_SCHEDULE: String,
+    pub REPORT_WEEK_START: Weekday,
+    pub REPORT_MONTH_START_DAY: u32,
+    pub REPORT_RECEIVERS: String,
+    // -- Daily digest, an empty schedule keeps the separate emails
+    pub DIGEST_SCHEDULE: String,
+    // -- Daily counts the stat deltas, digest and trend charts compare against
+    pub SNAPSHOT_SCHEDULE: String,
+    // -- Spreadsheet layouts, an empty name selects the built-in one
+    pub LAYOUTS_FILE: String,
+    pub STAT_LAYOUT: String,
+    pub WEEKLY_REPORT_LAYOUT: String,
+    pub MONTHLY_REPORT_LAYOUT: String,
+    pub DIGEST_LAYOUT: String,
+    // -- Handover forecast
+    pub FORECAST_WEEKS: u32,
+    pub HANDOVER_CAPACITY: usize,
+    // -- iCalendar feed
+    pub ICS_FILE: String,
+    pub ICS_ADDR: String,
+    // -- TTF font for chart labels and PDF acts
+    pub CHART_FONT: String,
+    // -- Retention, 0 keeps transferred deals forever
+    pub RETENTION_MONTHS: u32,
 }
 
 impl Config {
@@ -51,8 +97,12 @@ impl Config {
         dotenv().expect("dotenv init failed");
         Ok(Config {
             FUNNEL: get_env_as_parse("FUNNEL")?,
+            TIMEZONE: get_env_as_parse("TIMEZONE")?,
             ADMIN_ID: get_env_as_parse("TG_HANMASTER_ID")?,
             TG_GROUP_ID: get_env_as_parse("TG_GROUP_ID")?,
+            DIRECTOR_ID: get_env_or("TG_DIRECTOR_ID", 0)?,
+            MANAGERS: get_env_or("MANAGERS", String::new())?,
+            STAT_USERS: get_env_id_list("STAT_USERS")?,
             DB_URL: get_env("DB_URL")?,
             AMO_CITY_ACCOUNT: get_env("AMO_CITY_ACCOUNT")?,
             AMO_CITY_TOKEN: get_env("AMO_CITY_TOKEN")?,
@@ -64,12 +114,41 @@ impl Config {
             PROF_FORMAT_API_KEY: get_env("PROF_FORMAT_API_KEY")?,
             SCHEDULE: get_env("SCHEDULE")?,
             DEADLINE_SCHEDULE: get_env("DEADLINE_SCHEDULE")?,
+            DEADLINE_TIERS: get_env_or("DEADLINE_TIERS", "4=email;overdue=email".to_string())?,
+            RENOTIFY_DAYS: get_env_or("RENOTIFY_DAYS", 3)?,
+            CALENDAR_FILE: get_env_or("CALENDAR_FILE", String::new())?,
+            DAY_COUNTING: get_env_or("DAY_COUNTING", String::new())?,
             SMTP_SERVER: get_env("SMTP_SERVER")?,
-            SMTP_PORT: get_env_as_parse("SMTP_PORT")?,
+            SMTP_PORT: get_env_or("SMTP_PORT", 587)?,
+            SMTP_TLS: get_env_or("SMTP_TLS", SmtpTls::StartTls)?,
             FROM: get_env("FROM")?,
-            LOGIN: get_env("LOGIN")?,
-            PASSWORD: get_env("PASSWORD")?,
+            LOGIN: get_env_or("LOGIN", String::new())?,
+            PASSWORD: get_env_or("PASSWORD", String::new())?,
+            OUTBOX_INTERVAL_SECS: get_env_or("OUTBOX_INTERVAL_SECS", 60)?,
+            OUTBOX_MAX_ATTEMPTS: get_env_or("OUTBOX_MAX_ATTEMPTS", 10)?,
+            OUTBOX_ALERT_AFTER: get_env_or("OUTBOX_ALERT_AFTER", 3)?,
             RECEIVERS: get_env("RECEIVERS")?,
+            WEEKLY_REPORT_SCHEDULE: get_env_or("WEEKLY_REPORT_SCHEDULE", String::new())?,
+            MONTHLY_REPORT_SCHEDULE: get_env_or("MONTHLY_REPORT_SCHEDULE", String::new())?,
+            REPORT_WEEK_START: get_env_or("REPORT_WEEK_START", Weekday::Mon)?,
+            REPORT_MONTH_START_DAY: get_env_or("REPORT_MONTH_START_DAY", 1)?,
+            REPORT_RECEIVERS: get_env_or("REPORT_RECEIVERS", String::new())?,
+            DIGEST_SCHEDULE: get_env_or("DIGEST_SCHEDULE", String::new())?,
+            SNAPSHOT_SCHEDULE: get_env_or("SNAPSHOT_SCHEDULE", "0 55 23 * * * *".to_string())?,
+            LAYOUTS_FILE: get_env_or("LAYOUTS_FILE", String::new())?,
+            STAT_LAYOUT: get_env_or("STAT_LAYOUT", String::new())?,
+            WEEKLY_REPORT_LAYOUT: get_env_or("WEEKLY_REPORT_LAYOUT", String::new())?,
+            MONTHLY_REPORT_LAYOUT: get_env_or("MONTHLY_REPORT_LAYOUT", String::new())?,
+            DIGEST_LAYOUT: get_env_or("DIGEST_LAYOUT", String::new())?,
+            FORECAST_WEEKS: get_env_or("FORECAST_WEEKS", 4)?,
+            HANDOVER_CAPACITY: get_env_or("HANDOVER_CAPACITY", 0)?,
+            ICS_FILE: get_env_or("ICS_FILE", "deadlines.ics".to_string())?,
+            ICS_ADDR: get_env_or("ICS_ADDR", String::new())?,
+            CHART_FONT: get_env_or(
+                "CHART_FONT",
+                "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf".to_string(),
+            )?,
+            RETENTION_MONTHS: get_env_or("RETENTION_MONTHS", 0)?,
         })
     }
 }
@@ -82,3 +161,22 @@ fn get_env_as_parse<T: FromStr>(name: &'static str) -> Result<T> {
     let val = get_env(name)?;
     val.parse::<T>().map_err(|_| Error::ConfigWrongFormat(name))
 }
+
+/// Comma or semicolon separated telegram ids, empty when not set
+fn get_env_id_list(name: &'static str) -> Result<Vec<i64>> {
+    let val = env::var(name).unwrap_or_default();
+    val.split([',', ';'])
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse().map_err(|_| Error::ConfigWrongFormat(name)))
+        .collect()
+}
+
+fn get_env_or<T: FromStr>(name: &'static str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(val) if !val.trim().is_empty() => {
+            val.parse::<T>().map_err(|_| Error::ConfigWrongFormat(name))
+        }
+        _ => Ok(default),
+    }
+}
@@ -1,10 +1,11 @@
+use crate::clock::{local_now, tz, until_next};
 use crate::config::config;
+use crate::model::archive::archive_transferred;
 use crate::model::deadline::search_deadline;
 use crate::model::stat::send_stat;
 use crate::sender::send_msg_to_admin;
 use cron::Schedule;
 use log::{debug, error};
-use sqlx::types::chrono::Local;
 use std::str::FromStr;
 use teloxide::Bot;
 use tokio::time::sleep;
@@ -14,18 +15,16 @@ pub fn do_work(bot: Bot) {
         let schedule =
             Schedule::from_str(&config().DEADLINE_SCHEDULE).expect("Schedule is not valid");
         debug!("Upcoming fire times:");
-        for datetime in schedule.upcoming(Local).take(5) {
+        for datetime in schedule.upcoming(tz()).take(5) {
             debug!("-> {}", datetime);
         }
 
         loop {
-            let now = Local::now();
-            if let Some(next) = schedule.upcoming(Local).next() {
-                let duration = (next - now).to_std().expect("duration cannot be negative");
+            if let Some(duration) = until_next(&schedule) {
                 sleep(duration).await;
                 let info = format!(
                     "{}: поиск объектов deadline",
-                    Local::now().format("%d.%m.%Y %H:%M:%S")
+                    local_now().format("%d.%m.%Y %H:%M:%S")
                 );
                 debug!("{}", info);
                 send_msg_to_admin(&bot, &info).await;
@@ -40,13 +39,20 @@ pub fn do_work(bot: Bot) {
                 }
 
                 // Deadline
-                let results = search_deadline().await;
+                let results = search_deadline(&bot).await;
 
                 if let Err(e) = results {
                     let msg = format!("Failed to send deadline stat on email: {}", e);
                     error!("{msg}");
                     send_msg_to_admin(&bot, &msg).await;
                 }
+
+                // Retention
+                if let Err(e) = archive_transferred().await {
+                    let msg = format!("Failed to archive transferred deals: {}", e);
+                    error!("{msg}");
+                    send_msg_to_admin(&bot, &msg).await;
+                }
             }
         }
     });
@@ -0,0 +1,42 @@
+use crate::clock::{local_now, tz, until_next};
+use crate::config::config;
+use crate::model::digest::{digest_mode, send_digest};
+use crate::sender::send_msg_to_admin;
+use cron::Schedule;
+use log::{debug, error, info};
+use std::str::FromStr;
+use teloxide::Bot;
+use tokio::time::sleep;
+
+pub fn do_work(bot: Bot) {
+    if !digest_mode() {
+        info!("Daily digest is disabled");
+        return;
+    }
+    tokio::spawn(async move {
+        let schedule =
+            Schedule::from_str(&config().DIGEST_SCHEDULE).expect("Schedule is not valid");
+        debug!("Daily digest upcoming fire times:");
+        for datetime in schedule.upcoming(tz()).take(5) {
+            debug!("-> {}", datetime);
+        }
+
+        loop {
+            if let Some(duration) = until_next(&schedule) {
+                sleep(duration).await;
+                let info = format!(
+                    "{}: ежедневная сводка",
+                    local_now().format("%d.%m.%Y %H:%M:%S")
+                );
+                debug!("{}", info);
+                send_msg_to_admin(&bot, &info).await;
+
+                if let Err(e) = send_digest().await {
+                    let msg = format!("Failed to send daily digest on email: {}", e);
+                    error!("{msg}");
+                    send_msg_to_admin(&bot, &msg).await;
+                }
+            }
+        }
+    });
+}
@@ -21,11 +21,34 @@ pub enum Error {
     Time(chrono::OutOfRangeError),
     // -- Xlsx
     Xlsx(rust_xlsxwriter::XlsxError),
+    // -- Import
+    XlsxRead(calamine::XlsxError),
+    Csv(csv::Error),
+    // -- Charts
+    Png(png::EncodingError),
     AppErr(String),
 }
 
 // region:    ---From
 
+impl From<png::EncodingError> for Error {
+    fn from(value: png::EncodingError) -> Self {
+        Error::Png(value)
+    }
+}
+
+impl From<calamine::XlsxError> for Error {
+    fn from(value: calamine::XlsxError) -> Self {
+        Error::XlsxRead(value)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(value: csv::Error) -> Self {
+        Error::Csv(value)
+    }
+}
+
 impl From<rust_xlsxwriter::XlsxError> for Error {
     fn from(value: rust_xlsxwriter::XlsxError) -> Self {
         Error::Xlsx(value)
@@ -79,7 +102,11 @@ impl From<teloxide::RequestError> for Error {
 // region:    --- Error boilerplate
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            // the message is meant for the user as is
+            Error::AppErr(e) => write!(f, "{e}"),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
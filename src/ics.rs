@@ -0,0 +1,220 @@
+use crate::Result;
+use crate::clock::{self, to_local};
+use crate::config::config;
+use crate::error::Error;
+use crate::model::Db;
+use crate::model::deadline::{Tier, TierLevel, parse_tiers};
+use crate::model::deal::DealData;
+use axum::Router;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use chrono::{Days, NaiveDateTime};
+use log::{error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const LINE_LIMIT: usize = 75;
+
+/// iCalendar feed with an all-day event on the transfer deadline of every undone deal
+pub fn render(deals: &[DealData], tiers: &[Tier], stamp: NaiveDateTime) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//DKP bot//Transfer deadlines//RU".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:Передача объектов ДКП".to_string(),
+    ];
+    for deal in deals {
+        let exp_date = deal.exp_date();
+        let end_date = exp_date.checked_add_days(Days::new(1)).unwrap_or(exp_date);
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:deal-{}@dkp-bot", deal.id));
+        lines.push(format!("DTSTAMP:{}", stamp.format("%Y%m%dT%H%M%SZ")));
+        lines.push(format!("DTSTART;VALUE=DATE:{}", exp_date.format("%Y%m%d")));
+        lines.push(format!("DTEND;VALUE=DATE:{}", end_date.format("%Y%m%d")));
+        lines.push(format!(
+            "SUMMARY:{}",
+            escape(&format!(
+                "{}, {}, {} № {}",
+                deal.project, deal.house, deal.property_type, deal.property_num
+            ))
+        ));
+        lines.push(format!(
+            "DESCRIPTION:{}",
+            escape(&format!(
+                "Сделка: {}\nДата регистрации: {}\nСрок передачи: {} дн.",
+                deal.deal_id,
+                to_local(&deal.created_on).format("%d.%m.%Y"),
+                deal.days_limit
+            ))
+        ));
+        for tier in tiers {
+            lines.push("BEGIN:VALARM".to_string());
+            lines.push("ACTION:DISPLAY".to_string());
+            lines.push(format!("TRIGGER:{}", trigger(&tier.level)));
+            lines.push(format!("DESCRIPTION:{}", escape(&tier.level.title())));
+            lines.push("END:VALARM".to_string());
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines
+        .iter()
+        .map(|l| fold(l))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+        + "\r\n"
+}
+
+/// Alarms follow the deadline tiers, overdue one fires the day after the deadline
+fn trigger(level: &TierLevel) -> String {
+    match level {
+        TierLevel::Overdue => "P1D".to_string(),
+        TierLevel::Days(0) => "PT0S".to_string(),
+        TierLevel::Days(days) => format!("-P{days}D"),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Content lines longer than 75 octets continue on the next line after a space
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut len = 0;
+    for ch in line.chars() {
+        if len + ch.len_utf8() > LINE_LIMIT {
+            folded.push_str("\r\n ");
+            len = 1;
+        }
+        folded.push(ch);
+        len += ch.len_utf8();
+    }
+    folded
+}
+
+static TMP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Rewrites the feed file, called whenever a transfer date may move
+pub async fn regenerate() -> Result<()> {
+    let db = Db::new().await;
+    let deals = db.get_all_undone_deals().await?;
+    let tiers = parse_tiers(&config().DEADLINE_TIERS)?;
+    let content = render(&deals, &tiers, clock::now());
+    // readers see the old feed or the new one, never a half written file;
+    // the temp name is per call, so concurrent refreshes don't write into each other
+    let path = &config().ICS_FILE;
+    let tmp = format!("{path}.{}.tmp", TMP_SEQ.fetch_add(1, Ordering::Relaxed));
+    tokio::fs::write(&tmp, content)
+        .await
+        .map_err(|e| Error::AppErr(format!("Failed to write {tmp}: {e}")))?;
+    if let Err(e) = tokio::fs::rename(&tmp, path).await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(Error::AppErr(format!("Failed to replace {path}: {e}")));
+    }
+    info!("Calendar feed regenerated: {} deals", deals.len());
+    Ok(())
+}
+
+/// Same as `regenerate`, a stale feed must not break the caller
+pub async fn refresh() {
+    if let Err(e) = regenerate().await {
+        error!("Failed to regenerate calendar feed: {e}");
+    }
+}
+
+pub async fn read_feed() -> Result<Vec<u8>> {
+    if tokio::fs::metadata(&config().ICS_FILE).await.is_err() {
+        regenerate().await?;
+    }
+    tokio::fs::read(&config().ICS_FILE)
+        .await
+        .map_err(|e| Error::AppErr(format!("Failed to read {}: {e}", config().ICS_FILE)))
+}
+
+async fn feed_handler() -> impl IntoResponse {
+    match read_feed().await {
+        Ok(content) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+            content,
+        ),
+        Err(e) => {
+            error!("[feed_handler] {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                Vec::new(),
+            )
+        }
+    }
+}
+
+/// Serves the feed on `ICS_ADDR`, disabled when the address is empty
+pub fn serve() {
+    let addr = config().ICS_ADDR.clone();
+    if addr.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let app = Router::new().route("/deadlines.ics", get(feed_handler));
+        let listener = match tokio::net::TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind calendar feed on {addr}: {e}");
+                return;
+            }
+        };
+        info!("Calendar feed: http://{addr}/deadlines.ics");
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Calendar feed server stopped: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::date;
+
+    fn deal() -> DealData {
+        DealData::builder()
+            .id(7, 1001)
+            .project("DNS Сити")
+            .house("Дом 2")
+            .property_num(15)
+            .created(date(2025, 10, 1))
+            .build()
+    }
+
+    #[test]
+    fn test_render() {
+        let tiers = parse_tiers("7=email;1=group;overdue=email").unwrap();
+        let stamp = date(2025, 10, 2).and_hms_opt(6, 0, 0).unwrap();
+        let ics = render(&[deal()], &tiers, stamp);
+
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert!(ics.contains("UID:deal-7@dkp-bot\r\n"));
+        assert!(ics.contains("DTSTART;VALUE=DATE:20251031\r\n"));
+        assert!(ics.contains("DTEND;VALUE=DATE:20251101\r\n"));
+        assert!(ics.contains("SUMMARY:DNS Сити\\, Дом 2\\, Квартира № 15\r\n"));
+        assert!(ics.contains("TRIGGER:-P7D\r\n"));
+        assert!(ics.contains("TRIGGER:-P1D\r\n"));
+        assert!(ics.contains("TRIGGER:P1D\r\n"));
+        assert!(ics.lines().all(|l| l.len() <= LINE_LIMIT));
+    }
+
+    #[test]
+    fn test_fold() {
+        let line = format!("DESCRIPTION:{}", "Передача".repeat(10));
+        let folded = fold(&line);
+        assert!(folded.split("\r\n").all(|l| l.len() <= LINE_LIMIT));
+        assert_eq!(folded.replace("\r\n ", ""), line);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::Result;
+use crate::adapters::amo::amo_types::Deal;
+use crate::bot_interface::{PROJECTS, PROPERTY_TYPES};
+use crate::calendar::calendar;
+use crate::clock::from_local;
+use crate::error::Error;
+use crate::model::Db;
+use calamine::{Data, DataType, Reader, Xlsx as XlsxReader, open_workbook_from_rs};
+use chrono::NaiveDate;
+use log::info;
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+/// Column layout produced by `Xlsx::create`
+pub const COLUMNS: [&str; 7] = [
+    "Проект",
+    "Дом",
+    "Тип объекта",
+    "Номер объекта",
+    "Тип отделки",
+    "Дата регистрации",
+    "Передать объект до",
+];
+/// Optional trailing column for deals handed over before the bot existed
+pub const TRANSFERRED_COLUMN: &str = "Передан";
+/// Optional trailing column `/export` writes for transferred deals
+pub const TRANSFERRED_ON_COLUMN: &str = "Дата передачи";
+/// Keeps the summary inside a single telegram message
+const MAX_REPORTED_ERRORS: usize = 50;
+
+#[derive(Debug)]
+pub struct ImportRow {
+    pub deal: Deal,
+    /// Local transfer date, `None` keeps the deal in work
+    pub transferred_on: Option<NaiveDate>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub errors: Vec<(usize, String)>,
+}
+
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Импорт завершён\nДобавлено сделок: {}\nСтрок с ошибками: {}",
+            self.imported,
+            self.errors.len()
+        )?;
+        for (line, err) in self.errors.iter().take(MAX_REPORTED_ERRORS) {
+            write!(f, "\nСтрока {line}: {err}")?;
+        }
+        if self.errors.len() > MAX_REPORTED_ERRORS {
+            write!(f, "\n...и ещё {}", self.errors.len() - MAX_REPORTED_ERRORS)?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn import_deals(db: &Db, file_name: &str, bytes: &[u8]) -> Result<ImportReport> {
+    let rows = match file_name
+        .rsplit('.')
+        .next()
+        .map(str::to_lowercase)
+        .as_deref()
+    {
+        Some("xlsx") => read_xlsx(bytes)?,
+        Some("csv") => read_csv(bytes)?,
+        _ => {
+            return Err(Error::AppErr(
+                "Поддерживаются только файлы .xlsx и .csv".to_string(),
+            ));
+        }
+    };
+
+    let mut report = ImportReport::default();
+    for (line, parsed) in parse_rows(rows)? {
+        let row = match parsed {
+            Ok(row) => row,
+            Err(e) => {
+                report.errors.push((line, e));
+                continue;
+            }
+        };
+        let d = &row.deal;
+        if db
+            .deal_exists(&d.project, &d.property_type, &d.house, d.property_num)
+            .await?
+        {
+            report
+                .errors
+                .push((line, "объект уже есть в базе".to_string()));
+            continue;
+        }
+        let transferred_on = row
+            .transferred_on
+            .map(|d| from_local(&d.and_hms_opt(0, 0, 0).unwrap_or_default()));
+        db.import_deal(d, transferred_on).await?;
+        report.imported += 1;
+    }
+    info!(
+        "[import_deals] {file_name}: imported {}, errors {}",
+        report.imported,
+        report.errors.len()
+    );
+    Ok(report)
+}
+
+fn read_xlsx(bytes: &[u8]) -> Result<Vec<Vec<String>>> {
+    let mut workbook: XlsxReader<_> = open_workbook_from_rs(Cursor::new(bytes))?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| Error::AppErr("В файле нет листов".to_string()))??;
+
+    let rows = range
+        .rows()
+        .map(|row| {
+            row.iter()
+                .map(|cell| match cell {
+                    Data::DateTime(_) | Data::DateTimeIso(_) => cell
+                        .as_date()
+                        .map(|d| d.format("%d.%m.%Y").to_string())
+                        .unwrap_or_default(),
+                    _ => cell.to_string(),
+                })
+                .collect()
+        })
+        .collect();
+    Ok(rows)
+}
+
+fn read_csv(bytes: &[u8]) -> Result<Vec<Vec<String>>> {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    // Excel in the russian locale saves CSV with ';'
+    let first_line = bytes.split(|b| *b == b'\n').next().unwrap_or_default();
+    let delimiter = if first_line.contains(&b';') {
+        b';'
+    } else {
+        b','
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(bytes);
+
+    let mut rows = vec![];
+    for record in reader.records() {
+        rows.push(record?.iter().map(ToString::to_string).collect());
+    }
+    Ok(rows)
+}
+
+type ParsedRow = (usize, std::result::Result<ImportRow, String>);
+
+fn parse_rows(rows: Vec<Vec<String>>) -> Result<Vec<ParsedRow>> {
+    let mut rows = rows.into_iter();
+    let header = rows.next().unwrap_or_default();
+    let header: Vec<&str> = header.iter().map(|h| h.trim()).collect();
+    if header.len() < COLUMNS.len() || header[..COLUMNS.len()] != COLUMNS {
+        return Err(Error::AppErr(format!(
+            "Неверный заголовок, ожидаются колонки: {}",
+            COLUMNS.join(", ")
+        )));
+    }
+    let optional = |name: &str| {
+        header[COLUMNS.len()..]
+            .iter()
+            .position(|h| *h == name)
+            .map(|idx| COLUMNS.len() + idx)
+    };
+    let columns = OptionalColumns {
+        transferred: optional(TRANSFERRED_COLUMN),
+        transferred_on: optional(TRANSFERRED_ON_COLUMN),
+    };
+
+    let parsed = rows
+        .enumerate()
+        // header is the first line of the file
+        .map(|(idx, cells)| (idx + 2, cells))
+        .filter(|(_, cells)| cells.iter().any(|c| !c.trim().is_empty()))
+        .map(|(line, cells)| (line, parse_row(&cells, &columns)))
+        .collect();
+    Ok(parsed)
+}
+
+/// Positions of the trailing columns found in the header
+struct OptionalColumns {
+    transferred: Option<usize>,
+    transferred_on: Option<usize>,
+}
+
+fn parse_row(
+    cells: &[String],
+    columns: &OptionalColumns,
+) -> std::result::Result<ImportRow, String> {
+    let cell = |idx: usize| cells.get(idx).map(|c| c.trim()).unwrap_or_default();
+
+    let project = cell(0);
+    if !PROJECTS.contains(&project) {
+        return Err(format!("неизвестный проект \"{project}\""));
+    }
+    let house = cell(1);
+    if house.is_empty() {
+        return Err("не указан дом".to_string());
+    }
+    let property_type = cell(2);
+    if !PROPERTY_TYPES.contains(&property_type) {
+        return Err(format!("неизвестный тип объекта \"{property_type}\""));
+    }
+    let property_num = match cell(3).parse::<i32>() {
+        Ok(num) if num > 0 => num,
+        _ => return Err(format!("неверный номер объекта \"{}\"", cell(3))),
+    };
+    let reg_date =
+        parse_date(cell(5)).ok_or_else(|| format!("неверная дата регистрации \"{}\"", cell(5)))?;
+    let exp_date =
+        parse_date(cell(6)).ok_or_else(|| format!("неверная дата передачи \"{}\"", cell(6)))?;
+    if exp_date <= reg_date {
+        return Err("дата передачи раньше даты регистрации".to_string());
+    }
+    // the limit is stored in the project's days, so the deadline comes back the same
+    let counting = calendar().counting_for(project, property_type);
+    let days_limit = calendar().days_between(reg_date, exp_date, counting);
+    let transferred_on = match columns.transferred_on.map(cell).unwrap_or_default() {
+        "" => None,
+        value => {
+            Some(parse_date(value).ok_or_else(|| format!("неверная дата передачи \"{value}\""))?)
+        }
+    };
+    let transferred = columns.transferred.is_some_and(|idx| {
+        matches!(
+            cell(idx).to_lowercase().as_str(),
+            "да" | "1" | "true" | "yes"
+        )
+    });
+    // a flag without the date is taken as transferred in time
+    let transferred_on = transferred_on.or(transferred.then_some(exp_date));
+
+    Ok(ImportRow {
+        deal: Deal {
+            deal_id: 0,
+            project: project.to_string(),
+            house: house.to_string(),
+            property_type: property_type.to_string(),
+            property_num,
+            facing: cell(4).to_string(),
+            days_limit,
+            created_on: from_local(&reg_date.and_hms_opt(0, 0, 0).unwrap_or_default()),
+            responsible_id: 0,
+            default_limit: false,
+            buyer: String::new(),
+        },
+        transferred_on,
+    })
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%d.%m.%Y")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::mailer::data_types::DealInfo;
+    use crate::clock::{self, date};
+    use crate::layout::{DEFAULT_LAYOUT, layouts};
+    use crate::model::deal::DealData;
+    use crate::model::test_db;
+    use crate::xlsx::Xlsx;
+
+    fn deal(number: i32) -> Deal {
+        DealData::builder()
+            .id(0, 1)
+            .project(PROJECTS[1])
+            .house("Дом 2")
+            .property_type(PROPERTY_TYPES[0])
+            .property_num(number)
+            .facing("Чистовая")
+            .created(date(2024, 3, 1))
+            .build_lead()
+    }
+
+    #[test]
+    fn test_xlsx_round_trip() {
+        let deals: Vec<DealInfo> = vec![(&deal(12)).into(), (&deal(13)).into()];
+        let buf = Xlsx::create(deals, None, layouts().get("", DEFAULT_LAYOUT).unwrap()).unwrap();
+        let rows = parse_rows(read_xlsx(&buf).unwrap()).unwrap();
+        assert_eq!(rows.len(), 2);
+        let (line, row) = &rows[0];
+        assert_eq!(*line, 2);
+        let row = row.as_ref().unwrap();
+        assert_eq!(row.deal.property_num, 12);
+        assert_eq!(row.deal.days_limit, 30);
+        assert!(row.transferred_on.is_none());
+    }
+
+    #[test]
+    fn test_csv_row_errors() {
+        let csv = "Проект;Дом;Тип объекта;Номер объекта;Тип отделки;Дата регистрации;Передать объект до;Передан\n\
+                   ЖК Формат;Дом 1;Квартира;5;;01.02.2023;03.03.2023;да\n\
+                   ЖК Формат;Дом 1;Гараж;6;;01.02.2023;03.03.2023;\n\
+                   ЖК Формат;Дом 1;Квартира;7;;01.02.2023;31.01.2023;\n";
+        let rows = parse_rows(read_csv(csv.as_bytes()).unwrap()).unwrap();
+        assert_eq!(rows.len(), 3);
+        let first = rows[0].1.as_ref().unwrap();
+        // transferred without the date, taken as in time
+        assert_eq!(first.transferred_on, Some(date(2023, 3, 3)));
+        assert_eq!(first.deal.days_limit, 30);
+        assert_eq!(rows[1].0, 3);
+        assert!(rows[1].1.is_err());
+        assert!(rows[2].1.is_err());
+    }
+
+    #[test]
+    fn test_wrong_header() {
+        let rows = read_csv("a,b,c\n1,2,3".as_bytes()).unwrap();
+        assert!(parse_rows(rows).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_import_skips_duplicates() {
+        let db = test_db().await;
+        let buf = Xlsx::create(
+            vec![(&deal(12)).into(), (&deal(12)).into()],
+            None,
+            layouts().get("", DEFAULT_LAYOUT).unwrap(),
+        )
+        .unwrap();
+        let report = import_deals(&db, "deals.xlsx", &buf).await.unwrap();
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert!(db.read_deal_ids().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import_transferred() {
+        let db = test_db().await;
+        let csv = "Проект;Дом;Тип объекта;Номер объекта;Тип отделки;Дата регистрации;Передать объект до;Передан;Дата передачи\n\
+                   ЖК Формат;Дом 1;Квартира;5;;01.02.2023;03.03.2023;да;20.02.2023\n\
+                   ЖК Формат;Дом 1;Квартира;6;;01.02.2023;03.03.2023;нет;\n";
+        let report = import_deals(&db, "deals.csv", csv.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(report.imported, 2);
+
+        let (transferred,): (Option<chrono::NaiveDateTime>,) =
+            sqlx::query_as("SELECT transferred_on FROM deal WHERE property_num = 5")
+                .fetch_one(&db.db)
+                .await
+                .unwrap();
+        assert_eq!(
+            transferred.map(|t| clock::to_local(&t).date()),
+            Some(date(2023, 2, 20))
+        );
+        let ids: Vec<(i64,)> = sqlx::query_as("SELECT deal_id FROM deal ORDER BY id")
+            .fetch_all(&db.db)
+            .await
+            .unwrap();
+        assert_ne!(ids[0], ids[1]);
+
+        // archived deals are not imported again and come back one at a time
+        let archived = db.archive_transferred_before(clock::now()).await.unwrap();
+        assert_eq!(archived, 1);
+        let report = import_deals(&db, "deals.csv", csv.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(report.imported, 0);
+        assert_eq!(report.errors.len(), 2);
+        assert!(
+            db.restore_from_archive("ЖК Формат", ids[0].0 as u64)
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::Result;
+use crate::config::config;
+use crate::error::Error;
+use crate::import::COLUMNS;
+use log::info;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Built-in layout of the deal sheets, the one `/import` reads back
+pub const DEFAULT_LAYOUT: &str = "default";
+/// Built-in layout of the weekly and monthly report sheets
+pub const REPORT_LAYOUT: &str = "report";
+
+static INSTANCE: OnceLock<Layouts> = OnceLock::new();
+
+/// Loads `LAYOUTS_FILE` and checks the layouts the emails name, called at startup
+/// so a broken file stops the bot instead of a worker
+pub fn init_layouts() -> Result<()> {
+    let layouts = Layouts::load(&config().LAYOUTS_FILE)?;
+    for name in [
+        &config().STAT_LAYOUT,
+        &config().DIGEST_LAYOUT,
+        &config().WEEKLY_REPORT_LAYOUT,
+        &config().MONTHLY_REPORT_LAYOUT,
+    ] {
+        layouts.get(name, DEFAULT_LAYOUT)?;
+    }
+    INSTANCE
+        .set(layouts)
+        .map_err(|_| Error::AppErr("Layouts are already loaded".to_string()))
+}
+
+/// Built-in layouts only until `init_layouts`
+pub fn layouts() -> &'static Layouts {
+    INSTANCE.get_or_init(Layouts::default)
+}
+
+/// Deal attribute a spreadsheet column is filled from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    DealId,
+    /// Main contact of the AmoCRM lead
+    Buyer,
+    Project,
+    House,
+    PropertyType,
+    PropertyNum,
+    Facing,
+    RegDate,
+    ExpDate,
+    /// Live `deadline - TODAY()` formula
+    DaysLeft,
+    DaysLimit,
+    TransferredOn,
+    /// Days between the deadline and the transfer, empty when in time
+    DaysLate,
+}
+
+impl Field {
+    /// Excel number format used when the column sets none
+    pub fn default_format(&self) -> Option<&'static str> {
+        match self {
+            Field::RegDate | Field::ExpDate | Field::TransferredOn => Some("dd.mm.yyyy"),
+            Field::DaysLeft | Field::DaysLate => Some("0"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Column {
+    pub header: String,
+    pub field: Field,
+    /// Excel number format, e.g. "dd.mm.yyyy" or "0"
+    #[serde(default)]
+    pub format: Option<String>,
+    #[serde(default = "default_width")]
+    pub width: f64,
+}
+
+fn default_width() -> f64 {
+    15.0
+}
+
+fn column(header: &str, field: Field, width: f64) -> Column {
+    Column {
+        header: header.to_string(),
+        field,
+        format: None,
+        width,
+    }
+}
+
+impl Column {
+    pub fn num_format(&self) -> Option<&str> {
+        self.format
+            .as_deref()
+            .or_else(|| self.field.default_format())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Layout {
+    pub columns: Vec<Column>,
+}
+
+impl Layout {
+    pub fn position(&self, field: Field) -> Option<usize> {
+        self.columns.iter().position(|c| c.field == field)
+    }
+
+    /// `/import` columns and the days left
+    fn deals() -> Layout {
+        let fields = [
+            Field::Project,
+            Field::House,
+            Field::PropertyType,
+            Field::PropertyNum,
+            Field::Facing,
+            Field::RegDate,
+            Field::ExpDate,
+        ];
+        let widths = [15.0, 10.0, 15.0, 22.0, 22.0, 22.0, 22.0];
+        let mut columns: Vec<Column> = COLUMNS
+            .iter()
+            .zip(fields)
+            .zip(widths)
+            .map(|((header, field), width)| column(header, field, width))
+            .collect();
+        columns.push(column("Осталось дней", Field::DaysLeft, 16.0));
+        Layout { columns }
+    }
+
+    fn report() -> Layout {
+        Layout {
+            columns: vec![
+                column("Проект", Field::Project, 15.0),
+                column("Дом", Field::House, 10.0),
+                column("Тип объекта", Field::PropertyType, 15.0),
+                column("Номер объекта", Field::PropertyNum, 15.0),
+                column("Тип отделки", Field::Facing, 22.0),
+                column("Дата регистрации", Field::RegDate, 20.0),
+                column("Передать объект до", Field::ExpDate, 20.0),
+                column("Дата передачи", Field::TransferredOn, 20.0),
+                column("Опоздание, дн.", Field::DaysLate, 15.0),
+            ],
+        }
+    }
+}
+
+/// Named layouts, the built-in ones may be overridden by the file
+#[derive(Debug)]
+pub struct Layouts(BTreeMap<String, Layout>);
+
+impl Default for Layouts {
+    fn default() -> Self {
+        Layouts(BTreeMap::from([
+            (DEFAULT_LAYOUT.to_string(), Layout::deals()),
+            (REPORT_LAYOUT.to_string(), Layout::report()),
+        ]))
+    }
+}
+
+impl Layouts {
+    fn load(path: &str) -> Result<Layouts> {
+        if path.is_empty() {
+            return Ok(Layouts::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::AppErr(format!("Failed to read {path}: {e}")))?;
+        let layouts = Layouts::parse(&content)?;
+        info!("Report layouts loaded: {}", layouts.names().join(", "));
+        Ok(layouts)
+    }
+
+    /// JSON object of layout name to `{"columns": [{"header", "field", "format", "width"}]}`
+    fn parse(content: &str) -> Result<Layouts> {
+        let named: BTreeMap<String, Layout> = serde_json::from_str(content)
+            .map_err(|e| Error::AppErr(format!("Wrong layouts file: {e}")))?;
+        let mut layouts = Layouts::default();
+        for (name, layout) in named {
+            if layout.columns.is_empty() {
+                return Err(Error::AppErr(format!("Layout \"{name}\" has no columns")));
+            }
+            layouts.0.insert(name.trim().to_string(), layout);
+        }
+        Ok(layouts)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_str).collect()
+    }
+
+    /// Case-insensitive lookup, an empty name selects `fallback`
+    pub fn get(&self, name: &str, fallback: &str) -> Result<&Layout> {
+        let name = match name.trim() {
+            "" => fallback,
+            name => name,
+        };
+        self.0
+            .iter()
+            .find(|(n, _)| n.to_lowercase() == name.to_lowercase())
+            .map(|(_, layout)| layout)
+            .ok_or_else(|| {
+                Error::AppErr(format!(
+                    "Unknown layout \"{name}\", known: {}",
+                    self.names().join(", ")
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let layouts = Layouts::parse(
+            r#"{
+                "для руководства": {"columns": [
+                    {"header": "ID сделки", "field": "deal_id", "width": 12},
+                    {"header": "Покупатель", "field": "buyer"},
+                    {"header": "Проект", "field": "project"},
+                    {"header": "Срок", "field": "exp_date", "format": "dd.mm.yy"}
+                ]}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            layouts.names(),
+            vec![DEFAULT_LAYOUT, REPORT_LAYOUT, "для руководства"]
+        );
+
+        let layout = layouts.get("Для Руководства", DEFAULT_LAYOUT).unwrap();
+        assert_eq!(layout.columns[0].field, Field::DealId);
+        assert_eq!(layout.columns[1].field, Field::Buyer);
+        assert_eq!(layout.columns[2].width, 15.0);
+        assert_eq!(layout.columns[2].num_format(), None);
+        assert_eq!(layout.columns[3].num_format(), Some("dd.mm.yy"));
+        assert_eq!(layout.position(Field::ExpDate), Some(3));
+
+        assert_eq!(layouts.get("", REPORT_LAYOUT).unwrap(), &Layout::report());
+        assert!(layouts.get("для бухгалтерии", DEFAULT_LAYOUT).is_err());
+        assert!(
+            Layouts::parse(r#"{"x": {"columns": [{"header": "a", "field": "phone"}]}}"#).is_err()
+        );
+        assert!(Layouts::parse(r#"{"x": {"columns": []}}"#).is_err());
+    }
+
+    #[test]
+    fn test_default_matches_import() {
+        let layout = Layout::deals();
+        let headers: Vec<&str> = layout.columns.iter().map(|c| c.header.as_str()).collect();
+        assert_eq!(headers[..COLUMNS.len()], COLUMNS);
+    }
+}
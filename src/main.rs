@@ -1,6 +1,10 @@
 use crate::bot_interface::{BotCommand, State, bot_handler};
+use crate::calendar::init_calendar;
+use crate::clock::init_clock;
 pub use crate::error::Result;
+use crate::layout::init_layouts;
 use crate::model::init_db;
+use crate::model::report::ReportKind;
 use dotenvy::dotenv;
 use log::info;
 use teloxide::dispatching::dialogue::InMemStorage;
@@ -9,11 +13,22 @@ use teloxide::{prelude::*, utils::command::BotCommands};
 
 mod adapters;
 mod bot_interface;
+mod calendar;
+mod chart;
+mod clock;
 mod config;
 mod deadline_worker;
+mod digest_worker;
 mod error;
+mod ics;
+mod import;
+mod layout;
 mod model;
+mod outbox_worker;
+mod pdf;
+mod report_worker;
 mod sender;
+mod snapshot_worker;
 mod worker;
 mod xlsx;
 
@@ -27,7 +42,10 @@ async fn main() -> Result<()> {
         .install_default()
         .expect("Failed to install rustls CryptoProvider");
 
+    init_clock()?;
     init_db().await?;
+    init_calendar()?;
+    init_layouts()?;
 
     info!("Starting DKP bot...");
 
@@ -42,6 +60,16 @@ async fn main() -> Result<()> {
     let cloned_bot = bot.clone();
     deadline_worker::do_work(cloned_bot);
 
+    outbox_worker::do_work(bot.clone());
+
+    report_worker::do_work(bot.clone(), ReportKind::Weekly);
+    report_worker::do_work(bot.clone(), ReportKind::Monthly);
+    digest_worker::do_work(bot.clone());
+    snapshot_worker::do_work(bot.clone());
+
+    ics::refresh().await;
+    ics::serve();
+
     Dispatcher::builder(bot, bot_handler())
         .dependencies(deps![InMemStorage::<State>::new()])
         .enable_ctrlc_handler()
@@ -0,0 +1,197 @@
+use crate::Result;
+use crate::adapters::mailer::Attachment;
+use crate::clock::{self, to_local};
+use crate::config::config;
+use crate::error::Error;
+use crate::model::Db;
+use crate::model::deal::DealData;
+use crate::model::subscription::{MailKind, get_audiences};
+use crate::pdf::{self, Align, Line};
+use askama::Template;
+use chrono::NaiveDate;
+
+/// Written in by hand when the AmoCRM lead has no contact
+const BLANK: &str = "________________________________________";
+const TITLE_SIZE: f32 = 14.0;
+const TEXT_SIZE: f32 = 11.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActAction {
+    Download,
+    Email,
+}
+
+impl ActAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActAction::Download => "pdf",
+            ActAction::Email => "mail",
+        }
+    }
+
+    /// Inline button data is `act:<action>:<deal row id>`
+    pub fn callback_data(&self, deal: i32) -> String {
+        format!("act:{}:{deal}", self.as_str())
+    }
+
+    pub fn parse_callback(data: &str) -> Option<(ActAction, i32)> {
+        let (action, deal) = data.strip_prefix("act:")?.split_once(':')?;
+        let action = match action {
+            "pdf" => ActAction::Download,
+            "mail" => ActAction::Email,
+            _ => return None,
+        };
+        Some((action, deal.parse().ok()?))
+    }
+}
+
+/// Text of the act, `# ` starts the title and `## ` a subtitle
+#[derive(Template)]
+#[template(path = "act.txt")]
+pub struct TransferAct {
+    deal_id: u64,
+    act_date: String,
+    project: String,
+    house: String,
+    property_type: String,
+    property_num: i32,
+    facing: String,
+    reg_date: String,
+    exp_date: String,
+    buyer: String,
+}
+
+impl TransferAct {
+    pub fn new(deal: &DealData, act_date: NaiveDate) -> Self {
+        Self {
+            deal_id: deal.deal_id,
+            act_date: act_date.format("%d.%m.%Y").to_string(),
+            project: deal.project.clone(),
+            house: deal.house.clone(),
+            property_type: deal.property_type.clone(),
+            property_num: deal.property_num,
+            facing: deal.facing.clone(),
+            reg_date: to_local(&deal.created_on).format("%d.%m.%Y").to_string(),
+            exp_date: deal.exp_date().format("%d.%m.%Y").to_string(),
+            buyer: match deal.buyer.trim() {
+                "" => BLANK.to_string(),
+                buyer => buyer.to_string(),
+            },
+        }
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("act_{}.pdf", self.deal_id)
+    }
+
+    fn lines(text: &str) -> Vec<Line> {
+        text.lines()
+            .map(|line| {
+                let (text, size, align) = if let Some(title) = line.strip_prefix("# ") {
+                    (title, TITLE_SIZE, Align::Center)
+                } else if let Some(subtitle) = line.strip_prefix("## ") {
+                    (subtitle, TEXT_SIZE, Align::Center)
+                } else {
+                    (line, TEXT_SIZE, Align::Left)
+                };
+                Line {
+                    text: text.to_string(),
+                    size,
+                    align,
+                }
+            })
+            .collect()
+    }
+
+    pub fn to_pdf(&self, font: &[u8]) -> Result<Vec<u8>> {
+        pdf::render(&TransferAct::lines(&self.render()?), font)
+    }
+}
+
+/// The deal and its act as of today
+pub async fn get_act(deal: i32) -> Result<(DealData, Attachment)> {
+    let db = Db::new().await;
+    let deal = db.get_deal_by_id(deal).await?;
+    let path = &config().CHART_FONT;
+    let font = std::fs::read(path)
+        .map_err(|e| Error::AppErr(format!("Failed to read act font {path}: {e}")))?;
+    let act = TransferAct::new(&deal, clock::today());
+    let attachment = Attachment::pdf(act.file_name(), act.to_pdf(&font)?);
+    Ok((deal, attachment))
+}
+
+/// Queues the act for the subscribers of its project, returns the number of emails
+pub async fn send_act(deal: i32) -> Result<usize> {
+    let (deal, act) = get_act(deal).await?;
+    let db = Db::new().await;
+    let mut sent = 0;
+    for audience in get_audiences(&db, MailKind::Act).await? {
+        if audience.projects.covers(&deal.project) {
+            audience.email.act_notification(&deal, act.clone()).await?;
+            sent += 1;
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart::test_font;
+    use crate::clock::date;
+
+    fn deal(facing: &str) -> DealData {
+        DealData::builder()
+            .id(3, 7123)
+            .property_num(42)
+            .facing(facing)
+            .created(date(2025, 9, 1))
+            .build()
+    }
+
+    #[test]
+    fn test_callback() {
+        let data = ActAction::Email.callback_data(3);
+        assert_eq!(data, "act:mail:3");
+        assert_eq!(
+            ActAction::parse_callback(&data),
+            Some((ActAction::Email, 3))
+        );
+        assert_eq!(ActAction::parse_callback("ack:3"), None);
+        assert_eq!(ActAction::parse_callback("act:print:3"), None);
+    }
+
+    #[test]
+    fn test_act() {
+        let today = date(2025, 10, 1);
+        let act = TransferAct::new(&deal("Чистовая"), today);
+        let text = act.render().unwrap();
+        assert!(text.contains("## по сделке № 7123"));
+        assert!(text.contains(
+            "Номер объекта: 42\nТип отделки: Чистовая\nДата регистрации договора: 01.09.2025"
+        ));
+        assert!(text.contains(&format!("Покупатель: {BLANK}")));
+        assert_eq!(act.file_name(), "act_7123.pdf");
+
+        let with_buyer = DealData {
+            buyer: "Иванов Иван Иванович".to_string(),
+            ..deal("")
+        };
+        let text = TransferAct::new(&with_buyer, today).render().unwrap();
+        assert!(text.contains("Покупатель: Иванов Иван Иванович\n"));
+
+        let text = TransferAct::new(&deal(""), today).render().unwrap();
+        assert!(text.contains("Номер объекта: 42\nДата регистрации договора"));
+
+        let lines = TransferAct::lines(&text);
+        assert_eq!(lines[0].align, Align::Center);
+        assert_eq!(lines[0].size, TITLE_SIZE);
+        assert_eq!(lines[1].text, "по сделке № 7123");
+
+        let Some(font) = test_font() else {
+            return;
+        };
+        let font = std::fs::read(font).unwrap();
+        assert!(act.to_pdf(&font).unwrap().starts_with(b"%PDF"));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::Result;
+use crate::clock::from_local;
+use crate::model::Db;
+use crate::model::deal::DealData;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use cron::Schedule;
+use log::info;
+use sqlx::FromRow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertAction {
+    Ack,
+    Snooze,
+    Reschedule,
+}
+
+impl AlertAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertAction::Ack => "ack",
+            AlertAction::Snooze => "snooze",
+            AlertAction::Reschedule => "reschedule",
+        }
+    }
+
+    /// Inline button data is `<action>:<deal row id>`
+    pub fn callback_data(&self, deal: i32) -> String {
+        format!("{}:{deal}", self.as_str())
+    }
+
+    pub fn parse_callback(data: &str) -> Option<(AlertAction, i32)> {
+        let (action, deal) = data.split_once(':')?;
+        let action = match action {
+            "ack" => AlertAction::Ack,
+            "snooze" => AlertAction::Snooze,
+            "reschedule" => AlertAction::Reschedule,
+            _ => return None,
+        };
+        Some((action, deal.parse().ok()?))
+    }
+}
+
+/// Who did what with a deadline alert, kept as an audit trail
+#[allow(dead_code)]
+#[derive(FromRow, Debug, Clone)]
+pub struct AlertRecord {
+    pub deal: i32,
+    pub action: String,
+    pub user_id: i64,
+    pub user_name: String,
+    pub reason: String,
+    pub snoozed_until: Option<NaiveDateTime>,
+    pub agreed_deadline: Option<NaiveDate>,
+    pub created_on: NaiveDateTime,
+}
+
+/// Alerts run at fixed times, so a snooze ends with the local day of the next run:
+/// exactly one day of alerts is skipped whenever the button is pressed
+pub fn snooze_until(schedule: &Schedule, now: DateTime<Tz>) -> NaiveDateTime {
+    let next_run = schedule
+        .after(&now)
+        .next()
+        .map(|run| run.date_naive())
+        .unwrap_or(now.date_naive());
+    let end = next_run.succ_opt().unwrap_or(next_run);
+    from_local(&end.and_hms_opt(0, 0, 0).unwrap_or_default())
+}
+
+/// Reply to a reschedule: `дд.мм.гггг причина`, the date not in the past and the reason given
+pub fn parse_agreed_date(text: &str, today: NaiveDate) -> Option<(NaiveDate, &str)> {
+    let (date, reason) = text.trim().split_once(' ')?;
+    let date = NaiveDate::parse_from_str(date, "%d.%m.%Y").ok()?;
+    let reason = reason.trim();
+    (date >= today && !reason.is_empty()).then_some((date, reason))
+}
+
+pub struct Actor<'a> {
+    pub user_id: i64,
+    pub user_name: &'a str,
+}
+
+impl Db {
+    pub async fn get_deal_by_id(&self, id: i32) -> Result<DealData> {
+        let deal = sqlx::query_as("SELECT * FROM deal WHERE id = $1")
+            .bind(id)
+            .fetch_one(&self.db)
+            .await?;
+        Ok(deal)
+    }
+
+    pub async fn acknowledge_alert(
+        &self,
+        deal: i32,
+        reason: &str,
+        actor: &Actor<'_>,
+    ) -> Result<()> {
+        self.record_alert_action(deal, AlertAction::Ack, actor, reason, None, None)
+            .await
+    }
+
+    pub async fn snooze_alert(
+        &self,
+        deal: i32,
+        until: NaiveDateTime,
+        reason: &str,
+        actor: &Actor<'_>,
+    ) -> Result<()> {
+        sqlx::query("UPDATE deal SET snoozed_until = $1 WHERE id = $2")
+            .bind(until)
+            .bind(deal)
+            .execute(&self.db)
+            .await?;
+        self.record_alert_action(deal, AlertAction::Snooze, actor, reason, Some(until), None)
+            .await
+    }
+
+    /// The agreed date replaces the computed transfer deadline
+    pub async fn reschedule_deadline(
+        &self,
+        deal: i32,
+        new_date: NaiveDate,
+        reason: &str,
+        actor: &Actor<'_>,
+    ) -> Result<()> {
+        sqlx::query("UPDATE deal SET agreed_deadline = $1 WHERE id = $2")
+            .bind(new_date)
+            .bind(deal)
+            .execute(&self.db)
+            .await?;
+        self.record_alert_action(
+            deal,
+            AlertAction::Reschedule,
+            actor,
+            reason,
+            None,
+            Some(new_date),
+        )
+        .await
+    }
+
+    async fn record_alert_action(
+        &self,
+        deal: i32,
+        action: AlertAction,
+        actor: &Actor<'_>,
+        reason: &str,
+        snoozed_until: Option<NaiveDateTime>,
+        agreed_deadline: Option<NaiveDate>,
+    ) -> Result<()> {
+        info!(
+            "[alert] deal: {deal}, action: {}, user: {} ({}), reason: {reason}",
+            action.as_str(),
+            actor.user_name,
+            actor.user_id
+        );
+        sqlx::query(
+            r#"
+            INSERT INTO alert_action (deal, action, user_id, user_name, reason, snoozed_until, agreed_deadline, created_on)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, datetime('now'))"#,
+        )
+        .bind(deal)
+        .bind(action.as_str())
+        .bind(actor.user_id)
+        .bind(actor.user_name)
+        .bind(reason)
+        .bind(snoozed_until)
+        .bind(agreed_deadline)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn alert_history(&self, deal: i32) -> Result<Vec<AlertRecord>> {
+        let records = sqlx::query_as("SELECT * FROM alert_action WHERE deal = $1 ORDER BY id")
+            .bind(deal)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_db;
+    use chrono::TimeZone;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_callback_data() {
+        let data = AlertAction::Snooze.callback_data(42);
+        assert_eq!(
+            AlertAction::parse_callback(&data),
+            Some((AlertAction::Snooze, 42))
+        );
+        assert_eq!(AlertAction::parse_callback("cal:1"), None);
+    }
+
+    #[test]
+    fn test_snooze_until() {
+        let schedule = Schedule::from_str("0 0 9 * * * *").unwrap();
+        let at = |h: u32| {
+            Tz::UTC
+                .from_local_datetime(
+                    &NaiveDate::from_ymd_opt(2025, 11, 5)
+                        .unwrap()
+                        .and_hms_opt(h, 0, 0)
+                        .unwrap(),
+                )
+                .unwrap()
+        };
+        let midnight = |d: u32| {
+            NaiveDate::from_ymd_opt(2025, 11, d)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        // pressed after today's run: tomorrow's run is skipped
+        assert_eq!(snooze_until(&schedule, at(10)), midnight(7));
+        // pressed before today's run: today's run is skipped
+        assert_eq!(snooze_until(&schedule, at(8)), midnight(6));
+    }
+
+    #[tokio::test]
+    async fn test_reschedule_overrides_deadline() {
+        let db = test_db().await;
+        let deal = DealData::builder().id(0, 5).property_num(3).build_lead();
+        db.create_deal(&deal).await.unwrap();
+        let id = db.get_all_undone_deals().await.unwrap()[0].id;
+        let actor = Actor {
+            user_id: 1,
+            user_name: "Тест",
+        };
+        let new_date = NaiveDate::from_ymd_opt(2030, 1, 15).unwrap();
+
+        db.acknowledge_alert(id, "Принято в работу", &actor)
+            .await
+            .unwrap();
+        db.reschedule_deadline(id, new_date, "Согласовано с покупателем", &actor)
+            .await
+            .unwrap();
+
+        assert_eq!(db.get_deal_by_id(id).await.unwrap().exp_date(), new_date);
+        let history = db.alert_history(id).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].reason, "Принято в работу");
+        assert_eq!(history[1].agreed_deadline, Some(new_date));
+    }
+
+    #[test]
+    fn test_parse_agreed_date() {
+        let today = NaiveDate::from_ymd_opt(2025, 10, 1).unwrap();
+        assert_eq!(
+            parse_agreed_date("15.10.2025  ключи после ремонта ", today),
+            Some((
+                NaiveDate::from_ymd_opt(2025, 10, 15).unwrap(),
+                "ключи после ремонта"
+            ))
+        );
+        assert_eq!(parse_agreed_date("15.10.2025", today), None);
+        assert_eq!(parse_agreed_date("15.10.2025   ", today), None);
+        assert_eq!(parse_agreed_date("15.09.2025 поздно", today), None);
+        assert_eq!(parse_agreed_date("2025-10-15 формат", today), None);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::Result;
+use crate::clock::{now, to_local};
+use crate::config::config;
+use crate::model::Db;
+use crate::model::deal::{DealData, deal_card};
+use chrono::{Months, NaiveDateTime};
+use log::{debug, info};
+
+pub(crate) const ARCHIVE_COLUMNS: &str = "id, deal_id, project, house, property_type, property_num, facing, \
+     days_limit, transfer_completed, created_on, updated_on, source, transferred_on, responsible_id, \
+     snoozed_until, agreed_deadline, days_limit_default, buyer";
+const SEARCH_LIMIT: i64 = 20;
+
+impl Db {
+    /// Moves deals transferred before `cutoff` from `deal` into `deal_archive`
+    pub async fn archive_transferred_before(&self, cutoff: NaiveDateTime) -> Result<u64> {
+        let mut tx = self.db.begin().await?;
+        let condition = "transfer_completed = true AND COALESCE(transferred_on, updated_on) < $1";
+
+        sqlx::query(&format!(
+            "INSERT INTO deal_archive ({ARCHIVE_COLUMNS}, archived_on) SELECT {ARCHIVE_COLUMNS}, datetime('now') FROM deal WHERE {condition}"
+        ))
+        .bind(cutoff)
+        .execute(&mut *tx)
+        .await?;
+        let res = sqlx::query(&format!("DELETE FROM deal WHERE {condition}"))
+            .bind(cutoff)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(res.rows_affected())
+    }
+
+    /// Brings an archived deal back when it returns to the funnel
+    pub async fn restore_from_archive(&self, project: &str, deal_id: u64) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+        let condition = "project = $1 AND deal_id = $2";
+
+        sqlx::query(&format!(
+            "INSERT INTO deal ({ARCHIVE_COLUMNS}) SELECT {ARCHIVE_COLUMNS} FROM deal_archive WHERE {condition}"
+        ))
+        .bind(project)
+        .bind(deal_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        let res = sqlx::query(&format!("DELETE FROM deal_archive WHERE {condition}"))
+            .bind(project)
+            .bind(deal_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "UPDATE deal SET transfer_completed = false, transferred_on = NULL WHERE project = $1 AND deal_id = $2",
+        )
+        .bind(project)
+        .bind(deal_id as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        let restored = res.rows_affected() > 0;
+        if restored {
+            info!("restored from archive project: {project}, deal_id: {deal_id}");
+        }
+        Ok(restored)
+    }
+
+    /// Searches the archive by object number, deal id or house
+    pub async fn search_archive(&self, query: &str) -> Result<Vec<DealData>> {
+        let records: Vec<DealData> = sqlx::query_as(
+            r#"
+            SELECT * FROM deal_archive
+                     WHERE CAST(property_num AS TEXT) = $1
+                        OR CAST(deal_id AS TEXT) = $1
+                        OR house LIKE '%' || $1 || '%'
+                     ORDER BY project, house, property_num
+                     LIMIT $2"#,
+        )
+        .bind(query)
+        .bind(SEARCH_LIMIT)
+        .fetch_all(&self.db)
+        .await?;
+        debug!("[search_archive] {query}: {} records", records.len());
+        Ok(records)
+    }
+}
+
+pub async fn archive_transferred() -> Result<u64> {
+    let months = config().RETENTION_MONTHS;
+    if months == 0 {
+        return Ok(0);
+    }
+    let Some(cutoff) = now().checked_sub_months(Months::new(months)) else {
+        return Ok(0);
+    };
+
+    let db = Db::new().await;
+    let archived = db.archive_transferred_before(cutoff).await?;
+    info!("Archived {archived} deals transferred before {cutoff}");
+    Ok(archived)
+}
+
+pub async fn search_archived_deals(query: &str) -> Result<Vec<String>> {
+    let db = Db::new().await;
+    let cards = db
+        .search_archive(query)
+        .await?
+        .iter()
+        .map(|d| {
+            let transferred = d
+                .transferred_on
+                .map(|t| to_local(&t).format("%d.%m.%Y").to_string())
+                .unwrap_or_else(|| "-".to_string());
+            format!("{}Передан: {}\n", deal_card(d), transferred)
+        })
+        .collect();
+    Ok(cards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_db;
+
+    #[tokio::test]
+    async fn test_archive_and_restore() {
+        let db = test_db().await;
+        let deal = DealData::builder()
+            .id(0, 77)
+            .house("Дом 4")
+            .property_num(15)
+            .buyer("Петров Пётр")
+            .build_lead();
+        db.create_deal(&deal).await.unwrap();
+        db.mark_as_transferred(&[77]).await.unwrap();
+
+        let future = now() + chrono::Duration::days(1);
+        assert_eq!(db.archive_transferred_before(future).await.unwrap(), 1);
+        assert!(db.get_all_undone_deals().await.unwrap().is_empty());
+        assert_eq!(db.search_archive("15").await.unwrap().len(), 1);
+
+        assert!(db.restore_from_archive(&deal.project, 77).await.unwrap());
+        let restored = db.get_all_undone_deals().await.unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].buyer, "Петров Пётр");
+        assert!(db.search_archive("15").await.unwrap().is_empty());
+    }
+}
@@ -1,47 +1,443 @@
 use crate::Result;
-use crate::adapters::mailer::Email;
-use crate::adapters::mailer::data_types::DealInfo;
+use crate::adapters::mailer::data_types::{DeadlineInfo, DeadlineSection, DealInfo, TierGroup};
+use crate::bot_interface::alert_keyboard;
+use crate::clock;
+use crate::config::config;
+use crate::error::Error;
 use crate::model::Db;
-use chrono::{Local, TimeZone};
-use log::{debug, info};
-use std::ops::Add;
-use std::time::Duration;
+use crate::model::deal::DealData;
+use crate::model::digest::digest_mode;
+use crate::model::notification::{Freshness, freshness};
+use crate::model::subscription::{MailKind, get_audiences};
+use crate::sender::send_msg_to_chat;
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::str::FromStr;
+use teloxide::payloads::SendMessageSetters;
+use teloxide::prelude::{ChatId, Requester};
+use teloxide::{Bot, RequestError};
 
-pub async fn search_deadline() -> Result<()> {
+/// Telegram takes about one message a second to a chat
+const ALERT_PAUSE: std::time::Duration = std::time::Duration::from_secs(1);
+/// Times an alert is repeated after the wait telegram asks for on flood limits
+const ALERT_RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Email,
+    Group,
+    Manager,
+    Director,
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Email => "email",
+            Channel::Group => "group",
+            Channel::Manager => "manager",
+            Channel::Director => "director",
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "email" => Ok(Channel::Email),
+            "group" => Ok(Channel::Group),
+            "manager" => Ok(Channel::Manager),
+            "director" => Ok(Channel::Director),
+            _ => Err(Error::ConfigWrongFormat("DEADLINE_TIERS")),
+        }
+    }
+}
+
+/// Variant order puts the most urgent tier first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TierLevel {
+    Overdue,
+    Days(i64),
+}
+
+impl TierLevel {
+    /// Stable name used in the notification log
+    pub fn key(&self) -> String {
+        match self {
+            TierLevel::Overdue => "overdue".to_string(),
+            TierLevel::Days(days) => days.to_string(),
+        }
+    }
+
+    pub fn title(&self) -> String {
+        match self {
+            TierLevel::Overdue => "Просрочено".to_string(),
+            TierLevel::Days(days) => format!("Осталось не более {days} дн."),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tier {
+    pub level: TierLevel,
+    pub channels: Vec<Channel>,
+}
+
+#[derive(Clone)]
+pub struct TierDeal {
+    pub deal: DealData,
+    pub days_left: i64,
+}
+
+impl TierDeal {
+    pub fn info(&self) -> DeadlineInfo {
+        DeadlineInfo {
+            deal: self.deal.clone().into(),
+            remaining: remaining_text(self.days_left),
+        }
+    }
+}
+
+/// Parses `DEADLINE_TIERS`, e.g. `7=email,group;1=email,director;overdue=email`
+pub fn parse_tiers(raw: &str) -> Result<Vec<Tier>> {
+    let mut tiers = raw
+        .split(';')
+        .filter(|t| !t.trim().is_empty())
+        .map(|t| {
+            let (level, channels) = t
+                .split_once('=')
+                .ok_or(Error::ConfigWrongFormat("DEADLINE_TIERS"))?;
+            let level = match level.trim() {
+                "overdue" => TierLevel::Overdue,
+                days => TierLevel::Days(
+                    days.parse::<i64>()
+                        .map_err(|_| Error::ConfigWrongFormat("DEADLINE_TIERS"))?,
+                ),
+            };
+            let channels = channels
+                .split(',')
+                .map(Channel::from_str)
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Tier { level, channels })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    tiers.sort_by_key(|t| t.level);
+    Ok(tiers)
+}
+
+/// The most urgent tier the deal falls into
+pub fn tier_for(tiers: &[Tier], days_left: i64) -> Option<&Tier> {
+    tiers.iter().find(|t| match t.level {
+        TierLevel::Overdue => days_left < 0,
+        TierLevel::Days(days) => (0..=days).contains(&days_left),
+    })
+}
+
+pub fn remaining_text(days_left: i64) -> String {
+    match days_left {
+        d if d < 0 => format!("Просрочено на {} дн.", -d),
+        0 => "Срок передачи сегодня".to_string(),
+        d => format!("Осталось {d} дн."),
+    }
+}
+
+/// AmoCRM user id to telegram id
+pub fn managers() -> HashMap<i64, i64> {
+    config()
+        .MANAGERS
+        .split(';')
+        .filter_map(|pair| {
+            let (amo_id, tg_id) = pair.split_once(':')?;
+            Some((amo_id.trim().parse().ok()?, tg_id.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Deals by the most urgent tier they fall into, snoozed ones left out,
+/// empty tiers dropped and the fewest days left first
+pub fn bucket_deals(
+    tiers: &[Tier],
+    deals: Vec<DealData>,
+    today: NaiveDate,
+    now: NaiveDateTime,
+) -> Vec<(&Tier, Vec<TierDeal>)> {
+    let mut buckets: Vec<(&Tier, Vec<TierDeal>)> = tiers.iter().map(|t| (t, vec![])).collect();
+    for deal in deals {
+        if deal.snoozed_until.is_some_and(|until| until > now) {
+            continue;
+        }
+        let days_left = (deal.exp_date() - today).num_days();
+        if let Some(tier) = tier_for(tiers, days_left)
+            && let Some((_, bucket)) = buckets.iter_mut().find(|(t, _)| t.level == tier.level)
+        {
+            bucket.push(TierDeal { deal, days_left });
+        }
+    }
+    buckets.retain(|(_, deals)| !deals.is_empty());
+    for (_, deals) in buckets.iter_mut() {
+        deals.sort_by_key(|d| d.days_left);
+    }
+    buckets
+}
+
+pub async fn search_deadline(bot: &Bot) -> Result<()> {
     info!("Searching for deadline objects");
+    let tiers = parse_tiers(&config().DEADLINE_TIERS)?;
     let db = Db::new().await;
     let deals = db.get_all_undone_deals().await?;
-    let mut deadline_objects: Vec<DealInfo> = vec![];
-    let now = Local::now();
-    for deal in deals {
-        let exp_date = deal
-            .created_on
-            .add(Duration::from_secs(86400 * deal.days_limit as u64));
+    let today = clock::today();
+    let now = clock::now();
 
-        let exp_dt = Local.from_local_datetime(&exp_date).unwrap();
+    let buckets = bucket_deals(&tiers, deals, today, now);
+    debug!(
+        "Found deadlines: {:?}",
+        buckets
+            .iter()
+            .map(|(t, d)| (t.level, d.len()))
+            .collect::<Vec<_>>()
+    );
 
-        let delta = exp_dt - now;
+    let renotify = Duration::days(config().RENOTIFY_DAYS);
 
-        if delta.num_days() < 5 {
-            deadline_objects.push(deal.into());
+    // the digest lists the deadlines instead
+    let audiences = if digest_mode() {
+        vec![]
+    } else {
+        get_audiences(&db, MailKind::Deadline).await?
+    };
+    // planned before anything is logged, so every audience sees the same history
+    let mut emails = vec![];
+    for audience in audiences {
+        let covered = |d: &TierDeal| audience.projects.covers(&d.deal.project);
+        let email = plan(&db, &buckets, Channel::Email, covered, now, renotify).await?;
+        if !email.is_empty() {
+            emails.push((audience, email));
         }
     }
-    debug!("{:#?}", deadline_objects);
-    debug!("Found {:#?} deadlines", deadline_objects.len());
+    // logged right after queueing, a failed audience does not repeat the earlier ones
+    for (audience, email) in emails {
+        audience
+            .email
+            .deadline_notification(email.sections())
+            .await?;
+        db.log_notifications(Channel::Email.as_str(), &email.entries())
+            .await?;
+    }
 
-    if !deadline_objects.is_empty() {
-        let email = Email::new();
-        email.deadline_notification(deadline_objects).await?;
+    let group = plan(&db, &buckets, Channel::Group, |_| true, now, renotify).await?;
+    if !group.is_empty() {
+        let sent = send_alerts(bot, config().TG_GROUP_ID, &group).await;
+        db.log_notifications(Channel::Group.as_str(), &sent).await?;
+    }
+
+    if config().DIRECTOR_ID != 0 {
+        let director = plan(&db, &buckets, Channel::Director, |_| true, now, renotify).await?;
+        if !director.is_empty() {
+            let sent = send_alerts(bot, config().DIRECTOR_ID, &director).await;
+            db.log_notifications(Channel::Director.as_str(), &sent)
+                .await?;
+        }
+    }
+
+    for (amo_id, tg_id) in managers() {
+        let own = plan(
+            &db,
+            &buckets,
+            Channel::Manager,
+            |d| d.deal.responsible_id == amo_id,
+            now,
+            renotify,
+        )
+        .await?;
+        if !own.is_empty() {
+            let sent = send_alerts(bot, tg_id, &own).await;
+            db.log_notifications(Channel::Manager.as_str(), &sent)
+                .await?;
+        }
     }
     Ok(())
 }
 
+/// Paced to the telegram flood limits, returns the entries of the alerts delivered,
+/// the rest are repeated by the next run
+async fn send_alerts(bot: &Bot, chat_id: i64, plan: &Plan<'_>) -> Vec<(i32, String)> {
+    send_msg_to_chat(bot, chat_id, &plan.tg_header()).await;
+    let mut sent = vec![];
+    for (deal, tier, text) in plan.tg_alerts() {
+        tokio::time::sleep(ALERT_PAUSE).await;
+        let mut retries = 0;
+        loop {
+            let res = bot
+                .send_message(ChatId(chat_id), &text)
+                .reply_markup(alert_keyboard(deal))
+                .await;
+            match res {
+                Ok(_) => sent.push((deal, tier)),
+                Err(RequestError::RetryAfter(wait)) if retries < ALERT_RETRIES => {
+                    retries += 1;
+                    warn!("Alerts to chat {chat_id} are flood limited, waiting {wait}");
+                    tokio::time::sleep(wait.duration()).await;
+                    continue;
+                }
+                Err(e) => error!("Unable to send alert: {text} to chat {chat_id}: {e}"),
+            }
+            break;
+        }
+    }
+    sent
+}
+
+type TierDeals<'a> = Vec<(&'a Tier, Vec<&'a TierDeal>)>;
+
+/// Deals of a single channel split by whether they were announced before
+struct Plan<'a> {
+    new: TierDeals<'a>,
+    pending: TierDeals<'a>,
+}
+
+async fn plan<'a>(
+    db: &Db,
+    buckets: &'a [(&'a Tier, Vec<TierDeal>)],
+    channel: Channel,
+    filter: impl Fn(&TierDeal) -> bool,
+    now: NaiveDateTime,
+    renotify: Duration,
+) -> Result<Plan<'a>> {
+    let last = db.last_notifications(channel.as_str()).await?;
+    let mut plan = Plan {
+        new: vec![],
+        pending: vec![],
+    };
+    for (tier, deals) in buckets
+        .iter()
+        .filter(|(tier, _)| tier.channels.contains(&channel))
+    {
+        let (mut new, mut pending) = (vec![], vec![]);
+        for d in deals.iter().filter(|d| filter(d)) {
+            match freshness(last.get(&d.deal.id), &tier.level.key(), now, renotify) {
+                Some(Freshness::New) => new.push(d),
+                Some(Freshness::Pending) => pending.push(d),
+                None => {}
+            }
+        }
+        if !new.is_empty() {
+            plan.new.push((*tier, new));
+        }
+        if !pending.is_empty() {
+            plan.pending.push((*tier, pending));
+        }
+    }
+    Ok(plan)
+}
+
+impl Plan<'_> {
+    const NEW_TITLE: &'static str = "Новые приближающиеся сроки";
+    const PENDING_TITLE: &'static str = "Всё ещё не переданы";
+
+    fn is_empty(&self) -> bool {
+        self.new.is_empty() && self.pending.is_empty()
+    }
+
+    fn parts(&self) -> [(&'static str, &TierDeals<'_>); 2] {
+        [
+            (Self::NEW_TITLE, &self.new),
+            (Self::PENDING_TITLE, &self.pending),
+        ]
+    }
+
+    fn entries(&self) -> Vec<(i32, String)> {
+        self.new
+            .iter()
+            .chain(self.pending.iter())
+            .flat_map(|(tier, deals)| deals.iter().map(|d| (d.deal.id, tier.level.key())))
+            .collect()
+    }
+
+    fn sections(&self) -> Vec<DeadlineSection> {
+        self.parts()
+            .into_iter()
+            .filter(|(_, groups)| !groups.is_empty())
+            .map(|(title, groups)| DeadlineSection {
+                title: title.to_string(),
+                tiers: groups
+                    .iter()
+                    .map(|(tier, deals)| TierGroup {
+                        title: tier.level.title(),
+                        deals: deals.iter().map(|d| d.info()).collect(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn tg_header(&self) -> String {
+        let count = |groups: &TierDeals<'_>| groups.iter().map(|(_, d)| d.len()).sum::<usize>();
+        format!(
+            "Дедлайн по передаче объектов\n{}: {}\n{}: {}",
+            Self::NEW_TITLE,
+            count(&self.new),
+            Self::PENDING_TITLE,
+            count(&self.pending)
+        )
+    }
+
+    /// One message per deal, so each alert gets its own buttons, with the deal and tier key
+    fn tg_alerts(&self) -> Vec<(i32, String, String)> {
+        let mut alerts = vec![];
+        for (title, groups) in self.parts() {
+            for (tier, deals) in groups {
+                for d in deals {
+                    let info: DealInfo = d.deal.clone().into();
+                    let text = format!(
+                        "{title}. {}\n{}, {}, {} № {}\n{} (до {})",
+                        tier.level.title(),
+                        info.project,
+                        info.house,
+                        info.property_type,
+                        info.property_num,
+                        remaining_text(d.days_left),
+                        info.exp_date
+                    );
+                    alerts.push((d.deal.id, tier.level.key(), text));
+                }
+            }
+        }
+        alerts
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_parse_tiers() {
+        let tiers = parse_tiers("7=email,group;overdue=email,director;1=manager").unwrap();
+        assert_eq!(tiers.len(), 3);
+        assert_eq!(tiers[0].level, TierLevel::Overdue);
+        assert_eq!(tiers[1].level, TierLevel::Days(1));
+        assert_eq!(tiers[2].channels, vec![Channel::Email, Channel::Group]);
+        assert!(parse_tiers("7=sms").is_err());
+        assert!(parse_tiers("week=email").is_err());
+    }
+
+    #[test]
+    fn test_tier_for() {
+        let tiers = parse_tiers("14=email;3=email;overdue=email").unwrap();
+        assert_eq!(tier_for(&tiers, -2).unwrap().level, TierLevel::Overdue);
+        assert_eq!(tier_for(&tiers, 0).unwrap().level, TierLevel::Days(3));
+        assert_eq!(tier_for(&tiers, 3).unwrap().level, TierLevel::Days(3));
+        assert_eq!(tier_for(&tiers, 4).unwrap().level, TierLevel::Days(14));
+        assert!(tier_for(&tiers, 15).is_none());
+    }
+
     #[tokio::test]
     async fn test_search_deadline() {
-        let res = search_deadline().await;
+        let bot = Bot::from_env();
+        let res = search_deadline(&bot).await;
         if res.is_err() {
             println!("Error: {:?}", res);
         }
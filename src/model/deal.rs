@@ -1,11 +1,19 @@
 use crate::Result;
 use crate::adapters::amo::amo_types::Deal;
+use crate::adapters::mailer::OutgoingMail;
+use crate::adapters::mailer::data_types::DealCard;
+use crate::calendar::deadline;
+use crate::clock::{now, to_local};
 use crate::model::Db;
+use crate::model::outbox::insert_email;
+use crate::model::snapshot::{DealEvent, insert_deal_event};
 use log::{debug, error, info};
-use sqlx::FromRow;
-use sqlx::types::chrono::NaiveDateTime;
-use std::ops::Add;
-use std::time::Duration;
+use sqlx::types::chrono::{NaiveDate, NaiveDateTime};
+use sqlx::{Executor, FromRow, Sqlite};
+use std::fmt::Write;
+
+/// AmoCRM lead ids stay far below, imported deals get this plus their row id
+const IMPORTED_DEAL_IDS: i64 = 1_000_000_000_000;
 
 #[allow(dead_code)]
 #[derive(FromRow, Clone)]
@@ -21,7 +29,146 @@ pub struct DealData {
     pub transfer_completed: bool,
     pub created_on: NaiveDateTime,
     pub updated_on: String,
+    pub transferred_on: Option<NaiveDateTime>,
+    pub responsible_id: i64,
+    pub snoozed_until: Option<NaiveDateTime>,
+    pub agreed_deadline: Option<NaiveDate>,
+    pub days_limit_default: bool,
+    pub buyer: String,
+}
+impl DealData {
+    /// A transfer date agreed with the buyer overrides the computed one
+    pub fn exp_date(&self) -> NaiveDate {
+        if let Some(agreed) = self.agreed_deadline {
+            return agreed;
+        }
+        deadline(
+            &self.created_on,
+            self.days_limit,
+            &self.project,
+            &self.property_type,
+        )
+    }
+}
+
+/// Deal for unit tests: ЖК Формат, Дом 1, Квартира № 1 in work, sold now with 30 days limit
+#[cfg(test)]
+pub struct DealBuilder(DealData);
+
+#[cfg(test)]
+impl DealData {
+    pub fn builder() -> DealBuilder {
+        DealBuilder(DealData {
+            id: 0,
+            deal_id: 0,
+            project: "ЖК Формат".to_string(),
+            house: "Дом 1".to_string(),
+            property_type: "Квартира".to_string(),
+            property_num: 1,
+            facing: "".to_string(),
+            days_limit: 30,
+            transfer_completed: false,
+            created_on: now(),
+            updated_on: "".to_string(),
+            transferred_on: None,
+            responsible_id: 0,
+            snoozed_until: None,
+            agreed_deadline: None,
+            days_limit_default: false,
+            buyer: "".to_string(),
+        })
+    }
 }
+
+#[cfg(test)]
+impl DealBuilder {
+    pub fn id(mut self, id: i32, deal_id: u64) -> Self {
+        self.0.id = id;
+        self.0.deal_id = deal_id;
+        self
+    }
+
+    pub fn project(mut self, project: &str) -> Self {
+        self.0.project = project.to_string();
+        self
+    }
+
+    pub fn house(mut self, house: &str) -> Self {
+        self.0.house = house.to_string();
+        self
+    }
+
+    pub fn property_type(mut self, property_type: &str) -> Self {
+        self.0.property_type = property_type.to_string();
+        self
+    }
+
+    pub fn property_num(mut self, property_num: i32) -> Self {
+        self.0.property_num = property_num;
+        self
+    }
+
+    pub fn facing(mut self, facing: &str) -> Self {
+        self.0.facing = facing.to_string();
+        self
+    }
+
+    pub fn days_limit(mut self, days_limit: i32) -> Self {
+        self.0.days_limit = days_limit;
+        self
+    }
+
+    /// Sold at local noon, clear of the day boundaries
+    pub fn created(mut self, date: NaiveDate) -> Self {
+        self.0.created_on = at_noon(date);
+        self
+    }
+
+    /// Transferred at local noon, `None` keeps the deal in work
+    pub fn transferred(mut self, date: Option<NaiveDate>) -> Self {
+        self.0.transfer_completed = date.is_some();
+        self.0.transferred_on = date.map(at_noon);
+        self
+    }
+
+    pub fn buyer(mut self, buyer: &str) -> Self {
+        self.0.buyer = buyer.to_string();
+        self
+    }
+
+    pub fn default_limit(mut self, default_limit: bool) -> Self {
+        self.0.days_limit_default = default_limit;
+        self
+    }
+
+    pub fn build(self) -> DealData {
+        self.0
+    }
+
+    /// The same deal as the funnel sync reads it from AmoCRM
+    pub fn build_lead(self) -> Deal {
+        let d = self.0;
+        Deal {
+            deal_id: d.deal_id,
+            project: d.project,
+            house: d.house,
+            property_type: d.property_type,
+            property_num: d.property_num,
+            facing: d.facing,
+            days_limit: d.days_limit,
+            created_on: d.created_on,
+            responsible_id: d.responsible_id,
+            default_limit: d.days_limit_default,
+            buyer: d.buyer,
+        }
+    }
+}
+
+#[cfg(test)]
+fn at_noon(date: NaiveDate) -> NaiveDateTime {
+    crate::clock::from_local(&date.and_hms_opt(12, 0, 0).unwrap())
+}
+
 #[derive(FromRow, Debug)]
 pub struct HouseNumbers {
     pub house: String,
@@ -31,6 +178,34 @@ pub struct PropertyNumbers {
     pub property_num: i32,
 }
 
+async fn insert_deal<'e, E>(executor: E, d: &Deal) -> Result<()>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    debug!("create deal with data: {:?}", &d);
+    let (id, ): (i64,) = sqlx::query_as(
+        r#"
+            INSERT INTO deal (deal_id, project, house, property_type, property_num, facing, days_limit, created_on, responsible_id, days_limit_default, updated_on, buyer)
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) returning id"#,
+    )
+        .bind(d.deal_id as i64)
+        .bind(&d.project)
+        .bind(&d.house)
+        .bind(&d.property_type)
+        .bind(d.property_num)
+        .bind(&d.facing)
+        .bind(d.days_limit)
+        .bind(d.created_on)
+        .bind(d.responsible_id)
+        .bind(d.default_limit)
+        .bind(now())
+        .bind(&d.buyer)
+        .fetch_one(executor)
+        .await?;
+    debug!("Created row with id: {}", id);
+    Ok(())
+}
+
 impl Db {
     pub async fn get_all_undone_deals(&self) -> Result<Vec<DealData>> {
         let records: Vec<DealData> =
@@ -88,24 +263,23 @@ impl Db {
         Ok(res)
     }
 
+    #[cfg(test)]
     pub async fn create_deal(&self, d: &Deal) -> Result<()> {
-        debug!("create deal with data: {:?}", &d);
-        let (id, ): (i64,) = sqlx::query_as(
-            r#"
-                INSERT INTO deal (deal_id, project, house, property_type, property_num, facing, days_limit, created_on)
-                VALUES($1, $2, $3, $4, $5, $6, $7, $8) returning id"#,
-        )
-            .bind(d.deal_id as i64)
-            .bind(&d.project)
-            .bind(&d.house)
-            .bind(&d.property_type)
-            .bind(d.property_num)
-            .bind(&d.facing)
-            .bind(d.days_limit)
-            .bind(d.created_on)
-            .fetch_one(&self.db)
-            .await?;
-        debug!("Created row with id: {}", id);
+        insert_deal(&self.db, d).await
+    }
+
+    /// New funnel deals, their events and the emails about them are stored
+    /// together, so a failed delivery cannot lose the notification
+    pub async fn create_new_deals(&self, deals: &[Deal], mails: &[OutgoingMail]) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        for d in deals {
+            insert_deal(&mut *tx, d).await?;
+            insert_deal_event(&mut *tx, &d.project, d.deal_id, DealEvent::New).await?;
+        }
+        for mail in mails {
+            insert_email(&mut *tx, mail).await?;
+        }
+        tx.commit().await?;
         Ok(())
     }
 
@@ -114,8 +288,9 @@ impl Db {
         for id in ids {
             let res = sqlx::query(
                 r#"
-                UPDATE deal SET transfer_completed = true
-                            WHERE deal.deal_id = $1"#,
+                UPDATE deal SET transfer_completed = true,
+                                transferred_on = datetime('now')
+                            WHERE deal.deal_id = $1 AND transfer_completed = false"#,
             )
             .bind(*id as i64)
             .execute(&self.db)
@@ -141,7 +316,7 @@ impl Db {
     pub async fn mark_as_not_transferred(&self, project: &str, deal_id: u64) -> Result<bool> {
         let res = sqlx::query(
             r#"
-                UPDATE deal SET transfer_completed = false
+                UPDATE deal SET transfer_completed = false, transferred_on = NULL
                             WHERE project = $1 AND deal.deal_id = $2"#,
         )
         .bind(project)
@@ -158,14 +333,23 @@ impl Db {
         Ok(updated)
     }
 
-    pub async fn set_days_limit(&self, project: &str, deal_id: u64, days_limit: i32) -> Result<()> {
-        info!("[set_days_limit] project: {project}, deal_id: {deal_id}, limit: {days_limit}");
+    pub async fn set_days_limit(
+        &self,
+        project: &str,
+        deal_id: u64,
+        days_limit: i32,
+        default_limit: bool,
+    ) -> Result<()> {
+        info!(
+            "[set_days_limit] project: {project}, deal_id: {deal_id}, limit: {days_limit}, default: {default_limit}"
+        );
         let res = sqlx::query(
             r#"
-                UPDATE deal SET days_limit = $1
-                            WHERE project = $2 AND deal_id = $3"#,
+                UPDATE deal SET days_limit = $1, days_limit_default = $2
+                            WHERE project = $3 AND deal_id = $4"#,
         )
         .bind(days_limit)
+        .bind(default_limit)
         .bind(project)
         .bind(deal_id as i64)
         .execute(&self.db)
@@ -174,14 +358,109 @@ impl Db {
         Ok(())
     }
 
+    /// Imported deals are numbered past the AmoCRM ids, so each can be restored from the archive
+    pub async fn import_deal(&self, d: &Deal, transferred_on: Option<NaiveDateTime>) -> Result<()> {
+        debug!("import deal with data: {:?}", &d);
+        let mut tx = self.db.begin().await?;
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+                INSERT INTO deal (deal_id, project, house, property_type, property_num, facing, days_limit, transfer_completed, created_on, source, updated_on, transferred_on)
+                VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, 'import', $10, $11) returning id"#,
+        )
+            .bind(d.deal_id as i64)
+            .bind(&d.project)
+            .bind(&d.house)
+            .bind(&d.property_type)
+            .bind(d.property_num)
+            .bind(&d.facing)
+            .bind(d.days_limit)
+            .bind(transferred_on.is_some())
+            .bind(d.created_on)
+            .bind(now())
+            .bind(transferred_on)
+            .fetch_one(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE deal SET deal_id = $1 WHERE id = $2")
+            .bind(IMPORTED_DEAL_IDS + id)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Archived deals count too, so a file imported twice adds nothing
+    pub async fn deal_exists(
+        &self,
+        project: &str,
+        property_type: &str,
+        house: &str,
+        number: i32,
+    ) -> Result<bool> {
+        let condition = "project = $1 AND property_type = $2 AND house = $3 AND property_num = $4";
+        let (count,): (i64,) = sqlx::query_as(&format!(
+            "SELECT (SELECT COUNT(*) FROM deal WHERE {condition}) + (SELECT COUNT(*) FROM deal_archive WHERE {condition})"
+        ))
+        .bind(project)
+        .bind(property_type)
+        .bind(house)
+        .bind(number)
+        .fetch_one(&self.db)
+        .await?;
+        Ok(count > 0)
+    }
+
+    pub async fn set_responsible(
+        &self,
+        project: &str,
+        deal_id: u64,
+        responsible_id: i64,
+    ) -> Result<()> {
+        let res = sqlx::query(
+            r#"
+                UPDATE deal SET responsible_id = $1
+                            WHERE project = $2 AND deal_id = $3 AND responsible_id != $1"#,
+        )
+        .bind(responsible_id)
+        .bind(project)
+        .bind(deal_id as i64)
+        .execute(&self.db)
+        .await?;
+        if res.rows_affected() > 0 {
+            info!(
+                "[set_responsible] project: {project}, deal_id: {deal_id}, responsible: {responsible_id}"
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn set_buyer(&self, project: &str, deal_id: u64, buyer: &str) -> Result<()> {
+        let res = sqlx::query(
+            r#"
+                UPDATE deal SET buyer = $1
+                            WHERE project = $2 AND deal_id = $3 AND buyer != $1"#,
+        )
+        .bind(buyer)
+        .bind(project)
+        .bind(deal_id as i64)
+        .execute(&self.db)
+        .await?;
+        if res.rows_affected() > 0 {
+            info!("[set_buyer] project: {project}, deal_id: {deal_id}, buyer: {buyer}");
+        }
+        Ok(())
+    }
+
+    /// Only AmoCRM deals take part in funnel sync, imported ones are never in the funnel
     pub async fn read_deal_ids(&self) -> Result<Vec<(u64, i32, bool)>> {
-        let records: Vec<DealData> =
-            sqlx::query_as("SELECT * FROM deal WHERE transfer_completed = false")
-                .fetch_all(&self.db)
-                .await?;
+        let records: Vec<DealData> = sqlx::query_as(
+            "SELECT * FROM deal WHERE transfer_completed = false AND source = 'amo'",
+        )
+        .fetch_all(&self.db)
+        .await?;
         let res = records
             .iter()
-            .map(|r| (r.deal_id, r.days_limit, r.transfer_completed))
+            .map(|r| (r.deal_id, r.days_limit, r.days_limit_default))
             .collect();
         Ok(res)
     }
@@ -229,47 +508,43 @@ pub async fn get_property_numbers(project: &str, property_type: &str, house: &st
     })
 }
 
+/// Deal card and the deal row id, `None` when the deal is not found
 pub async fn prepare_response(
     project: &str,
     property_type: &str,
     house: &str,
     number: i32,
-) -> String {
+) -> (String, Option<i32>) {
     let db = Db::new().await;
     let result = db.get_deal(project, property_type, house, number).await;
 
     match result {
         Ok(b) => {
-            let facing = if b.property_type.eq("property") {
-                format!("Тип отделки: {}\n", b.facing)
-            } else {
-                "".to_string()
-            };
-
-            let res = format!(
-                "Сделка: {}\nПроект: {}\n{}\nТип объекта: {}\n№ {}\n{}Дата регистрации: {}\nПередать объект до: {}\n",
-                b.deal_id,
-                b.project,
-                b.house,
-                b.property_type,
-                b.property_num,
-                facing,
-                b.created_on.format("%d.%m.%Y"),
-                b.created_on
-                    .add(Duration::from_secs(86400 * b.days_limit as u64))
-                    .format("%d.%m.%Y")
-            );
-
-            if res.is_empty() {
-                "Нет данных".to_string()
-            } else {
-                res
+            let mut card = deal_card(&b);
+            // audit trail of deadline alerts
+            if let Ok(history) = db.alert_history(b.id).await {
+                for r in history {
+                    let _ = write!(
+                        card,
+                        "\n{} {}: {}",
+                        to_local(&r.created_on).format("%d.%m.%Y %H:%M"),
+                        r.user_name,
+                        r.reason
+                    );
+                    if let Some(date) = r.agreed_deadline {
+                        let _ = write!(card, " (новая дата {})", date.format("%d.%m.%Y"));
+                    }
+                }
             }
+            (card, Some(b.id))
         }
-
         Err(e) => {
             error!("Prepare response error: {}", e);
-            "Ошибка чтения данных".to_string()
+            ("Ошибка чтения данных".to_string(), None)
         }
     }
 }
+
+pub fn deal_card(b: &DealData) -> String {
+    format!("{}\n", DealCard::new(b.clone().into()))
+}
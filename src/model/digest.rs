@@ -0,0 +1,251 @@
+use crate::Result;
+use crate::adapters::mailer::OutgoingMail;
+use crate::adapters::mailer::data_types::TierGroup;
+use crate::chart::get_charts;
+use crate::clock::{self, to_local};
+use crate::config::config;
+use crate::model::Db;
+use crate::model::deadline::{Channel, Tier, TierDeal, TierLevel, bucket_deals, parse_tiers};
+use crate::model::deal::DealData;
+use crate::model::kpi::Kpi;
+use crate::model::outbox::insert_email;
+use crate::model::snapshot::DealEvent;
+use crate::model::stat::{aggregate, apply_deltas};
+use crate::model::subscription::{MailKind, Projects, get_audiences};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use log::{error, info};
+
+/// A digest schedule replaces the new deals, deadline and stat emails, telegram alerts stay
+pub fn digest_mode() -> bool {
+    !config().DIGEST_SCHEDULE.trim().is_empty()
+}
+
+/// Everything one daily digest reports, `[since, until)` for the events
+#[derive(Clone)]
+pub struct Digest {
+    pub since: NaiveDateTime,
+    pub until: NaiveDateTime,
+    pub new_sales: Vec<DealData>,
+    pub transferred: Vec<DealData>,
+    /// Email tiers only, the most urgent first
+    pub deadlines: Vec<(TierLevel, Vec<TierDeal>)>,
+    pub in_work: Vec<DealData>,
+    /// All transferred deals, the KPI are computed over them
+    pub history: Vec<DealData>,
+}
+
+impl Digest {
+    pub async fn load(
+        db: &Db,
+        tiers: &[Tier],
+        since: NaiveDateTime,
+        until: NaiveDateTime,
+        today: NaiveDate,
+    ) -> Result<Self> {
+        let in_work = db.get_all_undone_deals().await?;
+        let email_tiers: Vec<Tier> = tiers
+            .iter()
+            .filter(|t| t.channels.contains(&Channel::Email))
+            .cloned()
+            .collect();
+        let deadlines = bucket_deals(&email_tiers, in_work.clone(), today, until)
+            .into_iter()
+            .map(|(tier, deals)| (tier.level, deals))
+            .collect();
+        Ok(Self {
+            since,
+            until,
+            new_sales: db.deals_with_event(DealEvent::New, since, until).await?,
+            transferred: db
+                .deals_with_event(DealEvent::Transferred, since, until)
+                .await?,
+            deadlines,
+            in_work,
+            history: db.transferred_deals().await?,
+        })
+    }
+
+    pub fn for_projects(&self, projects: &Projects) -> Self {
+        Self {
+            since: self.since,
+            until: self.until,
+            new_sales: projects.filter(&self.new_sales, |d| &d.project),
+            transferred: projects.filter(&self.transferred, |d| &d.project),
+            deadlines: self
+                .deadlines
+                .iter()
+                .map(|(level, deals)| (*level, projects.filter(deals, |d| &d.deal.project)))
+                .filter(|(_, deals)| !deals.is_empty())
+                .collect(),
+            in_work: projects.filter(&self.in_work, |d| &d.project),
+            history: projects.filter(&self.history, |d| &d.project),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.new_sales.is_empty() && self.transferred.is_empty() && self.in_work.is_empty()
+    }
+
+    pub fn tier_groups(&self) -> Vec<TierGroup> {
+        self.deadlines
+            .iter()
+            .map(|(level, deals)| TierGroup {
+                title: level.title(),
+                deals: deals.iter().map(TierDeal::info).collect(),
+            })
+            .collect()
+    }
+
+    pub fn deadline_deals(&self) -> Vec<DealData> {
+        self.deadlines
+            .iter()
+            .flat_map(|(_, deals)| deals.iter().map(|d| d.deal.clone()))
+            .collect()
+    }
+
+    pub fn period_text(&self) -> String {
+        format!(
+            "{} – {}",
+            to_local(&self.since).format("%d.%m.%Y %H:%M"),
+            to_local(&self.until).format("%d.%m.%Y %H:%M")
+        )
+    }
+}
+
+impl Db {
+    /// End of the period the previous digest covered
+    pub async fn last_digest(&self) -> Result<Option<NaiveDateTime>> {
+        let (until,): (Option<NaiveDateTime>,) =
+            sqlx::query_as("SELECT MAX(period_end) FROM digest_log")
+                .fetch_one(&self.db)
+                .await?;
+        Ok(until)
+    }
+
+    /// Emails and the period they cover are stored together, a failed run repeats the whole period
+    pub async fn save_digest(&self, until: NaiveDateTime, mails: &[OutgoingMail]) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        for mail in mails {
+            insert_email(&mut *tx, mail).await?;
+        }
+        sqlx::query("INSERT INTO digest_log (period_end, emails) VALUES ($1, $2)")
+            .bind(until)
+            .bind(mails.len() as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+pub async fn send_digest() -> Result<()> {
+    let db = Db::new().await;
+    let tiers = parse_tiers(&config().DEADLINE_TIERS)?;
+    let until = clock::now();
+    let since = db.last_digest().await?.unwrap_or(until - Duration::days(1));
+    let today = clock::today();
+    let digest = Digest::load(&db, &tiers, since, until, today).await?;
+    let previous = db.snapshot_before(today).await?;
+
+    let mut mails = vec![];
+    for audience in get_audiences(&db, MailKind::Digest).await? {
+        let digest = digest.for_projects(&audience.projects);
+        if digest.is_empty() {
+            continue;
+        }
+        let mut projects = aggregate(&digest.in_work);
+        let previous = audience.projects.filter(&previous, |s| &s.project);
+        apply_deltas(&mut projects, &previous);
+        let kpi = Kpi::compute(&digest.history, &digest.in_work, today);
+        // the numbers still go out when charts can't be drawn
+        let charts = get_charts(&audience.projects).await.unwrap_or_else(|e| {
+            error!("Failed to render charts: {e}");
+            vec![]
+        });
+        mails.push(
+            audience
+                .email
+                .digest_mail(&digest, projects, &kpi, &charts)?,
+        );
+    }
+    db.save_digest(until, &mails).await?;
+    info!(
+        "Digest for {} queued: {}",
+        digest.period_text(),
+        mails.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::amo::amo_types::Deal;
+    use crate::layout::{DEFAULT_LAYOUT, layouts};
+    use crate::model::subscription::parse_subscription;
+    use crate::model::test_db;
+    use crate::xlsx::Xlsx;
+    use calamine::Reader;
+
+    fn deal(deal_id: u64, project: &str, days_limit: i32) -> Deal {
+        DealData::builder()
+            .id(0, deal_id)
+            .project(project)
+            .property_num(deal_id as i32)
+            .days_limit(days_limit)
+            .build_lead()
+    }
+
+    #[tokio::test]
+    async fn test_digest() {
+        let db = test_db().await;
+        let tiers = parse_tiers("7=email,group;30=group").unwrap();
+        let since = clock::now() - Duration::hours(1);
+        db.create_new_deals(&[deal(1, "ЖК Формат", 3), deal(2, "DNS Сити", 20)], &[])
+            .await
+            .unwrap();
+        let until = clock::now() + Duration::seconds(1);
+        let today = clock::today();
+
+        let digest = Digest::load(&db, &tiers, since, until, today)
+            .await
+            .unwrap();
+        assert_eq!(digest.new_sales.len(), 2);
+        assert!(digest.transferred.is_empty());
+        // the 30 days tier goes to telegram only
+        assert_eq!(digest.deadline_deals().len(), 1);
+        assert_eq!(digest.tier_groups()[0].title, "Осталось не более 7 дн.");
+
+        let projects = parse_subscription("dns@example.com|DNS|digest|DNS Сити")
+            .unwrap()
+            .projects();
+        let other = digest.for_projects(&projects);
+        assert_eq!(other.new_sales.len(), 1);
+        assert!(other.deadlines.is_empty());
+        assert!(!other.is_empty());
+
+        let later = Digest::load(&db, &tiers, until, until + Duration::days(1), today)
+            .await
+            .unwrap();
+        assert!(later.new_sales.is_empty());
+
+        let layout = layouts().get("", DEFAULT_LAYOUT).unwrap();
+        let buf = Xlsx::create_digest(&digest, layout).unwrap();
+        let workbook: calamine::Xlsx<_> =
+            calamine::open_workbook_from_rs(std::io::Cursor::new(buf)).unwrap();
+        assert_eq!(
+            workbook.sheet_names(),
+            vec![
+                "Новые продажи",
+                "Передано",
+                "Сроки передачи",
+                "В работе",
+                "Сводка"
+            ]
+        );
+
+        assert_eq!(db.last_digest().await.unwrap(), None);
+        db.save_digest(until, &[]).await.unwrap();
+        assert_eq!(db.last_digest().await.unwrap(), Some(until));
+    }
+}
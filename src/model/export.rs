@@ -0,0 +1,374 @@
+use crate::Result;
+use crate::clock::{self, to_local};
+use crate::error::Error;
+use crate::import::{COLUMNS, TRANSFERRED_COLUMN, TRANSFERRED_ON_COLUMN};
+use crate::layout::{DEFAULT_LAYOUT, Layout, REPORT_LAYOUT, layouts};
+use crate::model::Db;
+use crate::model::deal::DealData;
+use crate::xlsx::Xlsx;
+use chrono::NaiveDate;
+use serde::Serialize;
+
+pub const EXPORT_USAGE: &str = "Шаблон: /export проект=ЖК Формат; тип=Квартира; дом=Дом 1; \
+статус=в работе|просрочен|передан; с=01.10.2025; по=31.10.2025; формат=xlsx|csv|json; \
+макет=для руководства\n\
+Все фильтры необязательны, /export all - все объекты в xlsx, /export без параметров - выбор кнопками.\n\
+Период - по дате регистрации, для переданных - по дате передачи.\n\
+Макет задаёт столбцы xlsx, для переданных по умолчанию - с датой передачи, csv всегда в формате /import.";
+
+/// Keyboard choice that leaves a filter unset
+pub const ANY_CHOICE: &str = "Все";
+pub const STATUS_CHOICES: [&str; 3] = ["в работе", "просрочен", "передан"];
+pub const FORMAT_CHOICES: [&str; 3] = ["xlsx", "csv", "json"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportStatus {
+    InWork,
+    Overdue,
+    Transferred,
+}
+
+impl ExportStatus {
+    pub fn parse(value: &str) -> Option<ExportStatus> {
+        match value.to_lowercase().as_str() {
+            "в работе" | "work" => Some(ExportStatus::InWork),
+            "просрочен" | "просрочено" | "overdue" => {
+                Some(ExportStatus::Overdue)
+            }
+            "передан" | "передано" | "transferred" => {
+                Some(ExportStatus::Transferred)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Xlsx,
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn parse(value: &str) -> Option<ExportFormat> {
+        match value.to_lowercase().as_str() {
+            "xlsx" => Some(ExportFormat::Xlsx),
+            "csv" => Some(ExportFormat::Csv),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Xlsx => "xlsx",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportFilter {
+    pub project: Option<String>,
+    pub property_type: Option<String>,
+    pub house: Option<String>,
+    pub status: Option<ExportStatus>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub format: ExportFormat,
+    /// Xlsx layout name, the default one when empty
+    pub layout: String,
+}
+
+fn parse_date(value: &str) -> std::result::Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%d.%m.%Y").map_err(|_| format!("неверная дата \"{value}\""))
+}
+
+impl ExportFilter {
+    /// `key=value` pairs separated by `;`, "all" or nothing selects everything
+    pub fn parse(args: &str) -> std::result::Result<ExportFilter, String> {
+        let mut filter = ExportFilter::default();
+        let args = args.trim();
+        if args.is_empty() || args.eq_ignore_ascii_case("all") {
+            return Ok(filter);
+        }
+        for pair in args.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!("ожидается ключ=значение: \"{pair}\""));
+            };
+            let value = value.trim();
+            match key.trim().to_lowercase().as_str() {
+                "проект" | "project" => filter.project = Some(value.to_string()),
+                "тип" | "type" => filter.property_type = Some(value.to_string()),
+                "дом" | "house" => filter.house = Some(value.to_string()),
+                "статус" | "status" => {
+                    filter.status = Some(
+                        ExportStatus::parse(value)
+                            .ok_or_else(|| format!("неизвестный статус \"{value}\""))?,
+                    )
+                }
+                "с" | "from" => filter.from = Some(parse_date(value)?),
+                "по" | "to" => filter.to = Some(parse_date(value)?),
+                "формат" | "format" => {
+                    filter.format = ExportFormat::parse(value)
+                        .ok_or_else(|| format!("неизвестный формат \"{value}\""))?
+                }
+                "макет" | "layout" => {
+                    layouts().get(value, DEFAULT_LAYOUT).map_err(|_| {
+                        format!(
+                            "неизвестный макет \"{value}\", доступны: {}",
+                            layouts().names().join(", ")
+                        )
+                    })?;
+                    filter.layout = value.to_string();
+                }
+                other => return Err(format!("неизвестный фильтр \"{other}\"")),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Transferred deals have no days left, their sheet shows the transfer date instead
+    pub fn default_layout(&self) -> &'static str {
+        match self.status {
+            Some(ExportStatus::Transferred) => REPORT_LAYOUT,
+            _ => DEFAULT_LAYOUT,
+        }
+    }
+
+    pub fn matches(&self, deal: &DealData, today: NaiveDate) -> bool {
+        let same = |filter: &Option<String>, value: &str| {
+            filter
+                .as_ref()
+                .is_none_or(|f| f.to_lowercase() == value.to_lowercase())
+        };
+        if !same(&self.project, &deal.project)
+            || !same(&self.property_type, &deal.property_type)
+            || !same(&self.house, &deal.house)
+        {
+            return false;
+        }
+        let status_ok = match self.status {
+            None => true,
+            Some(ExportStatus::InWork) => !deal.transfer_completed,
+            Some(ExportStatus::Overdue) => !deal.transfer_completed && deal.exp_date() < today,
+            Some(ExportStatus::Transferred) => deal.transfer_completed,
+        };
+        if !status_ok {
+            return false;
+        }
+        if self.from.is_none() && self.to.is_none() {
+            return true;
+        }
+        let date = match self.status {
+            Some(ExportStatus::Transferred) => match deal.transferred_on {
+                Some(t) => to_local(&t).date(),
+                None => return false,
+            },
+            _ => to_local(&deal.created_on).date(),
+        };
+        self.from.is_none_or(|from| date >= from) && self.to.is_none_or(|to| date <= to)
+    }
+}
+
+/// Plain row for CSV and JSON consumers, dates are ISO 8601
+#[derive(Debug, Serialize)]
+pub struct ExportRow {
+    pub deal_id: u64,
+    pub project: String,
+    pub house: String,
+    pub property_type: String,
+    pub property_num: i32,
+    pub facing: String,
+    pub reg_date: String,
+    pub exp_date: String,
+    pub transferred: bool,
+    pub transferred_on: Option<String>,
+}
+
+impl From<&DealData> for ExportRow {
+    fn from(d: &DealData) -> Self {
+        Self {
+            deal_id: d.deal_id,
+            project: d.project.clone(),
+            house: d.house.clone(),
+            property_type: d.property_type.clone(),
+            property_num: d.property_num,
+            facing: d.facing.clone(),
+            reg_date: to_local(&d.created_on).date().to_string(),
+            exp_date: d.exp_date().to_string(),
+            transferred: d.transfer_completed,
+            transferred_on: d.transferred_on.map(|t| to_local(&t).date().to_string()),
+        }
+    }
+}
+
+/// Same layout `/import` reads, with the transfer flag and date at the end
+fn to_csv(deals: &[DealData]) -> Result<Vec<u8>> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b';')
+        .from_writer(b"\xEF\xBB\xBF".to_vec());
+    let mut header: Vec<&str> = COLUMNS.to_vec();
+    header.extend([TRANSFERRED_COLUMN, TRANSFERRED_ON_COLUMN]);
+    writer.write_record(&header)?;
+    let date = |d: NaiveDate| d.format("%d.%m.%Y").to_string();
+    for d in deals {
+        writer.write_record([
+            d.project.clone(),
+            d.house.clone(),
+            d.property_type.clone(),
+            d.property_num.to_string(),
+            d.facing.clone(),
+            date(to_local(&d.created_on).date()),
+            date(d.exp_date()),
+            if d.transfer_completed {
+                "да"
+            } else {
+                "нет"
+            }
+            .to_string(),
+            d.transferred_on
+                .map(|t| date(to_local(&t).date()))
+                .unwrap_or_default(),
+        ])?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| Error::AppErr(format!("Failed to write csv: {e}")))
+}
+
+fn to_json(deals: &[DealData]) -> Result<Vec<u8>> {
+    let rows: Vec<ExportRow> = deals.iter().map(Into::into).collect();
+    serde_json::to_vec_pretty(&rows)
+        .map_err(|e| Error::AppErr(format!("Failed to write json: {e}")))
+}
+
+pub fn render(deals: Vec<DealData>, format: ExportFormat, layout: &Layout) -> Result<Vec<u8>> {
+    match format {
+        ExportFormat::Xlsx => {
+            Xlsx::create(deals.into_iter().map(Into::into).collect(), None, layout)
+        }
+        ExportFormat::Csv => to_csv(&deals),
+        ExportFormat::Json => to_json(&deals),
+    }
+}
+
+/// File name and content, `None` when nothing matches the filter
+pub async fn export(filter: &ExportFilter) -> Result<Option<(String, Vec<u8>)>> {
+    let db = Db::new().await;
+    let today = clock::today();
+    let deals: Vec<DealData> = db
+        .all_deals()
+        .await?
+        .into_iter()
+        .filter(|d| filter.matches(d, today))
+        .collect();
+    if deals.is_empty() {
+        return Ok(None);
+    }
+    let name = format!(
+        "export_{}.{}",
+        today.format("%Y%m%d"),
+        filter.format.extension()
+    );
+    let layout = layouts().get(&filter.layout, filter.default_layout())?;
+    Ok(Some((name, render(deals, filter.format, layout)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::date;
+    use calamine::{Data, Reader};
+
+    fn deal(house: &str, created: NaiveDate, transferred: Option<NaiveDate>) -> DealData {
+        DealData::builder()
+            .id(0, 7)
+            .house(house)
+            .created(created)
+            .transferred(transferred)
+            .build()
+    }
+
+    #[test]
+    fn test_parse() {
+        let filter = ExportFilter::parse(
+            "проект=ЖК Формат; дом=Дом 1; статус=просрочен; с=01.10.2025; формат=csv",
+        )
+        .unwrap();
+        assert_eq!(filter.project.as_deref(), Some("ЖК Формат"));
+        assert_eq!(filter.house.as_deref(), Some("Дом 1"));
+        assert_eq!(filter.status, Some(ExportStatus::Overdue));
+        assert_eq!(filter.from, Some(date(2025, 10, 1)));
+        assert_eq!(filter.format, ExportFormat::Csv);
+        assert_eq!(ExportFilter::parse("all").unwrap(), ExportFilter::default());
+        assert!(ExportFilter::parse("цвет=синий").is_err());
+        assert!(ExportFilter::parse("с=2025-10-01").is_err());
+        assert_eq!(
+            ExportFilter::parse("макет=Default").unwrap().layout,
+            "Default"
+        );
+        assert!(ExportFilter::parse("макет=для бухгалтерии").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let today = date(2025, 11, 5);
+        let overdue = deal("Дом 1", date(2025, 9, 1), None);
+        let in_work = deal("Дом 2", date(2025, 10, 20), None);
+        let transferred = deal("Дом 1", date(2025, 9, 1), Some(date(2025, 10, 15)));
+
+        let filter = ExportFilter::parse("статус=просрочен").unwrap();
+        assert!(filter.matches(&overdue, today));
+        assert!(!filter.matches(&in_work, today));
+        assert!(!filter.matches(&transferred, today));
+
+        let filter = ExportFilter::parse("статус=в работе; дом=дом 2").unwrap();
+        assert!(filter.matches(&in_work, today));
+        assert!(!filter.matches(&overdue, today));
+
+        // transferred deals are filtered by the transfer date
+        let filter = ExportFilter::parse("статус=передан; с=01.10.2025; по=31.10.2025").unwrap();
+        assert!(filter.matches(&transferred, today));
+        let filter = ExportFilter::parse("с=01.10.2025").unwrap();
+        assert!(!filter.matches(&transferred, today));
+        assert!(filter.matches(&in_work, today));
+    }
+
+    #[test]
+    fn test_render() {
+        let layout = layouts().get("", DEFAULT_LAYOUT).unwrap();
+        let deals = vec![deal("Дом 1", date(2025, 9, 1), Some(date(2025, 10, 15)))];
+        let csv =
+            String::from_utf8(render(deals.clone(), ExportFormat::Csv, layout).unwrap()).unwrap();
+        assert!(csv.contains("Проект;Дом;Тип объекта"));
+        assert!(csv.contains("ЖК Формат;Дом 1;Квартира;1;;01.09.2025;01.10.2025;да;15.10.2025"));
+
+        let json =
+            String::from_utf8(render(deals.clone(), ExportFormat::Json, layout).unwrap()).unwrap();
+        assert!(json.contains("\"reg_date\": \"2025-09-01\""));
+        assert!(json.contains("\"transferred_on\": \"2025-10-15\""));
+
+        let xlsx = render(deals.clone(), ExportFormat::Xlsx, layout).unwrap();
+        assert!(xlsx.starts_with(b"PK"));
+
+        let filter = ExportFilter::parse("статус=передан").unwrap();
+        let layout = layouts()
+            .get(&filter.layout, filter.default_layout())
+            .unwrap();
+        let xlsx = render(deals, ExportFormat::Xlsx, layout).unwrap();
+        let mut workbook: calamine::Xlsx<_> =
+            calamine::open_workbook_from_rs(std::io::Cursor::new(xlsx)).unwrap();
+        let sheet = workbook.worksheet_range("Все объекты").unwrap();
+        assert_eq!(
+            sheet.get((0, 7)),
+            Some(&Data::String("Дата передачи".to_string()))
+        );
+        let summary = workbook.worksheet_range("Сводка").unwrap();
+        // Проект, Квартира, Всего, Просрочено
+        assert_eq!(summary.get((1, 3)), Some(&Data::Float(0.0)));
+    }
+}
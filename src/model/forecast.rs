@@ -0,0 +1,190 @@
+use crate::Result;
+use crate::clock;
+use crate::config::config;
+use crate::model::Db;
+use crate::model::deal::DealData;
+use chrono::{Datelike, Days, NaiveDate};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+const WEEKDAYS: [&str; 7] = ["Пн", "Вт", "Ср", "Чт", "Пт", "Сб", "Вс"];
+const CALLBACK_PREFIX: &str = "cal:";
+
+/// Handovers due on one day, per (project, house)
+#[derive(Debug, Clone)]
+pub struct ForecastDay {
+    pub date: NaiveDate,
+    pub houses: BTreeMap<(String, String), usize>,
+}
+
+impl ForecastDay {
+    pub fn total(&self) -> usize {
+        self.houses.values().sum()
+    }
+}
+
+/// Undone deals bucketed by deadline date, starting from Monday of the current week
+#[derive(Debug, Clone)]
+pub struct Forecast {
+    pub days: Vec<ForecastDay>,
+    /// Deadline already passed, not shown by day
+    pub overdue: usize,
+    /// Handovers per day the staff can manage, 0 - unlimited
+    pub capacity: usize,
+}
+
+impl Forecast {
+    pub fn build(deals: &[DealData], today: NaiveDate, weeks: u32, capacity: usize) -> Forecast {
+        let start = today - Days::new(today.weekday().num_days_from_monday() as u64);
+        let mut days: Vec<ForecastDay> = start
+            .iter_days()
+            .take(weeks as usize * 7)
+            .map(|date| ForecastDay {
+                date,
+                houses: BTreeMap::new(),
+            })
+            .collect();
+
+        let mut overdue = 0;
+        for deal in deals {
+            let exp_date = deal.exp_date();
+            if exp_date < today {
+                overdue += 1;
+                continue;
+            }
+            if let Some(day) = days.iter_mut().find(|d| d.date == exp_date) {
+                *day.houses
+                    .entry((deal.project.clone(), deal.house.clone()))
+                    .or_default() += 1;
+            }
+        }
+
+        Forecast {
+            days,
+            overdue,
+            capacity,
+        }
+    }
+
+    pub fn is_overloaded(&self, day: &ForecastDay) -> bool {
+        self.capacity > 0 && day.total() > self.capacity
+    }
+
+    pub fn weeks(&self) -> usize {
+        self.days.len() / 7
+    }
+
+    /// Every (project, house) with at least one handover in the forecast
+    pub fn houses(&self) -> Vec<(String, String)> {
+        let houses: BTreeSet<&(String, String)> =
+            self.days.iter().flat_map(|d| d.houses.keys()).collect();
+        houses.into_iter().cloned().collect()
+    }
+
+    pub fn week_text(&self, week: usize) -> String {
+        let days = self.days.chunks(7).nth(week).unwrap_or_default();
+        let (Some(first), Some(last)) = (days.first(), days.last()) else {
+            return "Нет данных для прогноза".to_string();
+        };
+
+        let mut text = format!(
+            "Прогноз передач {} – {}\n",
+            first.date.format("%d.%m.%Y"),
+            last.date.format("%d.%m.%Y")
+        );
+        if week == 0 && self.overdue > 0 {
+            let _ = writeln!(text, "Просрочено: {}", self.overdue);
+        }
+        for day in days {
+            let weekday = WEEKDAYS[day.date.weekday().num_days_from_monday() as usize];
+            let mark = if self.is_overloaded(day) {
+                "⚠️ "
+            } else {
+                ""
+            };
+            let _ = write!(
+                text,
+                "\n{mark}{weekday} {}: {}",
+                day.date.format("%d.%m"),
+                day.total()
+            );
+            if self.is_overloaded(day) {
+                let _ = write!(text, " (норма {})", self.capacity);
+            }
+            for ((project, house), count) in &day.houses {
+                let _ = write!(text, "\n    {project}, {house}: {count}");
+            }
+        }
+        text
+    }
+}
+
+/// Week navigation buttons carry `cal:<week>`
+pub fn callback_data(week: usize) -> String {
+    format!("{CALLBACK_PREFIX}{week}")
+}
+
+pub fn parse_callback(data: &str) -> Option<usize> {
+    data.strip_prefix(CALLBACK_PREFIX)?.parse().ok()
+}
+
+pub async fn get_forecast() -> Result<Forecast> {
+    let db = Db::new().await;
+    let deals = db.get_all_undone_deals().await?;
+    Ok(Forecast::build(
+        &deals,
+        clock::today(),
+        config().FORECAST_WEEKS,
+        config().HANDOVER_CAPACITY,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::date;
+
+    fn deal(house: &str, created: NaiveDate, days_limit: i32) -> DealData {
+        DealData::builder()
+            .house(house)
+            .created(created)
+            .days_limit(days_limit)
+            .build()
+    }
+
+    #[test]
+    fn test_forecast() {
+        // Wednesday
+        let today = date(2025, 11, 5);
+        let created = date(2025, 10, 1);
+        let deals = vec![
+            deal("Дом 1", created, 36),
+            deal("Дом 1", created, 36),
+            deal("Дом 2", created, 36),
+            deal("Дом 2", created, 44),
+            deal("Дом 2", created, 30),
+        ];
+        let forecast = Forecast::build(&deals, today, 2, 2);
+
+        assert_eq!(forecast.weeks(), 2);
+        assert_eq!(forecast.days[0].date.weekday(), chrono::Weekday::Mon);
+        assert_eq!(forecast.overdue, 1);
+        // 06.11 - Thursday of the first week
+        let thursday = &forecast.days[3];
+        assert_eq!(thursday.total(), 3);
+        assert!(forecast.is_overloaded(thursday));
+        assert_eq!(forecast.days[7 + 4].total(), 1);
+        assert_eq!(forecast.houses().len(), 2);
+
+        let text = forecast.week_text(0);
+        assert!(text.contains("⚠️ Чт 06.11: 3 (норма 2)"));
+        assert!(text.contains("Просрочено: 1"));
+        assert!(!forecast.week_text(1).contains("Просрочено"));
+    }
+
+    #[test]
+    fn test_callback() {
+        assert_eq!(parse_callback(&callback_data(3)), Some(3));
+        assert_eq!(parse_callback("ack:3"), None);
+    }
+}
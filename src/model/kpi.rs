@@ -0,0 +1,264 @@
+use crate::Result;
+use crate::clock::{self, to_local};
+use crate::model::Db;
+use crate::model::archive::ARCHIVE_COLUMNS;
+use crate::model::deal::DealData;
+use chrono::{Datelike, NaiveDate};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// Months of transfer history shown in reports
+const KPI_MONTHS: usize = 12;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KpiStats {
+    pub label: String,
+    pub transferred: usize,
+    pub median_days: Option<f64>,
+    pub p90_days: Option<i64>,
+    pub on_time_pct: Option<f64>,
+    /// Undone deals past their deadline
+    pub overdue: usize,
+    pub avg_overdue_days: Option<f64>,
+}
+
+impl Display for KpiStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: передано {}", self.label, self.transferred)?;
+        if let (Some(median), Some(p90)) = (self.median_days, self.p90_days) {
+            write!(f, ", медиана {median:.0} дн., p90 {p90} дн.")?;
+        }
+        if let Some(pct) = self.on_time_pct {
+            write!(f, ", в срок {pct:.0}%")?;
+        }
+        if self.overdue > 0 {
+            write!(f, ", просрочено {}", self.overdue)?;
+            if let Some(avg) = self.avg_overdue_days {
+                write!(f, " (в среднем {avg:.1} дн.)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Kpi {
+    pub total: KpiStats,
+    pub by_project: Vec<KpiStats>,
+    pub by_type: Vec<KpiStats>,
+    /// By month of transfer, overdue numbers are not split by month
+    pub by_month: Vec<KpiStats>,
+}
+
+impl Kpi {
+    /// `transferred` are deals with `transferred_on`, `undone` are deals still in work
+    pub fn compute(transferred: &[DealData], undone: &[DealData], today: NaiveDate) -> Kpi {
+        let overdue: Vec<(&DealData, i64)> = undone
+            .iter()
+            .filter_map(|d| {
+                let days = (today - d.exp_date()).num_days();
+                (days > 0).then_some((d, days))
+            })
+            .collect();
+
+        let mut by_month = group(
+            transferred,
+            &[],
+            |d| {
+                d.transferred_on
+                    .map(|t| to_local(&t).date())
+                    .map(|t| (t.year(), t.month()))
+            },
+            |(year, month)| format!("{month:02}.{year}"),
+        );
+        by_month.drain(..by_month.len().saturating_sub(KPI_MONTHS));
+
+        Kpi {
+            total: stats("Всего", transferred.iter().collect(), &overdue),
+            by_project: group(
+                transferred,
+                &overdue,
+                |d| Some(d.project.clone()),
+                |p| p.clone(),
+            ),
+            by_type: group(
+                transferred,
+                &overdue,
+                |d| Some(d.property_type.clone()),
+                |t| t.clone(),
+            ),
+            by_month,
+        }
+    }
+}
+
+impl Display for Kpi {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.total)?;
+        for (title, rows) in [
+            ("По проектам", &self.by_project),
+            ("По типам объектов", &self.by_type),
+            ("По месяцам передачи", &self.by_month),
+        ] {
+            if rows.is_empty() {
+                continue;
+            }
+            writeln!(f, "\n{title}")?;
+            for row in rows {
+                writeln!(f, "{row}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transferred deals and overdue deals with their days past the deadline
+type Group<'a> = (Vec<&'a DealData>, Vec<(&'a DealData, i64)>);
+
+fn group<K: Ord>(
+    transferred: &[DealData],
+    overdue: &[(&DealData, i64)],
+    key: impl Fn(&DealData) -> Option<K>,
+    label: impl Fn(&K) -> String,
+) -> Vec<KpiStats> {
+    let mut groups: BTreeMap<K, Group> = BTreeMap::new();
+    for d in transferred {
+        if let Some(k) = key(d) {
+            groups.entry(k).or_default().0.push(d);
+        }
+    }
+    for (d, days) in overdue {
+        if let Some(k) = key(d) {
+            groups.entry(k).or_default().1.push((d, *days));
+        }
+    }
+    groups
+        .iter()
+        .map(|(k, (done, late))| stats(&label(k), done.clone(), late))
+        .collect()
+}
+
+fn stats(label: &str, transferred: Vec<&DealData>, overdue: &[(&DealData, i64)]) -> KpiStats {
+    let mut durations: Vec<i64> = transferred
+        .iter()
+        .filter_map(|d| transfer_days(d))
+        .collect();
+    durations.sort_unstable();
+    let on_time = transferred.iter().filter(|d| on_time(d)).count();
+    let overdue_days: i64 = overdue.iter().map(|(_, days)| days).sum();
+
+    KpiStats {
+        label: label.to_string(),
+        transferred: transferred.len(),
+        median_days: median(&durations),
+        p90_days: percentile(&durations, 0.9),
+        on_time_pct: (!transferred.is_empty())
+            .then(|| on_time as f64 * 100.0 / transferred.len() as f64),
+        overdue: overdue.len(),
+        avg_overdue_days: (!overdue.is_empty()).then(|| overdue_days as f64 / overdue.len() as f64),
+    }
+}
+
+/// Days from the sale to the detected transfer, both in the business timezone
+fn transfer_days(d: &DealData) -> Option<i64> {
+    let transferred = to_local(&d.transferred_on?).date();
+    Some((transferred - to_local(&d.created_on).date()).num_days())
+}
+
+fn on_time(d: &DealData) -> bool {
+    d.transferred_on
+        .is_some_and(|t| to_local(&t).date() <= d.exp_date())
+}
+
+fn median(sorted: &[i64]) -> Option<f64> {
+    let n = sorted.len();
+    match n {
+        0 => None,
+        _ if n % 2 == 1 => Some(sorted[n / 2] as f64),
+        _ => Some((sorted[n / 2 - 1] + sorted[n / 2]) as f64 / 2.0),
+    }
+}
+
+/// Nearest-rank percentile
+fn percentile(sorted: &[i64], p: f64) -> Option<i64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len()) - 1).copied()
+}
+
+impl Db {
+    /// Transferred deals with a known transfer moment, archived ones included
+    pub async fn transferred_deals(&self) -> Result<Vec<DealData>> {
+        let records = sqlx::query_as(&format!(
+            r#"
+            SELECT {ARCHIVE_COLUMNS} FROM deal
+                     WHERE transfer_completed = true AND transferred_on IS NOT NULL
+            UNION ALL
+            SELECT {ARCHIVE_COLUMNS} FROM deal_archive
+                     WHERE transfer_completed = true AND transferred_on IS NOT NULL"#
+        ))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(records)
+    }
+}
+
+pub async fn get_kpi() -> Result<Kpi> {
+    let db = Db::new().await;
+    let transferred = db.transferred_deals().await?;
+    let undone = db.get_all_undone_deals().await?;
+    Ok(Kpi::compute(&transferred, &undone, clock::today()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::date;
+
+    fn deal(project: &str, created: NaiveDate, transferred: Option<NaiveDate>) -> DealData {
+        DealData::builder()
+            .project(project)
+            .created(created)
+            .transferred(transferred)
+            .build()
+    }
+
+    #[test]
+    fn test_percentiles() {
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[10, 20, 40]), Some(20.0));
+        assert_eq!(median(&[10, 20, 30, 40]), Some(25.0));
+        let days: Vec<i64> = (1..=10).collect();
+        assert_eq!(percentile(&days, 0.9), Some(9));
+        assert_eq!(percentile(&[5], 0.9), Some(5));
+    }
+
+    #[test]
+    fn test_compute() {
+        let created = date(2025, 9, 1);
+        let transferred = vec![
+            deal("DNS Сити", created, Some(date(2025, 9, 21))),
+            deal("DNS Сити", created, Some(date(2025, 10, 11))),
+            deal("ЖК Формат", created, Some(date(2025, 9, 30))),
+        ];
+        let undone = vec![
+            deal("ЖК Формат", created, None),
+            deal("ЖК Формат", date(2025, 10, 20), None),
+        ];
+        let kpi = Kpi::compute(&transferred, &undone, date(2025, 10, 5));
+
+        assert_eq!(kpi.total.transferred, 3);
+        assert_eq!(kpi.total.median_days, Some(29.0));
+        assert_eq!(kpi.total.p90_days, Some(40));
+        // 40 days of the DNS deal exceed its 30 days limit
+        assert_eq!(kpi.by_project[0].on_time_pct, Some(50.0));
+        assert_eq!(kpi.by_project[1].overdue, 1);
+        assert_eq!(kpi.by_project[1].avg_overdue_days, Some(4.0));
+        assert_eq!(kpi.by_month.len(), 2);
+        assert_eq!(kpi.by_month[0].label, "09.2025");
+        assert_eq!(kpi.by_month[0].transferred, 2);
+        assert!(kpi.to_string().contains("По месяцам передачи"));
+    }
+}
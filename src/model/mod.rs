@@ -1,13 +1,29 @@
 use crate::Result;
+use crate::clock;
 use crate::config::config;
+use chrono::NaiveDateTime;
+use chrono_tz::Tz;
 use log::info;
 use sqlx::migrate::MigrateDatabase;
 use sqlx::sqlite::SqlitePoolOptions;
-use sqlx::{Sqlite, SqlitePool};
+use sqlx::{Row, Sqlite, SqliteConnection, SqlitePool};
 
+pub mod act;
+pub mod alert;
+pub mod archive;
 pub mod deadline;
 pub mod deal;
+pub mod digest;
+pub mod export;
+pub mod forecast;
+pub mod kpi;
+pub mod notification;
+pub mod outbox;
+pub mod report;
+pub mod rules;
+pub mod snapshot;
 pub mod stat;
+pub mod subscription;
 pub mod sync;
 
 pub struct Db {
@@ -30,8 +46,200 @@ impl Db {
     }
 }
 
-async fn create_schema(db_url: &str) -> Result<()> {
-    let pool = SqlitePool::connect(db_url).await?;
+enum Migration {
+    Sql(&'static str),
+    /// Timestamp columns of a table converted from `TIMEZONE` to UTC,
+    /// SQLite itself only knows the zone of the server
+    ToUtc(&'static str, &'static [&'static str]),
+}
+
+use Migration::{Sql, ToUtc};
+
+/// Schema changes applied on top of the initial `deal` table.
+/// The index of the last applied entry is kept in `PRAGMA user_version`,
+/// so entries must only ever be appended.
+const MIGRATIONS: &[Migration] = &[
+    // deals imported from files are not tracked in the AmoCRM funnel
+    Sql("ALTER TABLE deal ADD COLUMN source TEXT NOT NULL DEFAULT 'amo'"),
+    // moment the deal left the funnel, used by the retention policy
+    Sql("ALTER TABLE deal ADD COLUMN transferred_on DATETIME"),
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS deal_archive
+    (
+        id                  INTEGER PRIMARY KEY,
+        deal_id             BIGINTEGER          NOT NULL,
+        project             TEXT                NOT NULL,
+        house               TEXT                NOT NULL,
+        property_type       TEXT                NOT NULL,
+        property_num        INTEGER             NOT NULL,
+        facing              TEXT,
+        days_limit          INTEGER,
+        transfer_completed  BOOLEAN,
+        created_on          DATETIME,
+        updated_on          DATETIME,
+        source              TEXT                NOT NULL,
+        transferred_on      DATETIME,
+        archived_on         DATETIME DEFAULT    (datetime('now', 'localtime'))
+    )
+    "#),
+    // AmoCRM user responsible for the deal
+    Sql("ALTER TABLE deal ADD COLUMN responsible_id BIGINTEGER NOT NULL DEFAULT 0"),
+    Sql("ALTER TABLE deal_archive ADD COLUMN responsible_id BIGINTEGER NOT NULL DEFAULT 0"),
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS notification_log
+    (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        deal                INTEGER             NOT NULL,
+        tier                TEXT                NOT NULL,
+        channel             TEXT                NOT NULL,
+        notified_on         DATETIME DEFAULT    (datetime('now', 'localtime'))
+    )
+    "#),
+    Sql("CREATE INDEX IF NOT EXISTS notification_log_deal ON notification_log (deal, channel)"),
+    // deadline alerts handled from telegram
+    Sql("ALTER TABLE deal ADD COLUMN snoozed_until DATETIME"),
+    Sql("ALTER TABLE deal ADD COLUMN agreed_deadline DATE"),
+    Sql("ALTER TABLE deal_archive ADD COLUMN snoozed_until DATETIME"),
+    Sql("ALTER TABLE deal_archive ADD COLUMN agreed_deadline DATE"),
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS alert_action
+    (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        deal                INTEGER             NOT NULL,
+        action              TEXT                NOT NULL,
+        user_id             BIGINTEGER          NOT NULL,
+        user_name           TEXT                NOT NULL,
+        reason              TEXT                NOT NULL,
+        snoozed_until       DATETIME,
+        agreed_deadline     DATE,
+        created_on          DATETIME DEFAULT    (datetime('now', 'localtime'))
+    )
+    "#),
+    // default transfer period when AmoCRM has no "Период передачи (дней)", NULL matches any value
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS days_limit_rule
+    (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        position            INTEGER             NOT NULL,
+        project             TEXT,
+        property_type       TEXT,
+        facing              TEXT,
+        days_limit          INTEGER             NOT NULL
+    )
+    "#),
+    Sql(r#"
+    INSERT INTO days_limit_rule (position, project, property_type, facing, days_limit)
+    VALUES (10, 'DNS Сити', NULL, NULL, 60),
+           (100, NULL, NULL, NULL, 30)
+    "#),
+    // the limit was set by the rules, not by AmoCRM, so rule changes recalculate it
+    Sql("ALTER TABLE deal ADD COLUMN days_limit_default BOOLEAN NOT NULL DEFAULT FALSE"),
+    Sql("ALTER TABLE deal_archive ADD COLUMN days_limit_default BOOLEAN NOT NULL DEFAULT FALSE"),
+    // a guess that also took explicit limits of 60 and 30 for defaulted, undone below
+    Sql(r#"
+    UPDATE deal SET days_limit_default = true
+     WHERE source = 'amo'
+       AND days_limit = CASE WHEN project = 'DNS Сити' THEN 60 ELSE 30 END
+    "#),
+    // timestamps were written in the local time, they are kept in UTC from now on
+    ToUtc(
+        "deal",
+        &[
+            "created_on",
+            "updated_on",
+            "transferred_on",
+            "snoozed_until",
+        ],
+    ),
+    ToUtc(
+        "deal_archive",
+        &[
+            "created_on",
+            "updated_on",
+            "transferred_on",
+            "snoozed_until",
+            "archived_on",
+        ],
+    ),
+    ToUtc("notification_log", &["notified_on"]),
+    ToUtc("alert_action", &["created_on", "snoozed_until"]),
+    // funnel movements counted by the daily snapshot
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS deal_event
+    (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        deal_id             BIGINTEGER          NOT NULL,
+        project             TEXT                NOT NULL,
+        property_type       TEXT                NOT NULL,
+        event               TEXT                NOT NULL,
+        created_on          DATETIME            NOT NULL
+    )
+    "#),
+    Sql("CREATE INDEX IF NOT EXISTS deal_event_created_on ON deal_event (created_on)"),
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS stat_snapshot
+    (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        snapshot_date       DATE                NOT NULL,
+        project             TEXT                NOT NULL,
+        property_type       TEXT                NOT NULL,
+        in_work             INTEGER             NOT NULL,
+        new                 INTEGER             NOT NULL,
+        transferred         INTEGER             NOT NULL,
+        returned            INTEGER             NOT NULL,
+        overdue             INTEGER             NOT NULL,
+        taken_on            DATETIME            NOT NULL,
+        UNIQUE (snapshot_date, project, property_type)
+    )
+    "#),
+    // emails are delivered from here, so an SMTP outage does not lose them
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS outbox
+    (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        subject             TEXT                NOT NULL,
+        mail_from           TEXT                NOT NULL,
+        rcpt_to             TEXT                NOT NULL,
+        body                BLOB                NOT NULL,
+        status              TEXT                NOT NULL,
+        attempts            INTEGER             NOT NULL DEFAULT 0,
+        next_attempt_on     DATETIME            NOT NULL,
+        last_error          TEXT,
+        created_on          DATETIME            NOT NULL,
+        sent_on             DATETIME
+    )
+    "#),
+    Sql("CREATE INDEX IF NOT EXISTS outbox_status ON outbox (status, next_attempt_on)"),
+    // who gets which emails, RECEIVERS is used while the table is empty
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS subscription
+    (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        email               TEXT                NOT NULL UNIQUE,
+        name                TEXT                NOT NULL,
+        kinds               TEXT                NOT NULL,
+        projects            TEXT                NOT NULL DEFAULT '',
+        created_on          DATETIME            NOT NULL
+    )
+    "#),
+    // end of the period each daily digest covered, the next one starts there
+    Sql(r#"
+    CREATE TABLE IF NOT EXISTS digest_log
+    (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        period_end          DATETIME            NOT NULL,
+        emails              INTEGER             NOT NULL
+    )
+    "#),
+    // main contact of the AmoCRM lead, printed on the transfer act
+    Sql("ALTER TABLE deal ADD COLUMN buyer TEXT NOT NULL DEFAULT ''"),
+    Sql("ALTER TABLE deal_archive ADD COLUMN buyer TEXT NOT NULL DEFAULT ''"),
+    // nothing stored tells a defaulted limit from an explicit one, the next sync sets
+    // the flag from AmoCRM for the deals still in the funnel
+    Sql("UPDATE deal SET days_limit_default = false"),
+];
+
+async fn create_schema(pool: &SqlitePool) -> Result<()> {
     let qry = r#"
     CREATE TABLE IF NOT EXISTS deal
     (
@@ -44,12 +252,51 @@ async fn create_schema(db_url: &str) -> Result<()> {
         facing              TEXT,
         days_limit          INTEGER  DEFAULT    30,
         transfer_completed  BOOLEAN DEFAULT FALSE,
-        created_on          DATETIME DEFAULT    (datetime('now', 'localtime')),
-        updated_on          DATETIME DEFAULT    (datetime('now', 'localtime'))
+        created_on          DATETIME DEFAULT    (datetime('now')),
+        updated_on          DATETIME DEFAULT    (datetime('now'))
     );
     "#;
-    let _ = sqlx::query(qry).execute(&pool).await?;
-    pool.close().await;
+    let _ = sqlx::query(qry).execute(pool).await?;
+    Ok(())
+}
+
+async fn migrate(pool: &SqlitePool) -> Result<()> {
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+        .fetch_one(pool)
+        .await?;
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Applying migration #{}", idx + 1);
+        // the step and its version land together, a crash between them can't repeat the step
+        let mut tx = pool.begin().await?;
+        match migration {
+            Sql(qry) => {
+                sqlx::query(qry).execute(&mut *tx).await?;
+            }
+            ToUtc(table, columns) => to_utc(&mut tx, &clock::tz(), table, columns).await?,
+        }
+        sqlx::query(&format!("PRAGMA user_version = {}", idx + 1))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+async fn to_utc(conn: &mut SqliteConnection, tz: &Tz, table: &str, columns: &[&str]) -> Result<()> {
+    let rows = sqlx::query(&format!("SELECT id, {} FROM {table}", columns.join(", ")))
+        .fetch_all(&mut *conn)
+        .await?;
+    let set: Vec<String> = columns.iter().map(|col| format!("{col} = ?")).collect();
+    let update = format!("UPDATE {table} SET {} WHERE id = ?", set.join(", "));
+    for row in rows {
+        let mut qry = sqlx::query(&update);
+        for idx in 1..=columns.len() {
+            let local: Option<NaiveDateTime> = row.try_get(idx)?;
+            qry = qry.bind(local.map(|local| clock::from_local_in(tz, &local)));
+        }
+        let id: i64 = row.try_get(0)?;
+        qry.bind(id).execute(&mut *conn).await?;
+    }
     Ok(())
 }
 
@@ -71,13 +318,59 @@ pub async fn init_db() -> Result<()> {
     {
         info!("Initializing DB...");
         Sqlite::create_database(&config().DB_URL).await?;
-        match create_schema(&config().DB_URL).await {
-            Ok(_) => log::info!("database created successfully"),
-            Err(e) => panic!("{}", e),
-        }
     }
+    let pool = SqlitePool::connect(&config().DB_URL).await?;
+    match create_schema(&pool).await {
+        Ok(_) => log::info!("database schema is ready"),
+        Err(e) => panic!("{}", e),
+    }
+    migrate(&pool).await?;
+    pool.close().await;
     // info!("clean deals");
     // clean_deals(&config().DB_URL).await?;
     // info!("clean deals successfully");
     Ok(())
 }
+
+#[cfg(test)]
+pub async fn test_db() -> Db {
+    // a single connection keeps the in-memory database alive for the whole test
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("in-memory db");
+    create_schema(&db).await.expect("schema");
+    migrate(&db).await.expect("migrations");
+    Db { db }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_to_utc() {
+        let db = test_db().await;
+        sqlx::query("INSERT INTO notification_log (deal, tier, channel, notified_on) VALUES (1, '4', 'email', '2025-11-05 12:00:00'), (2, '4', 'email', NULL)")
+            .execute(&db.db)
+            .await
+            .unwrap();
+        let mut conn = db.db.acquire().await.unwrap();
+        to_utc(
+            &mut conn,
+            &chrono_tz::Europe::Moscow,
+            "notification_log",
+            &["notified_on"],
+        )
+        .await
+        .unwrap();
+        let rows: Vec<(Option<NaiveDateTime>,)> =
+            sqlx::query_as("SELECT notified_on FROM notification_log ORDER BY deal")
+                .fetch_all(&mut *conn)
+                .await
+                .unwrap();
+        let utc = clock::date(2025, 11, 5).and_hms_opt(9, 0, 0);
+        assert_eq!(rows, vec![(utc,), (None,)]);
+    }
+}
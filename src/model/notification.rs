@@ -0,0 +1,116 @@
+use crate::Result;
+use crate::model::Db;
+use chrono::{Duration, NaiveDateTime};
+use log::debug;
+use sqlx::FromRow;
+use std::collections::HashMap;
+
+#[derive(FromRow, Debug, Clone)]
+pub struct Notification {
+    pub deal: i32,
+    pub tier: String,
+    pub notified_on: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// The deal reached a tier it was not announced in yet
+    New,
+    /// Same tier, but the re-notify interval has passed
+    Pending,
+}
+
+/// Decides whether a deal should be announced again, `None` means keep silent
+pub fn freshness(
+    last: Option<&Notification>,
+    tier: &str,
+    now: NaiveDateTime,
+    renotify: Duration,
+) -> Option<Freshness> {
+    match last {
+        None => Some(Freshness::New),
+        Some(n) if n.tier != tier => Some(Freshness::New),
+        Some(n) if now - n.notified_on >= renotify => Some(Freshness::Pending),
+        Some(_) => None,
+    }
+}
+
+impl Db {
+    /// Latest notification of every deal sent through the channel
+    pub async fn last_notifications(&self, channel: &str) -> Result<HashMap<i32, Notification>> {
+        let records: Vec<Notification> = sqlx::query_as(
+            r#"
+            SELECT deal, tier, notified_on
+              FROM notification_log
+             WHERE id IN (SELECT MAX(id) FROM notification_log WHERE channel = $1 GROUP BY deal)"#,
+        )
+        .bind(channel)
+        .fetch_all(&self.db)
+        .await?;
+        debug!("[last_notifications] {channel}: {}", records.len());
+        Ok(records.into_iter().map(|n| (n.deal, n)).collect())
+    }
+
+    pub async fn log_notifications(&self, channel: &str, sent: &[(i32, String)]) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        for (deal, tier) in sent {
+            sqlx::query("INSERT INTO notification_log (deal, tier, channel, notified_on) VALUES ($1, $2, $3, datetime('now'))")
+                .bind(deal)
+                .bind(tier)
+                .bind(channel)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+    use crate::model::test_db;
+
+    #[test]
+    fn test_freshness() {
+        let now = clock::now();
+        let last = Notification {
+            deal: 1,
+            tier: "7".to_string(),
+            notified_on: now - Duration::days(1),
+        };
+        let renotify = Duration::days(3);
+        assert_eq!(freshness(None, "7", now, renotify), Some(Freshness::New));
+        assert_eq!(
+            freshness(Some(&last), "3", now, renotify),
+            Some(Freshness::New)
+        );
+        assert_eq!(freshness(Some(&last), "7", now, renotify), None);
+        let later = now + Duration::days(2);
+        assert_eq!(
+            freshness(Some(&last), "7", later, renotify),
+            Some(Freshness::Pending)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notification_log() {
+        let db = test_db().await;
+        db.log_notifications("email", &[(1, "7".to_string()), (2, "overdue".to_string())])
+            .await
+            .unwrap();
+        db.log_notifications("email", &[(1, "3".to_string())])
+            .await
+            .unwrap();
+        db.log_notifications("group", &[(1, "7".to_string())])
+            .await
+            .unwrap();
+
+        let last = db.last_notifications("email").await.unwrap();
+        assert_eq!(last.len(), 2);
+        assert_eq!(last[&1].tier, "3");
+        assert_eq!(last[&2].tier, "overdue");
+        assert_eq!(db.last_notifications("group").await.unwrap()[&1].tier, "7");
+    }
+}
@@ -0,0 +1,335 @@
+use crate::Result;
+use crate::adapters::mailer::OutgoingMail;
+use crate::adapters::mailer::smtp::Smtp;
+use crate::clock::{self, to_local};
+use crate::model::Db;
+use chrono::{Duration, NaiveDateTime};
+use log::{debug, error, info};
+use sqlx::{Executor, FromRow, Sqlite};
+use std::fmt::Write;
+
+pub const PENDING: &str = "pending";
+pub const SENT: &str = "sent";
+pub const FAILED: &str = "failed";
+
+/// Delay before the first retry, doubled after every failure
+const FIRST_RETRY_MINUTES: i64 = 1;
+const MAX_RETRY_MINUTES: i64 = 6 * 60;
+/// Keeps `/outbox` inside a single telegram message
+const MAX_LISTED: usize = 20;
+
+#[allow(dead_code)]
+#[derive(FromRow, Debug, Clone)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub subject: String,
+    pub mail_from: String,
+    /// Comma separated addresses
+    pub rcpt_to: String,
+    pub body: Vec<u8>,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_on: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub created_on: NaiveDateTime,
+    pub sent_on: Option<NaiveDateTime>,
+}
+
+impl OutboxEntry {
+    pub fn mail(&self) -> OutgoingMail {
+        OutgoingMail {
+            subject: self.subject.clone(),
+            mail_from: self.mail_from.clone(),
+            rcpt_to: self.rcpt_to.split(',').map(str::to_string).collect(),
+            body: self.body.clone(),
+        }
+    }
+}
+
+/// Wait after `attempts` failed deliveries
+pub fn backoff(attempts: i64) -> Duration {
+    let minutes = FIRST_RETRY_MINUTES
+        .saturating_mul(1 << (attempts - 1).clamp(0, 20))
+        .min(MAX_RETRY_MINUTES);
+    Duration::minutes(minutes)
+}
+
+/// Insert with any executor, so the email can share a transaction with the change it reports
+pub async fn insert_email<'e, E>(executor: E, mail: &OutgoingMail) -> Result<i64>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let now = clock::now();
+    let (id,): (i64,) = sqlx::query_as(
+        r#"
+        INSERT INTO outbox (subject, mail_from, rcpt_to, body, status, attempts, next_attempt_on, created_on)
+        VALUES ($1, $2, $3, $4, $5, 0, $6, $6) RETURNING id"#,
+    )
+    .bind(&mail.subject)
+    .bind(&mail.mail_from)
+    .bind(mail.rcpt_to.join(","))
+    .bind(&mail.body)
+    .bind(PENDING)
+    .bind(now)
+    .fetch_one(executor)
+    .await?;
+    Ok(id)
+}
+
+impl Db {
+    pub async fn enqueue_email(&self, mail: &OutgoingMail) -> Result<i64> {
+        insert_email(&self.db, mail).await
+    }
+
+    pub async fn due_emails(&self, now: NaiveDateTime) -> Result<Vec<OutboxEntry>> {
+        let records: Vec<OutboxEntry> = sqlx::query_as(
+            "SELECT * FROM outbox WHERE status = $1 AND next_attempt_on <= $2 ORDER BY id",
+        )
+        .bind(PENDING)
+        .bind(now)
+        .fetch_all(&self.db)
+        .await?;
+        debug!("[due_emails] {}", records.len());
+        Ok(records)
+    }
+
+    /// The body is dropped once delivered, attachments make it large
+    pub async fn mark_email_sent(&self, id: i64, now: NaiveDateTime) -> Result<()> {
+        sqlx::query("UPDATE outbox SET status = $2, sent_on = $3, body = X'' WHERE id = $1")
+            .bind(id)
+            .bind(SENT)
+            .bind(now)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// `next_attempt_on` is `None` when no retries are left
+    pub async fn mark_email_failed(
+        &self,
+        id: i64,
+        attempts: i64,
+        error: &str,
+        next_attempt_on: Option<NaiveDateTime>,
+    ) -> Result<()> {
+        let status = if next_attempt_on.is_some() {
+            PENDING
+        } else {
+            FAILED
+        };
+        sqlx::query(
+            r#"
+            UPDATE outbox SET status = $2, attempts = $3, last_error = $4,
+                              next_attempt_on = COALESCE($5, next_attempt_on)
+             WHERE id = $1"#,
+        )
+        .bind(id)
+        .bind(status)
+        .bind(attempts)
+        .bind(error)
+        .bind(next_attempt_on)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Pending and failed emails, bodies left out
+    pub async fn undelivered_emails(&self) -> Result<Vec<OutboxEntry>> {
+        let records = sqlx::query_as(
+            r#"
+            SELECT id, subject, mail_from, rcpt_to, X'' AS body, status, attempts,
+                   next_attempt_on, last_error, created_on, sent_on
+              FROM outbox WHERE status != $1 ORDER BY id"#,
+        )
+        .bind(SENT)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(records)
+    }
+}
+
+/// Delivers the due emails, returns alerts for the admin
+pub async fn process_outbox(
+    db: &Db,
+    smtp: &Smtp,
+    now: NaiveDateTime,
+    max_attempts: i64,
+    alert_after: i64,
+) -> Result<Vec<String>> {
+    let mut alerts = vec![];
+    for entry in db.due_emails(now).await? {
+        match smtp.send(&entry.mail()).await {
+            Ok(()) => {
+                db.mark_email_sent(entry.id, now).await?;
+                info!("Email {} sent", entry.id);
+            }
+            Err(e) => {
+                let attempts = entry.attempts + 1;
+                error!("Email {} attempt {attempts} failed: {e}", entry.id);
+                let next = (attempts < max_attempts).then(|| now + backoff(attempts));
+                db.mark_email_failed(entry.id, attempts, &e.to_string(), next)
+                    .await?;
+                if next.is_none() {
+                    alerts.push(format!(
+                        "Письмо \"{}\" не отправлено, попыток: {attempts}, отправка прекращена. Ошибка: {e}",
+                        entry.subject
+                    ));
+                } else if attempts == alert_after {
+                    alerts.push(format!(
+                        "Письмо \"{}\" не отправляется, попыток: {attempts}. Ошибка: {e}",
+                        entry.subject
+                    ));
+                }
+            }
+        }
+    }
+    Ok(alerts)
+}
+
+pub fn outbox_text(entries: &[OutboxEntry]) -> String {
+    if entries.is_empty() {
+        return "Очередь писем пуста".to_string();
+    }
+    let count = |status: &str| entries.iter().filter(|e| e.status == status).count();
+    let mut text = format!(
+        "Ожидают отправки: {}, не отправлены: {}\n",
+        count(PENDING),
+        count(FAILED)
+    );
+    for e in entries.iter().take(MAX_LISTED) {
+        let _ = write!(
+            text,
+            "\n#{} {} ({})\nсоздано {}, попыток: {}",
+            e.id,
+            e.subject,
+            e.rcpt_to,
+            to_local(&e.created_on).format("%d.%m.%Y %H:%M"),
+            e.attempts
+        );
+        if e.status == PENDING && e.attempts > 0 {
+            let _ = write!(
+                text,
+                ", следующая {}",
+                to_local(&e.next_attempt_on).format("%d.%m.%Y %H:%M")
+            );
+        }
+        if e.status == FAILED {
+            text.push_str(", отправка прекращена");
+        }
+        if let Some(error) = &e.last_error {
+            let _ = write!(text, "\nошибка: {error}");
+        }
+        text.push('\n');
+    }
+    if entries.len() > MAX_LISTED {
+        let _ = write!(text, "\n... и ещё {}", entries.len() - MAX_LISTED);
+    }
+    text
+}
+
+pub async fn get_outbox_text() -> Result<String> {
+    let db = Db::new().await;
+    Ok(outbox_text(&db.undelivered_emails().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::mailer::smtp::SmtpTls;
+    use crate::adapters::mailer::smtp::tests::{fake_server, message, smtp};
+    use crate::model::deal::DealData;
+    use crate::model::test_db;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::minutes(1));
+        assert_eq!(backoff(2), Duration::minutes(2));
+        assert_eq!(backoff(5), Duration::minutes(16));
+        assert_eq!(backoff(100), Duration::hours(6));
+    }
+
+    #[tokio::test]
+    async fn test_retry_and_deliver() {
+        let db = test_db().await;
+        let id = db.enqueue_email(&message()).await.unwrap();
+        let now = clock::now();
+
+        // nothing listens on a released port
+        let port = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let down = smtp(port, SmtpTls::None, "");
+        let alerts = process_outbox(&db, &down, now, 3, 2).await.unwrap();
+        assert!(alerts.is_empty());
+        let entries = db.undelivered_emails().await.unwrap();
+        assert_eq!(entries[0].attempts, 1);
+        assert_eq!(entries[0].next_attempt_on, now + Duration::minutes(1));
+        // not due until the backoff passes
+        assert!(db.due_emails(now).await.unwrap().is_empty());
+
+        let later = now + Duration::minutes(1);
+        let alerts = process_outbox(&db, &down, later, 3, 2).await.unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert!(outbox_text(&db.undelivered_emails().await.unwrap()).contains("попыток: 2"));
+
+        let (port, server) = fake_server().await;
+        let alerts = process_outbox(
+            &db,
+            &smtp(port, SmtpTls::None, ""),
+            later + Duration::hours(1),
+            3,
+            2,
+        )
+        .await
+        .unwrap();
+        assert!(alerts.is_empty());
+        assert!(server.await.unwrap().contains(&"DATA".to_string()));
+        assert!(db.undelivered_emails().await.unwrap().is_empty());
+        let (body,): (Vec<u8>,) = sqlx::query_as("SELECT body FROM outbox WHERE id = $1")
+            .bind(id)
+            .fetch_one(&db.db)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_new_deals_with_email() {
+        let db = test_db().await;
+        let deal = DealData::builder().id(0, 9).property_num(9).build_lead();
+        db.create_new_deals(&[deal], &[message()]).await.unwrap();
+        assert_eq!(db.all_deals().await.unwrap().len(), 1);
+        assert_eq!(db.due_emails(clock::now()).await.unwrap().len(), 1);
+        let (events,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM deal_event WHERE event = 'new'")
+                .fetch_one(&db.db)
+                .await
+                .unwrap();
+        assert_eq!(events, 1);
+    }
+
+    #[tokio::test]
+    async fn test_give_up() {
+        let db = test_db().await;
+        db.enqueue_email(&message()).await.unwrap();
+        let port = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let alerts = process_outbox(&db, &smtp(port, SmtpTls::None, ""), clock::now(), 1, 3)
+            .await
+            .unwrap();
+        assert!(alerts[0].contains("отправка прекращена"));
+        let entries = db.undelivered_emails().await.unwrap();
+        assert_eq!(entries[0].status, FAILED);
+        assert!(
+            db.due_emails(clock::now() + Duration::days(1))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(outbox_text(&entries).contains("не отправлены: 1"));
+        assert_eq!(outbox_text(&[]), "Очередь писем пуста");
+    }
+}
@@ -0,0 +1,374 @@
+use crate::Result;
+use crate::clock::{self, from_local, to_local};
+use crate::config::config;
+use crate::model::Db;
+use crate::model::archive::ARCHIVE_COLUMNS;
+use crate::model::deal::DealData;
+use crate::model::snapshot::DealEvent;
+use crate::model::subscription::{Projects, get_audiences};
+use chrono::{Datelike, Days, Months, NaiveDate, NaiveDateTime, Weekday};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportKind {
+    Weekly,
+    Monthly,
+}
+
+impl ReportKind {
+    pub fn title(&self) -> &'static str {
+        match self {
+            ReportKind::Weekly => "Еженедельный отчёт",
+            ReportKind::Monthly => "Ежемесячный отчёт",
+        }
+    }
+
+    pub fn schedule(&self) -> &'static str {
+        match self {
+            ReportKind::Weekly => &config().WEEKLY_REPORT_SCHEDULE,
+            ReportKind::Monthly => &config().MONTHLY_REPORT_SCHEDULE,
+        }
+    }
+
+    /// Layout name of the attached workbook, empty for the built-in one
+    pub fn layout(&self) -> &'static str {
+        match self {
+            ReportKind::Weekly => &config().WEEKLY_REPORT_LAYOUT,
+            ReportKind::Monthly => &config().MONTHLY_REPORT_LAYOUT,
+        }
+    }
+
+    /// The last complete period before `today` as `[from, to)`
+    pub fn period(
+        &self,
+        today: NaiveDate,
+        week_start: Weekday,
+        month_start_day: u32,
+    ) -> (NaiveDate, NaiveDate) {
+        match self {
+            ReportKind::Weekly => {
+                let back = (today.weekday().num_days_from_monday() + 7
+                    - week_start.num_days_from_monday())
+                    % 7;
+                let to = today - Days::new(back as u64);
+                (to - Days::new(7), to)
+            }
+            ReportKind::Monthly => {
+                let this_month = today
+                    .with_day(month_start_day.clamp(1, 28))
+                    .unwrap_or(today);
+                let to = if this_month <= today {
+                    this_month
+                } else {
+                    this_month - Months::new(1)
+                };
+                (to - Months::new(1), to)
+            }
+        }
+    }
+}
+
+/// Deals of one report category, e.g. transfers completed in the period
+pub struct Category<'a> {
+    pub title: &'static str,
+    pub deals: &'a [DealData],
+}
+
+pub struct ManagementReport {
+    pub kind: ReportKind,
+    pub from: NaiveDate,
+    /// Exclusive
+    pub to: NaiveDate,
+    pub new_sales: Vec<DealData>,
+    pub transferred: Vec<DealData>,
+    /// Transferred in the period after their deadline
+    pub late: Vec<DealData>,
+    pub returned: Vec<DealData>,
+    /// In work at the end of the period
+    pub backlog: Vec<DealData>,
+}
+
+fn local_date(utc: &NaiveDateTime) -> NaiveDate {
+    to_local(utc).date()
+}
+
+impl ManagementReport {
+    /// `deals` are all known deals, archived ones included, `returned` came back
+    /// to the transfer stage during the period
+    pub fn build(
+        kind: ReportKind,
+        from: NaiveDate,
+        to: NaiveDate,
+        deals: &[DealData],
+        returned: Vec<DealData>,
+    ) -> ManagementReport {
+        let in_period = |date: NaiveDate| from <= date && date < to;
+        let transferred_on = |d: &DealData| d.transferred_on.as_ref().map(local_date);
+
+        let new_sales = deals
+            .iter()
+            .filter(|d| in_period(local_date(&d.created_on)))
+            .cloned()
+            .collect();
+        let transferred: Vec<DealData> = deals
+            .iter()
+            .filter(|d| transferred_on(d).is_some_and(in_period))
+            .cloned()
+            .collect();
+        let late = transferred
+            .iter()
+            .filter(|d| transferred_on(d).is_some_and(|t| t > d.exp_date()))
+            .cloned()
+            .collect();
+        // transfers without a known moment are old ones, done before any period
+        let backlog = deals
+            .iter()
+            .filter(|d| local_date(&d.created_on) < to)
+            .filter(|d| !d.transfer_completed || transferred_on(d).is_some_and(|t| t >= to))
+            .cloned()
+            .collect();
+
+        ManagementReport {
+            kind,
+            from,
+            to,
+            new_sales,
+            transferred,
+            late,
+            returned,
+            backlog,
+        }
+    }
+
+    /// Last day of the period, for headers
+    pub fn last_day(&self) -> NaiveDate {
+        self.to.pred_opt().unwrap_or(self.to)
+    }
+
+    pub fn period_text(&self) -> String {
+        format!(
+            "{} – {}",
+            self.from.format("%d.%m.%Y"),
+            self.last_day().format("%d.%m.%Y")
+        )
+    }
+
+    pub fn categories(&self) -> [Category<'_>; 5] {
+        [
+            Category {
+                title: "Новые продажи",
+                deals: &self.new_sales,
+            },
+            Category {
+                title: "Передано",
+                deals: &self.transferred,
+            },
+            Category {
+                title: "Передано с опозданием",
+                deals: &self.late,
+            },
+            Category {
+                title: "Возвраты в воронку",
+                deals: &self.returned,
+            },
+            Category {
+                title: "В работе на конец периода",
+                deals: &self.backlog,
+            },
+        ]
+    }
+
+    /// The same report limited to the covered projects
+    pub fn for_projects(&self, projects: &Projects) -> ManagementReport {
+        let filter = |deals: &[DealData]| projects.filter(deals, |d| &d.project);
+        ManagementReport {
+            kind: self.kind,
+            from: self.from,
+            to: self.to,
+            new_sales: filter(&self.new_sales),
+            transferred: filter(&self.transferred),
+            late: filter(&self.late),
+            returned: filter(&self.returned),
+            backlog: filter(&self.backlog),
+        }
+    }
+
+    /// Every project present in any category
+    pub fn projects(&self) -> Vec<String> {
+        let projects: BTreeSet<&String> = self
+            .categories()
+            .iter()
+            .flat_map(|c| c.deals.iter().map(|d| &d.project))
+            .collect();
+        projects.into_iter().cloned().collect()
+    }
+}
+
+impl Db {
+    /// All deals with their archive
+    pub async fn all_deals(&self) -> Result<Vec<DealData>> {
+        let records = sqlx::query_as(&format!(
+            "SELECT {ARCHIVE_COLUMNS} FROM deal UNION ALL SELECT {ARCHIVE_COLUMNS} FROM deal_archive"
+        ))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(records)
+    }
+
+    /// Deals that came back to the transfer stage in `[from, to)`
+    pub async fn returned_deals(
+        &self,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<DealData>> {
+        self.deals_with_event(DealEvent::Returned, from, to).await
+    }
+
+    /// Deals, archived ones included, with `event` logged in `[from, to)`
+    pub async fn deals_with_event(
+        &self,
+        event: DealEvent,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<DealData>> {
+        let condition = "EXISTS (SELECT 1 FROM deal_event e
+                 WHERE e.project = d.project AND e.deal_id = d.deal_id AND e.event = $3
+                   AND e.created_on >= $1 AND e.created_on < $2)";
+        let records = sqlx::query_as(&format!(
+            r#"
+            SELECT {ARCHIVE_COLUMNS} FROM deal d WHERE {condition}
+            UNION ALL
+            SELECT {ARCHIVE_COLUMNS} FROM deal_archive d WHERE {condition}"#
+        ))
+        .bind(from)
+        .bind(to)
+        .bind(event.as_str())
+        .fetch_all(&self.db)
+        .await?;
+        Ok(records)
+    }
+}
+
+fn midnight(date: NaiveDate) -> NaiveDateTime {
+    from_local(&date.and_hms_opt(0, 0, 0).unwrap_or_default())
+}
+
+pub async fn get_report(kind: ReportKind) -> Result<ManagementReport> {
+    let (from, to) = kind.period(
+        clock::today(),
+        config().REPORT_WEEK_START,
+        config().REPORT_MONTH_START_DAY,
+    );
+    let db = Db::new().await;
+    let deals = db.all_deals().await?;
+    let returned = db.returned_deals(midnight(from), midnight(to)).await?;
+    Ok(ManagementReport::build(kind, from, to, &deals, returned))
+}
+
+pub async fn send_report(kind: ReportKind) -> Result<()> {
+    let report = get_report(kind).await?;
+    let db = Db::new().await;
+    for audience in get_audiences(&db, kind.into()).await? {
+        audience
+            .email
+            .report_notification(&report.for_projects(&audience.projects))
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::mailer::data_types::{DkpReport, MailBody, ReportSummary};
+    use crate::clock::date;
+    use crate::model::test_db;
+
+    fn deal(project: &str, created: NaiveDate, transferred: Option<NaiveDate>) -> DealData {
+        DealData::builder()
+            .project(project)
+            .created(created)
+            .transferred(transferred)
+            .build()
+    }
+
+    #[test]
+    fn test_period() {
+        // Wednesday
+        let today = date(2025, 11, 5);
+        assert_eq!(
+            ReportKind::Weekly.period(today, Weekday::Mon, 1),
+            (date(2025, 10, 27), date(2025, 11, 3))
+        );
+        assert_eq!(
+            ReportKind::Weekly.period(date(2025, 11, 3), Weekday::Mon, 1),
+            (date(2025, 10, 27), date(2025, 11, 3))
+        );
+        assert_eq!(
+            ReportKind::Weekly.period(today, Weekday::Thu, 1),
+            (date(2025, 10, 23), date(2025, 10, 30))
+        );
+        assert_eq!(
+            ReportKind::Monthly.period(today, Weekday::Mon, 1),
+            (date(2025, 10, 1), date(2025, 11, 1))
+        );
+        assert_eq!(
+            ReportKind::Monthly.period(today, Weekday::Mon, 25),
+            (date(2025, 9, 25), date(2025, 10, 25))
+        );
+    }
+
+    #[test]
+    fn test_build() {
+        let (from, to) = (date(2025, 10, 1), date(2025, 11, 1));
+        let deals = vec![
+            // sold and transferred in time within the period
+            deal("DNS Сити", date(2025, 10, 2), Some(date(2025, 10, 20))),
+            // transferred late
+            deal("DNS Сити", date(2025, 9, 1), Some(date(2025, 10, 10))),
+            // transferred after the period, still in work at its end
+            deal("ЖК Формат", date(2025, 9, 20), Some(date(2025, 11, 2))),
+            deal("ЖК Формат", date(2025, 10, 30), None),
+            // sold after the period
+            deal("ЖК Формат", date(2025, 11, 1), None),
+        ];
+        let report = ManagementReport::build(ReportKind::Monthly, from, to, &deals, vec![]);
+
+        assert_eq!(report.new_sales.len(), 2);
+        assert_eq!(report.transferred.len(), 2);
+        assert_eq!(report.late.len(), 1);
+        assert_eq!(report.late[0].created_on, deals[1].created_on);
+        assert_eq!(report.backlog.len(), 2);
+        assert_eq!(report.projects(), vec!["DNS Сити", "ЖК Формат"]);
+        assert_eq!(report.period_text(), "01.10.2025 – 31.10.2025");
+
+        let summary = ReportSummary::from_report(&report);
+        assert_eq!(summary.rows[2].title, "Передано с опозданием");
+        assert_eq!(summary.rows[4].counts, vec![0, 2]);
+        let body = DkpReport::new("header", summary).body().unwrap();
+        assert!(body.html.contains("<th>ЖК Формат</th>"));
+        assert!(
+            body.text
+                .contains("В работе на конец периода: 2 (DNS Сити: 0, ЖК Формат: 2)")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_returned_deals() {
+        let db = test_db().await;
+        let deal = DealData::builder().id(0, 5).property_num(5).build_lead();
+        db.create_deal(&deal).await.unwrap();
+        db.log_deal_event("ЖК Формат", 5, DealEvent::New)
+            .await
+            .unwrap();
+        let today = midnight(clock::today());
+        let tomorrow = today + chrono::Duration::days(1);
+        assert!(db.returned_deals(today, tomorrow).await.unwrap().is_empty());
+
+        db.log_deal_event("ЖК Формат", 5, DealEvent::Returned)
+            .await
+            .unwrap();
+        assert_eq!(db.returned_deals(today, tomorrow).await.unwrap().len(), 1);
+        assert_eq!(db.all_deals().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,252 @@
+use crate::Result;
+use crate::model::Db;
+use crate::model::deal::DealData;
+use log::info;
+use sqlx::{Executor, FromRow, Sqlite};
+use std::fmt::{Display, Formatter};
+
+/// Used when no rule matches, same as the `deal.days_limit` column default
+pub const DEFAULT_DAYS_LIMIT: i32 = 30;
+const ANY: &str = "*";
+
+/// Default transfer period for deals without "Период передачи (дней)" in AmoCRM.
+/// Empty matchers match any value, rules are checked by position.
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct DaysLimitRule {
+    pub id: i64,
+    pub position: i64,
+    pub project: Option<String>,
+    pub property_type: Option<String>,
+    pub facing: Option<String>,
+    pub days_limit: i32,
+}
+
+impl DaysLimitRule {
+    fn matches(&self, project: &str, property_type: &str, facing: &str) -> bool {
+        let check = |rule: &Option<String>, value: &str| rule.as_deref().is_none_or(|r| r == value);
+        check(&self.project, project)
+            && check(&self.property_type, property_type)
+            && check(&self.facing, facing)
+    }
+}
+
+impl Display for DaysLimitRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let show = |v: &Option<String>| v.clone().unwrap_or_else(|| ANY.to_string());
+        write!(
+            f,
+            "#{} [поз. {}] {} | {} | {} → {} дн.",
+            self.id,
+            self.position,
+            show(&self.project),
+            show(&self.property_type),
+            show(&self.facing),
+            self.days_limit
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Rules(pub Vec<DaysLimitRule>);
+
+impl Rules {
+    pub fn days_limit_for(&self, project: &str, property_type: &str, facing: &str) -> i32 {
+        self.0
+            .iter()
+            .find(|r| r.matches(project, property_type, facing))
+            .map(|r| r.days_limit)
+            .unwrap_or(DEFAULT_DAYS_LIMIT)
+    }
+}
+
+/// Parses `/addrule` arguments: `<позиция> <проект>|<тип>|<отделка>|<дней>`, `*` matches any value
+pub fn parse_rule(text: &str) -> std::result::Result<DaysLimitRule, String> {
+    let template = "Шаблон: /addrule позиция проект|тип|отделка|дней, * - любое значение";
+    let (position, rest) = text.trim().split_once(' ').ok_or(template)?;
+    let position = position.parse::<i64>().map_err(|_| template)?;
+    let parts: Vec<&str> = rest.split('|').map(str::trim).collect();
+    let [project, property_type, facing, days] = parts[..] else {
+        return Err(template.to_string());
+    };
+    let days_limit = match days.parse::<i32>() {
+        Ok(days) if days > 0 => days,
+        _ => return Err(template.to_string()),
+    };
+    let matcher = |v: &str| (v != ANY && !v.is_empty()).then(|| v.to_string());
+
+    Ok(DaysLimitRule {
+        id: 0,
+        position,
+        project: matcher(project),
+        property_type: matcher(property_type),
+        facing: matcher(facing),
+        days_limit,
+    })
+}
+
+impl Display for Rules {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return writeln!(f, "Правил нет, по умолчанию {DEFAULT_DAYS_LIMIT} дн.");
+        }
+        writeln!(f, "Проект | Тип | Отделка → срок передачи")?;
+        for rule in &self.0 {
+            writeln!(f, "{rule}")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct LimitChange {
+    pub deal: DealData,
+    pub old_limit: i32,
+}
+
+impl Display for LimitChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, {}, {} № {}: {} → {} дн. (до {})",
+            self.deal.project,
+            self.deal.house,
+            self.deal.property_type,
+            self.deal.property_num,
+            self.old_limit,
+            self.deal.days_limit,
+            self.deal.exp_date().format("%d.%m.%Y")
+        )
+    }
+}
+
+/// Reply to a rule change: which deals got a new transfer date
+pub fn changes_report(changes: &[LimitChange]) -> String {
+    let mut report = format!("Пересчитано сделок: {}\n", changes.len());
+    for change in changes {
+        report.push_str(&format!("{change}\n"));
+    }
+    report
+}
+
+async fn select_rules<'e, E>(executor: E) -> Result<Rules>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let rules = sqlx::query_as("SELECT * FROM days_limit_rule ORDER BY position, id")
+        .fetch_all(executor)
+        .await?;
+    Ok(Rules(rules))
+}
+
+impl Db {
+    pub async fn list_rules(&self) -> Result<Rules> {
+        select_rules(&self.db).await
+    }
+
+    pub async fn add_rule(&self, rule: &DaysLimitRule) -> Result<()> {
+        info!("[add_rule] {rule}");
+        sqlx::query(
+            r#"
+            INSERT INTO days_limit_rule (position, project, property_type, facing, days_limit)
+            VALUES ($1, $2, $3, $4, $5)"#,
+        )
+        .bind(rule.position)
+        .bind(&rule.project)
+        .bind(&rule.property_type)
+        .bind(&rule.facing)
+        .bind(rule.days_limit)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_rule(&self, id: i64) -> Result<bool> {
+        info!("[delete_rule] {id}");
+        let res = sqlx::query("DELETE FROM days_limit_rule WHERE id = $1")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Recalculates deals whose limit came from the rules, not from AmoCRM.
+    /// All deals move to the new rules together, or none does.
+    pub async fn apply_rules(&self) -> Result<Vec<LimitChange>> {
+        let mut tx = self.db.begin().await?;
+        let rules = select_rules(&mut *tx).await?;
+        let deals: Vec<DealData> = sqlx::query_as(
+            "SELECT * FROM deal WHERE transfer_completed = false AND days_limit_default = true",
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut changes = vec![];
+        for mut deal in deals {
+            let days_limit = rules.days_limit_for(&deal.project, &deal.property_type, &deal.facing);
+            if days_limit == deal.days_limit {
+                continue;
+            }
+            sqlx::query("UPDATE deal SET days_limit = $1 WHERE id = $2")
+                .bind(days_limit)
+                .bind(deal.id)
+                .execute(&mut *tx)
+                .await?;
+            let old_limit = deal.days_limit;
+            deal.days_limit = days_limit;
+            changes.push(LimitChange { deal, old_limit });
+        }
+        tx.commit().await?;
+        info!("[apply_rules] {} deals recalculated", changes.len());
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_db;
+
+    #[test]
+    fn test_rule_order() {
+        let rules = Rules(vec![
+            parse_rule("1 DNS Сити|Кладовка|*|20").unwrap(),
+            parse_rule("2 DNS Сити|*|*|60").unwrap(),
+            parse_rule("3 *|*|Чистовая|45").unwrap(),
+        ]);
+        assert_eq!(rules.days_limit_for("DNS Сити", "Кладовка", ""), 20);
+        assert_eq!(rules.days_limit_for("DNS Сити", "Квартира", "Чистовая"), 60);
+        assert_eq!(
+            rules.days_limit_for("ЖК Формат", "Квартира", "Чистовая"),
+            45
+        );
+        assert_eq!(
+            rules.days_limit_for("ЖК Формат", "Квартира", ""),
+            DEFAULT_DAYS_LIMIT
+        );
+        assert!(parse_rule("DNS Сити|*|*|60").is_err());
+        assert!(parse_rule("1 DNS Сити|*|60").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_rules() {
+        let db = test_db().await;
+        let mut deal = DealData::builder()
+            .id(0, 9)
+            .property_type("Кладовка")
+            .property_num(8)
+            .default_limit(true)
+            .build_lead();
+        db.create_deal(&deal).await.unwrap();
+        deal.deal_id = 10;
+        deal.property_num = 9;
+        deal.default_limit = false;
+        db.create_deal(&deal).await.unwrap();
+
+        db.add_rule(&parse_rule("0 ЖК Формат|Кладовка|*|14").unwrap())
+            .await
+            .unwrap();
+        let changes = db.apply_rules().await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].deal.property_num, 8);
+        assert_eq!(changes[0].deal.days_limit, 14);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::Result;
+use crate::clock;
+use crate::model::Db;
+use crate::model::deal::DealData;
+use chrono::{Days, NaiveDate};
+use log::info;
+use sqlx::{Executor, FromRow, Sqlite};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DealEvent {
+    New,
+    Transferred,
+    /// Left the transfer stage and came back to it
+    Returned,
+}
+
+impl DealEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DealEvent::New => "new",
+            DealEvent::Transferred => "transferred",
+            DealEvent::Returned => "returned",
+        }
+    }
+}
+
+/// Today's snapshot of the deals in work, taken by its own daily job
+pub async fn take_daily_snapshot() -> Result<Vec<Snapshot>> {
+    let db = Db::new().await;
+    let undone = db.get_all_undone_deals().await?;
+    db.take_snapshot(&undone, clock::today()).await
+}
+
+/// Daily counts of one project and property type
+#[derive(FromRow, Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub snapshot_date: NaiveDate,
+    pub project: String,
+    pub property_type: String,
+    pub in_work: i64,
+    pub new: i64,
+    pub transferred: i64,
+    pub returned: i64,
+    pub overdue: i64,
+}
+
+#[derive(FromRow)]
+struct EventCount {
+    project: String,
+    property_type: String,
+    event: String,
+    count: i64,
+}
+
+type Key = (String, String);
+
+/// Change against the previous value, e.g. `(+5)`, empty when there is nothing to compare with
+pub fn delta_text(current: i64, previous: Option<i64>) -> String {
+    match previous {
+        None => String::new(),
+        Some(previous) => format!("({:+})", current - previous),
+    }
+}
+
+pub async fn insert_deal_event<'e, E>(
+    executor: E,
+    project: &str,
+    deal_id: u64,
+    event: DealEvent,
+) -> Result<()>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO deal_event (deal_id, project, property_type, event, created_on)
+        SELECT deal_id, project, property_type, $3, datetime('now')
+          FROM deal WHERE project = $1 AND deal_id = $2"#,
+    )
+    .bind(project)
+    .bind(deal_id as i64)
+    .bind(event.as_str())
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+impl Db {
+    pub async fn log_deal_event(
+        &self,
+        project: &str,
+        deal_id: u64,
+        event: DealEvent,
+    ) -> Result<()> {
+        insert_deal_event(&self.db, project, deal_id, event).await
+    }
+
+    /// Stores today's counts, events are the ones logged since the previous snapshot
+    pub async fn take_snapshot(
+        &self,
+        undone: &[DealData],
+        today: NaiveDate,
+    ) -> Result<Vec<Snapshot>> {
+        let mut rows: BTreeMap<Key, Snapshot> = BTreeMap::new();
+        for d in undone {
+            let entry = rows
+                .entry((d.project.clone(), d.property_type.clone()))
+                .or_insert_with(|| new_row(today, &d.project, &d.property_type));
+            entry.in_work += 1;
+            if d.exp_date() < today {
+                entry.overdue += 1;
+            }
+        }
+
+        let events: Vec<EventCount> = sqlx::query_as(
+            r#"
+            SELECT project, property_type, event, COUNT(*) AS count
+              FROM deal_event
+             WHERE created_on > COALESCE(
+                   (SELECT MAX(taken_on) FROM stat_snapshot WHERE snapshot_date < $1), '')
+             GROUP BY project, property_type, event"#,
+        )
+        .bind(today)
+        .fetch_all(&self.db)
+        .await?;
+        for e in events {
+            let entry = rows
+                .entry((e.project.clone(), e.property_type.clone()))
+                .or_insert_with(|| new_row(today, &e.project, &e.property_type));
+            match e.event.as_str() {
+                "new" => entry.new = e.count,
+                "transferred" => entry.transferred = e.count,
+                "returned" => entry.returned = e.count,
+                _ => {}
+            }
+        }
+
+        let mut tx = self.db.begin().await?;
+        sqlx::query("DELETE FROM stat_snapshot WHERE snapshot_date = $1")
+            .bind(today)
+            .execute(&mut *tx)
+            .await?;
+        for s in rows.values() {
+            sqlx::query(
+                r#"
+                INSERT INTO stat_snapshot (snapshot_date, project, property_type, in_work, new, transferred, returned, overdue, taken_on)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, datetime('now'))"#,
+            )
+            .bind(s.snapshot_date)
+            .bind(&s.project)
+            .bind(&s.property_type)
+            .bind(s.in_work)
+            .bind(s.new)
+            .bind(s.transferred)
+            .bind(s.returned)
+            .bind(s.overdue)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        info!("[take_snapshot] {today}: {} rows", rows.len());
+        Ok(rows.into_values().collect())
+    }
+
+    /// The latest snapshot taken before `date`
+    pub async fn snapshot_before(&self, date: NaiveDate) -> Result<Vec<Snapshot>> {
+        let rows = sqlx::query_as(
+            r#"
+            SELECT snapshot_date, project, property_type, in_work, new, transferred, returned, overdue
+              FROM stat_snapshot
+             WHERE snapshot_date = (SELECT MAX(snapshot_date) FROM stat_snapshot WHERE snapshot_date < $1)"#,
+        )
+        .bind(date)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows)
+    }
+
+    /// Every snapshot in `(from, to]`
+    pub async fn snapshots_between(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<Snapshot>> {
+        let rows = sqlx::query_as(
+            r#"
+            SELECT snapshot_date, project, property_type, in_work, new, transferred, returned, overdue
+              FROM stat_snapshot
+             WHERE snapshot_date > $1 AND snapshot_date <= $2
+             ORDER BY snapshot_date"#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows)
+    }
+}
+
+fn new_row(date: NaiveDate, project: &str, property_type: &str) -> Snapshot {
+    Snapshot {
+        snapshot_date: date,
+        project: project.to_string(),
+        property_type: property_type.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Latest snapshot compared with the state at the period start, events summed over the period
+pub struct PeriodReport {
+    pub title: String,
+    pub rows: Vec<PeriodRow>,
+}
+
+pub struct PeriodRow {
+    pub project: String,
+    pub property_type: String,
+    pub in_work: i64,
+    pub in_work_before: Option<i64>,
+    pub new: i64,
+    pub transferred: i64,
+    pub returned: i64,
+    pub overdue: i64,
+}
+
+impl PeriodReport {
+    pub fn build(title: &str, snapshots: &[Snapshot], start: NaiveDate) -> PeriodReport {
+        let Some(last_date) = snapshots.iter().map(|s| s.snapshot_date).max() else {
+            return PeriodReport {
+                title: title.to_string(),
+                rows: vec![],
+            };
+        };
+        // the state at the period start is the latest snapshot not after it
+        let base_date = snapshots
+            .iter()
+            .map(|s| s.snapshot_date)
+            .filter(|d| *d <= start)
+            .max();
+
+        let mut rows: BTreeMap<Key, PeriodRow> = BTreeMap::new();
+        for s in snapshots {
+            let key = (s.project.clone(), s.property_type.clone());
+            let row = rows.entry(key).or_insert_with(|| PeriodRow {
+                project: s.project.clone(),
+                property_type: s.property_type.clone(),
+                in_work: 0,
+                in_work_before: base_date.map(|_| 0),
+                new: 0,
+                transferred: 0,
+                returned: 0,
+                overdue: 0,
+            });
+            if Some(s.snapshot_date) == base_date {
+                row.in_work_before = Some(s.in_work);
+            }
+            if s.snapshot_date > start {
+                row.new += s.new;
+                row.transferred += s.transferred;
+                row.returned += s.returned;
+            }
+            if s.snapshot_date == last_date {
+                row.in_work = s.in_work;
+                row.overdue = s.overdue;
+            }
+        }
+
+        PeriodReport {
+            title: title.to_string(),
+            rows: rows.into_values().collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for PeriodReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.title)?;
+        if self.rows.is_empty() {
+            return writeln!(f, "Нет данных");
+        }
+        for r in &self.rows {
+            let mut line = format!(
+                "{} / {}: в работе {}",
+                r.project, r.property_type, r.in_work
+            );
+            let delta = delta_text(r.in_work, r.in_work_before);
+            if !delta.is_empty() {
+                let _ = write!(line, " {delta}");
+            }
+            let _ = write!(
+                line,
+                ", новых {}, передано {}, возвратов {}, просрочено {}",
+                r.new, r.transferred, r.returned, r.overdue
+            );
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Week-over-week and month-over-month changes for `/trend`
+pub async fn trend_report() -> Result<String> {
+    let db = Db::new().await;
+    let today = clock::today();
+    let month_ago = today.checked_sub_days(Days::new(30)).unwrap_or(today);
+    let week_ago = today.checked_sub_days(Days::new(7)).unwrap_or(today);
+    // the base snapshot of a period may be older than the period itself
+    let snapshots = db
+        .snapshots_between(
+            month_ago
+                .checked_sub_days(Days::new(30))
+                .unwrap_or(month_ago),
+            today,
+        )
+        .await?;
+
+    let week = PeriodReport::build("За неделю", &snapshots, week_ago);
+    let month = PeriodReport::build("За месяц", &snapshots, month_ago);
+    Ok(format!("{week}\n{month}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::amo::amo_types::Deal;
+    use crate::clock::date;
+    use crate::model::test_db;
+
+    fn deal(deal_id: u64, property_type: &str) -> Deal {
+        DealData::builder()
+            .id(0, deal_id)
+            .property_type(property_type)
+            .property_num(deal_id as i32)
+            .build_lead()
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let db = test_db().await;
+        for (id, property_type) in [(1, "Квартира"), (2, "Квартира"), (3, "Кладовка")]
+        {
+            db.create_deal(&deal(id, property_type)).await.unwrap();
+            db.log_deal_event("ЖК Формат", id, DealEvent::New)
+                .await
+                .unwrap();
+        }
+        db.mark_as_transferred(&[3]).await.unwrap();
+        db.log_deal_event("ЖК Формат", 3, DealEvent::Transferred)
+            .await
+            .unwrap();
+
+        let today = clock::today();
+        let undone = db.get_all_undone_deals().await.unwrap();
+        let rows = db.take_snapshot(&undone, today).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        let pantries = rows.iter().find(|r| r.property_type == "Кладовка").unwrap();
+        assert_eq!(pantries.in_work, 0);
+        assert_eq!(pantries.new, 1);
+        assert_eq!(pantries.transferred, 1);
+        let apartments = rows.iter().find(|r| r.property_type == "Квартира").unwrap();
+        assert_eq!(apartments.in_work, 2);
+
+        // taking it again the same day replaces the rows
+        db.take_snapshot(&undone, today).await.unwrap();
+        let tomorrow = today.succ_opt().unwrap();
+        assert_eq!(db.snapshot_before(tomorrow).await.unwrap().len(), 2);
+        assert!(db.snapshot_before(today).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_period_report() {
+        let row = |d: NaiveDate, in_work: i64, new: i64| Snapshot {
+            snapshot_date: d,
+            project: "DNS Сити".to_string(),
+            property_type: "Квартира".to_string(),
+            in_work,
+            new,
+            ..Default::default()
+        };
+        let snapshots = vec![
+            row(date(2025, 10, 1), 37, 2),
+            row(date(2025, 10, 5), 40, 3),
+            row(date(2025, 10, 8), 42, 4),
+        ];
+        let report = PeriodReport::build("За неделю", &snapshots, date(2025, 10, 1));
+        assert_eq!(report.rows[0].in_work, 42);
+        assert_eq!(report.rows[0].in_work_before, Some(37));
+        assert_eq!(report.rows[0].new, 7);
+        assert!(report.to_string().contains("в работе 42 (+5), новых 7"));
+        assert_eq!(delta_text(3, None), "");
+        assert_eq!(delta_text(3, Some(5)), "(-2)");
+    }
+}
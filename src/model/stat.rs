@@ -1,55 +1,506 @@
 use crate::Result;
-use crate::adapters::mailer::Email;
-use crate::adapters::mailer::data_types::{DealInfo, StatNumbers};
-use crate::bot_interface::PROJECTS;
+use crate::adapters::mailer::data_types::{DealInfo, HouseStat, ProjectStat, TypeStat};
+use crate::chart::get_charts;
+use crate::clock;
+use crate::config::config;
 use crate::model::Db;
+use crate::model::deal::DealData;
+use crate::model::digest::digest_mode;
+use crate::model::forecast::Forecast;
+use crate::model::kpi::Kpi;
+use crate::model::snapshot::{Snapshot, delta_text};
+use crate::model::subscription::{MailKind, get_audiences};
+use chrono::NaiveDate;
+use log::error;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Counts deals per project, property type and house, whatever values are present
+pub fn aggregate(deals: &[DealData]) -> Vec<ProjectStat> {
+    let mut counts: BTreeMap<&str, BTreeMap<&str, BTreeMap<&str, usize>>> = BTreeMap::new();
+    for d in deals {
+        *counts
+            .entry(&d.project)
+            .or_default()
+            .entry(&d.property_type)
+            .or_default()
+            .entry(&d.house)
+            .or_default() += 1;
+    }
+
+    counts
+        .into_iter()
+        .map(|(project, types)| {
+            let types: Vec<TypeStat> = types
+                .into_iter()
+                .map(|(property_type, houses)| TypeStat {
+                    property_type: property_type.to_string(),
+                    total: houses.values().sum(),
+                    delta: String::new(),
+                    houses: houses
+                        .into_iter()
+                        .map(|(house, count)| HouseStat {
+                            house: house.to_string(),
+                            count,
+                        })
+                        .collect(),
+                })
+                .collect();
+            ProjectStat {
+                project: project.to_string(),
+                total: types.iter().map(|t| t.total).sum(),
+                delta: String::new(),
+                types,
+            }
+        })
+        .collect()
+}
+
+/// Fills in changes against the previous snapshot, nothing is shown on the first run.
+/// Groups that were in work before and have none left are kept with 0, the drop shows.
+pub fn apply_deltas(projects: &mut Vec<ProjectStat>, previous: &[Snapshot]) {
+    if previous.is_empty() {
+        return;
+    }
+    for s in previous {
+        let idx = match projects.iter().position(|p| p.project == s.project) {
+            Some(idx) => idx,
+            None => {
+                projects.push(ProjectStat {
+                    project: s.project.clone(),
+                    total: 0,
+                    delta: String::new(),
+                    types: vec![],
+                });
+                projects.len() - 1
+            }
+        };
+        let types = &mut projects[idx].types;
+        if !types.iter().any(|t| t.property_type == s.property_type) {
+            types.push(TypeStat {
+                property_type: s.property_type.clone(),
+                total: 0,
+                delta: String::new(),
+                houses: vec![],
+            });
+            types.sort_by(|a, b| a.property_type.cmp(&b.property_type));
+        }
+    }
+    projects.sort_by(|a, b| a.project.cmp(&b.project));
+
+    for p in projects.iter_mut() {
+        let project_before: i64 = previous
+            .iter()
+            .filter(|s| s.project == p.project)
+            .map(|s| s.in_work)
+            .sum();
+        p.delta = delta_text(p.total as i64, Some(project_before));
+        for t in p.types.iter_mut() {
+            let before = previous
+                .iter()
+                .find(|s| s.project == p.project && s.property_type == t.property_type)
+                .map(|s| s.in_work)
+                .unwrap_or_default();
+            t.delta = delta_text(t.total as i64, Some(before));
+        }
+    }
+}
 
 pub async fn send_stat() -> Result<()> {
     let db = Db::new().await;
     let deals_in_work = db.get_all_undone_deals().await?;
-
-    if deals_in_work.is_empty() {
+    let today = clock::today();
+    if deals_in_work.is_empty() || digest_mode() {
         return Ok(());
     }
+    let previous = db.snapshot_before(today).await?;
 
-    let format_apartments = deals_in_work
-        .iter()
-        .filter(|d| d.project == PROJECTS[1] && d.property_type == "Квартира")
-        .count();
-    let format_pantries = deals_in_work
-        .iter()
-        .filter(|d| d.project == PROJECTS[1] && d.property_type == "Кладовка")
-        .count();
-    let format_parking = deals_in_work
-        .iter()
-        .filter(|d| d.project == PROJECTS[1] && d.property_type == "Машиноместо")
-        .count();
-    let city_apartments = deals_in_work
-        .iter()
-        .filter(|d| d.project == PROJECTS[0] && d.property_type == "Квартира")
-        .count();
-    let city_pantries = deals_in_work
-        .iter()
-        .filter(|d| d.project == PROJECTS[0] && d.property_type == "Кладовка")
-        .count();
-    let stat_numbers = StatNumbers {
-        format_apartments,
-        format_pantries,
-        format_parking,
-        city_apartments,
-        city_pantries,
-    };
-    let undone_deals: Vec<DealInfo> = deals_in_work.into_iter().map(Into::into).collect();
+    let transferred = db.transferred_deals().await?;
+    for audience in get_audiences(&db, MailKind::Stat).await? {
+        let deals = audience.projects.filter(&deals_in_work, |d| &d.project);
+        if deals.is_empty() {
+            continue;
+        }
+        let mut projects = aggregate(&deals);
+        let previous = audience.projects.filter(&previous, |s| &s.project);
+        apply_deltas(&mut projects, &previous);
+        let transferred = audience.projects.filter(&transferred, |d| &d.project);
+        let kpi = Kpi::compute(&transferred, &deals, today);
+        let forecast = Forecast::build(
+            &deals,
+            today,
+            config().FORECAST_WEEKS,
+            config().HANDOVER_CAPACITY,
+        );
+        // the numbers still go out when charts can't be drawn
+        let charts = get_charts(&audience.projects).await.unwrap_or_else(|e| {
+            error!("Failed to render charts: {e}");
+            vec![]
+        });
+        let undone_deals: Vec<DealInfo> = deals.into_iter().map(Into::into).collect();
 
-    let email = Email::new();
-    email.stat_notification(undone_deals, stat_numbers).await?;
+        audience
+            .email
+            .stat_notification(undone_deals, projects, &kpi, &forecast, &charts)
+            .await?;
+    }
 
     Ok(())
 }
 
+const CALLBACK_PREFIX: &str = "st:";
+
+/// Position in the `/stat` drill-down: keys of the project, property type and house names.
+/// Keys stay put when a sync adds or removes a group, and keep the callback data
+/// within telegram's 64 bytes whatever the names are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatPath(pub Vec<String>);
+
+/// FNV-1a of the name, stable across restarts so old buttons keep working
+fn name_key(name: &str) -> String {
+    let hash = name.bytes().fold(0x811c_9dc5_u32, |hash, b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    });
+    format!("{hash:08x}")
+}
+
+impl StatPath {
+    pub fn callback_data(&self) -> String {
+        format!("{CALLBACK_PREFIX}{}", self.0.join(":"))
+    }
+
+    pub fn parse_callback(data: &str) -> Option<StatPath> {
+        let rest = data.strip_prefix(CALLBACK_PREFIX)?;
+        if rest.is_empty() {
+            return Some(StatPath::default());
+        }
+        let keys: Vec<String> = rest.split(':').map(str::to_string).collect();
+        let valid = keys
+            .iter()
+            .all(|key| key.len() == 8 && key.chars().all(|c| c.is_ascii_hexdigit()));
+        (valid && keys.len() <= 3).then_some(StatPath(keys))
+    }
+
+    fn child(&self, name: &str) -> StatPath {
+        let mut path = self.0.clone();
+        path.push(name_key(name));
+        StatPath(path)
+    }
+
+    fn parent(&self) -> Option<StatPath> {
+        let (_, parent) = self.0.split_last()?;
+        Some(StatPath(parent.to_vec()))
+    }
+
+    fn matches(&self, level: usize, name: &str) -> bool {
+        self.0.get(level).is_some_and(|key| *key == name_key(name))
+    }
+}
+
+/// One screen of the `/stat` drill-down
+pub struct StatView {
+    pub text: String,
+    pub buttons: Vec<(String, StatPath)>,
+    pub back: Option<StatPath>,
+}
+
+fn with_delta(total: usize, delta: &str) -> String {
+    if delta.is_empty() {
+        total.to_string()
+    } else {
+        format!("{total} {delta}")
+    }
+}
+
+fn deadline_text(date: NaiveDate, today: NaiveDate) -> String {
+    let mark = if date < today {
+        " (просрочен)"
+    } else {
+        ""
+    };
+    format!("{}{mark}", date.format("%d.%m.%Y"))
+}
+
+/// Builds the screen for `path`, a name that is gone after a sync falls back to the level above
+pub fn stat_view(
+    projects: &[ProjectStat],
+    deals: &[DealData],
+    path: &StatPath,
+    today: NaiveDate,
+) -> StatView {
+    let project = projects.iter().find(|p| path.matches(0, &p.project));
+    let property_type =
+        project.and_then(|p| p.types.iter().find(|t| path.matches(1, &t.property_type)));
+    let house = property_type.and_then(|t| t.houses.iter().find(|h| path.matches(2, &h.house)));
+
+    match (project, property_type, house) {
+        (Some(p), Some(t), Some(h)) => {
+            let mut objects: Vec<&DealData> = deals
+                .iter()
+                .filter(|d| {
+                    d.project == p.project
+                        && d.property_type == t.property_type
+                        && d.house == h.house
+                })
+                .collect();
+            objects.sort_by_key(|d| (d.exp_date(), d.property_num));
+            let mut text = format!(
+                "{} / {} / {}: {}\n",
+                p.project, t.property_type, h.house, h.count
+            );
+            for d in objects {
+                let _ = write!(
+                    text,
+                    "\n№ {} — до {}",
+                    d.property_num,
+                    deadline_text(d.exp_date(), today)
+                );
+            }
+            StatView {
+                text,
+                buttons: vec![],
+                back: path.parent(),
+            }
+        }
+        (Some(p), Some(t), None) => {
+            let mut text = format!(
+                "{} / {}: {}\n",
+                p.project,
+                t.property_type,
+                with_delta(t.total, &t.delta)
+            );
+            for h in &t.houses {
+                let nearest = deals
+                    .iter()
+                    .filter(|d| {
+                        d.project == p.project
+                            && d.property_type == t.property_type
+                            && d.house == h.house
+                    })
+                    .map(|d| d.exp_date())
+                    .min();
+                let _ = write!(text, "\n{}: {}", h.house, h.count);
+                if let Some(nearest) = nearest {
+                    let _ = write!(text, ", ближайший срок {}", deadline_text(nearest, today));
+                }
+            }
+            let prefix = StatPath(path.0[..2].to_vec());
+            StatView {
+                text,
+                buttons: t
+                    .houses
+                    .iter()
+                    .map(|h| (format!("{} ({})", h.house, h.count), prefix.child(&h.house)))
+                    .collect(),
+                back: prefix.parent(),
+            }
+        }
+        (Some(p), None, _) => {
+            let mut text = format!("{}: {}\n", p.project, with_delta(p.total, &p.delta));
+            for t in &p.types {
+                let houses: Vec<String> = t
+                    .houses
+                    .iter()
+                    .map(|h| format!("{}: {}", h.house, h.count))
+                    .collect();
+                let _ = write!(
+                    text,
+                    "\n{}: {}\n    {}",
+                    t.property_type,
+                    with_delta(t.total, &t.delta),
+                    houses.join(", ")
+                );
+            }
+            let prefix = StatPath(path.0[..1].to_vec());
+            StatView {
+                text,
+                buttons: p
+                    .types
+                    .iter()
+                    .map(|t| {
+                        (
+                            format!("{} ({})", t.property_type, t.total),
+                            prefix.child(&t.property_type),
+                        )
+                    })
+                    .collect(),
+                back: Some(StatPath::default()),
+            }
+        }
+        (None, _, _) => {
+            let total: usize = projects.iter().map(|p| p.total).sum();
+            let mut text = format!("Объекты на этапе «Передача»: {total}\n");
+            for p in projects {
+                let _ = write!(text, "\n{}: {}", p.project, with_delta(p.total, &p.delta));
+                for t in &p.types {
+                    let _ = write!(
+                        text,
+                        "\n    {}: {}",
+                        t.property_type,
+                        with_delta(t.total, &t.delta)
+                    );
+                }
+            }
+            StatView {
+                text,
+                buttons: projects
+                    .iter()
+                    .map(|p| {
+                        (
+                            format!("{} ({})", p.project, p.total),
+                            StatPath::default().child(&p.project),
+                        )
+                    })
+                    .collect(),
+                back: None,
+            }
+        }
+    }
+}
+
+pub async fn get_stat_view(path: &StatPath) -> Result<StatView> {
+    let db = Db::new().await;
+    let deals = db.get_all_undone_deals().await?;
+    let today = clock::today();
+    let previous = db.snapshot_before(today).await?;
+    let mut projects = aggregate(&deals);
+    apply_deltas(&mut projects, &previous);
+    Ok(stat_view(&projects, &deals, path, today))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::adapters::mailer::data_types::{DkpStat, MailBody};
+
+    fn deal(project: &str, property_type: &str, house: &str) -> DealData {
+        DealData::builder()
+            .project(project)
+            .property_type(property_type)
+            .house(house)
+            .build()
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let deals = vec![
+            deal("ЖК Формат", "Квартира", "Дом 2"),
+            deal("ЖК Формат", "Квартира", "Дом 1"),
+            deal("ЖК Формат", "Квартира", "Дом 1"),
+            deal("DNS Сити", "Машиноместо", "Дом 1"),
+            deal("Новый ЖК", "Кладовка", "Дом 3"),
+        ];
+        let stat = aggregate(&deals);
+
+        assert_eq!(stat.len(), 3);
+        assert_eq!(stat[0].project, "DNS Сити");
+        assert_eq!(stat[0].types[0].property_type, "Машиноместо");
+        let format = stat.iter().find(|p| p.project == "ЖК Формат").unwrap();
+        assert_eq!(format.total, 3);
+        assert_eq!(format.types[0].houses[0].house, "Дом 1");
+        assert_eq!(format.types[0].houses[0].count, 2);
+
+        let mut stat = stat;
+        let previous = Snapshot {
+            project: "ЖК Формат".to_string(),
+            property_type: "Квартира".to_string(),
+            in_work: 5,
+            ..Default::default()
+        };
+        let gone = Snapshot {
+            project: "ЖК Формат".to_string(),
+            property_type: "Кладовка".to_string(),
+            in_work: 2,
+            ..Default::default()
+        };
+        let gone_project = Snapshot {
+            project: "Старый ЖК".to_string(),
+            property_type: "Квартира".to_string(),
+            in_work: 1,
+            ..Default::default()
+        };
+        apply_deltas(&mut stat, &[previous.clone(), gone, gone_project]);
+        assert_eq!(stat[1].delta, "(-4)");
+        assert_eq!(stat[1].types[0].property_type, "Квартира");
+        assert_eq!(stat[1].types[0].delta, "(-2)");
+        assert_eq!(stat[1].types[1].property_type, "Кладовка");
+        assert_eq!(stat[1].types[1].total, 0);
+        assert_eq!(stat[1].types[1].delta, "(-2)");
+        assert_eq!(stat[2].types[0].delta, "(+1)");
+        assert_eq!(stat[3].project, "Старый ЖК");
+        assert_eq!(stat[3].total, 0);
+        assert_eq!(stat[3].delta, "(-1)");
+
+        let mut stat = aggregate(&deals);
+        apply_deltas(&mut stat, &[previous]);
+        assert_eq!(stat[1].delta, "(-2)");
+
+        let body = DkpStat::new("header", stat, vec![], vec!["backlog"])
+            .body()
+            .unwrap();
+        assert!(body.html.contains("Новый ЖК"));
+        assert!(body.html.contains("Дом 1: 2, Дом 2: 1"));
+        assert!(body.html.contains("(-2)"));
+        assert!(body.html.contains("cid:backlog"));
+        assert!(body.text.contains("Квартира: 3 (-2)\n  Дом 1: 2, Дом 2: 1"));
+        assert!(!body.text.contains("cid:"));
+    }
+    #[test]
+    fn test_stat_view() {
+        let deals = vec![
+            deal("ЖК Формат", "Квартира", "Дом 2"),
+            deal("ЖК Формат", "Квартира", "Дом 1"),
+            deal("ЖК Формат", "Кладовка", "Дом 1"),
+            deal("DNS Сити", "Машиноместо", "Дом 1"),
+        ];
+        let projects = aggregate(&deals);
+        let today = clock::today();
+
+        let root = stat_view(&projects, &deals, &StatPath::default(), today);
+        assert!(root.text.starts_with("Объекты на этапе «Передача»: 4"));
+        assert_eq!(root.buttons[1].0, "ЖК Формат (3)");
+        assert_eq!(root.back, None);
+
+        let path = StatPath::parse_callback(&root.buttons[1].1.callback_data()).unwrap();
+        let project = stat_view(&projects, &deals, &path, today);
+        assert!(project.text.contains("Квартира: 2\n    Дом 1: 1, Дом 2: 1"));
+        assert_eq!(project.back, Some(StatPath::default()));
+
+        let path = |names: &[&str]| -> StatPath {
+            names
+                .iter()
+                .fold(StatPath::default(), |path, name| path.child(name))
+        };
+        let property_type = stat_view(&projects, &deals, &path(&["ЖК Формат", "Квартира"]), today);
+        assert!(property_type.text.contains("Дом 2: 1, ближайший срок"));
+        assert_eq!(property_type.buttons.len(), 2);
+
+        let house_path = path(&["ЖК Формат", "Квартира", "Дом 2"]);
+        assert!(house_path.callback_data().len() <= 64);
+        let house = stat_view(&projects, &deals, &house_path, today);
+        assert!(house.text.contains("ЖК Формат / Квартира / Дом 2: 1"));
+        assert_eq!(house.back, Some(path(&["ЖК Формат", "Квартира"])));
+
+        // the path follows the names, not the positions, when a project is added
+        let mut more = deals.clone();
+        more.push(deal("Альфа", "Квартира", "Дом 1"));
+        let projects = aggregate(&more);
+        let house = stat_view(&projects, &more, &house_path, today);
+        assert!(house.text.contains("ЖК Формат / Квартира / Дом 2: 1"));
+
+        // a project that disappeared after a sync shows the top level
+        let gone = stat_view(&projects, &deals, &path(&["Старый ЖК", "Квартира"]), today);
+        assert_eq!(gone.back, None);
+        assert_eq!(StatPath::parse_callback("st:"), Some(StatPath::default()));
+        let keys = vec![name_key("a"); 4];
+        assert_eq!(
+            StatPath::parse_callback(&format!("st:{}", keys.join(":"))),
+            None
+        );
+        assert_eq!(StatPath::parse_callback("st:1:2"), None);
+        assert_eq!(StatPath::parse_callback("cal:1"), None);
+    }
+
     #[tokio::test]
     async fn test_send_stat() {
         let res = send_stat().await;
@@ -0,0 +1,449 @@
+use crate::Result;
+use crate::adapters::mailer::Email;
+use crate::clock;
+use crate::model::Db;
+use crate::model::report::ReportKind;
+use log::info;
+use sqlx::FromRow;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+const ANY: &str = "*";
+
+/// Emails a recipient can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailKind {
+    NewDeals,
+    Deadline,
+    Stat,
+    Weekly,
+    Monthly,
+    Act,
+    Digest,
+}
+
+impl MailKind {
+    pub const ALL: [MailKind; 7] = [
+        MailKind::NewDeals,
+        MailKind::Deadline,
+        MailKind::Stat,
+        MailKind::Weekly,
+        MailKind::Monthly,
+        MailKind::Act,
+        MailKind::Digest,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MailKind::NewDeals => "new",
+            MailKind::Deadline => "deadline",
+            MailKind::Stat => "stat",
+            MailKind::Weekly => "weekly",
+            MailKind::Monthly => "monthly",
+            MailKind::Act => "act",
+            MailKind::Digest => "digest",
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            MailKind::NewDeals => "новые сделки",
+            MailKind::Deadline => "сроки передачи",
+            MailKind::Stat => "статистика",
+            MailKind::Weekly => "еженедельный отчёт",
+            MailKind::Monthly => "ежемесячный отчёт",
+            MailKind::Act => "акты приёма-передачи",
+            MailKind::Digest => "ежедневная сводка",
+        }
+    }
+
+    /// Separate emails the daily digest takes the place of
+    pub fn in_digest(&self) -> bool {
+        matches!(
+            self,
+            MailKind::NewDeals | MailKind::Deadline | MailKind::Stat
+        )
+    }
+
+    fn parse(code: &str) -> Option<MailKind> {
+        MailKind::ALL
+            .into_iter()
+            .find(|k| k.as_str() == code.trim().to_lowercase())
+    }
+}
+
+impl From<ReportKind> for MailKind {
+    fn from(kind: ReportKind) -> Self {
+        match kind {
+            ReportKind::Weekly => MailKind::Weekly,
+            ReportKind::Monthly => MailKind::Monthly,
+        }
+    }
+}
+
+/// Projects a recipient gets mail about, empty means every project
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Projects(Vec<String>);
+
+impl Projects {
+    pub fn all() -> Projects {
+        Projects::default()
+    }
+
+    /// Comma separated names, `*` or nothing selects every project
+    fn parse(list: &str) -> Projects {
+        let mut projects: Vec<String> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(str::to_string)
+            .collect();
+        if projects.iter().any(|p| p == ANY) {
+            return Projects::all();
+        }
+        projects.sort();
+        projects.dedup();
+        Projects(projects)
+    }
+
+    pub fn covers(&self, project: &str) -> bool {
+        self.0.is_empty()
+            || self
+                .0
+                .iter()
+                .any(|p| p.to_lowercase() == project.to_lowercase())
+    }
+
+    /// The items of the covered projects
+    pub fn filter<T: Clone>(&self, items: &[T], project: impl Fn(&T) -> &str) -> Vec<T> {
+        items
+            .iter()
+            .filter(|item| self.covers(project(item)))
+            .cloned()
+            .collect()
+    }
+
+    fn to_column(&self) -> String {
+        self.0.join(",")
+    }
+}
+
+impl Display for Projects {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "все")
+        } else {
+            write!(f, "{}", self.0.join(", "))
+        }
+    }
+}
+
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub id: i64,
+    pub email: String,
+    pub name: String,
+    /// Comma separated `MailKind` codes, `*` for every kind including ones added later
+    pub kinds: String,
+    /// Comma separated projects, empty for all
+    pub projects: String,
+}
+
+impl Subscription {
+    pub fn kinds(&self) -> Vec<MailKind> {
+        if self.kinds.trim() == ANY {
+            return MailKind::ALL.to_vec();
+        }
+        self.kinds.split(',').filter_map(MailKind::parse).collect()
+    }
+
+    /// Subscribers of the emails the digest replaces get the digest as well,
+    /// whether or not they picked it
+    pub fn receives(&self, kind: MailKind) -> bool {
+        let kinds = self.kinds();
+        kinds.contains(&kind) || (kind == MailKind::Digest && kinds.iter().any(MailKind::in_digest))
+    }
+
+    pub fn projects(&self) -> Projects {
+        Projects::parse(&self.projects)
+    }
+}
+
+impl Display for Subscription {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kinds: Vec<&str> = self.kinds().iter().map(MailKind::title).collect();
+        write!(
+            f,
+            "#{} {} <{}>\nписьма: {}\nпроекты: {}",
+            self.id,
+            self.name,
+            self.email,
+            kinds.join(", "),
+            self.projects()
+        )
+    }
+}
+
+pub fn subscribe_usage() -> String {
+    let kinds: Vec<String> = MailKind::ALL
+        .iter()
+        .map(|k| format!("{} - {}", k.as_str(), k.title()))
+        .collect();
+    format!(
+        "Шаблон: /subscribe адрес|имя|письма|проекты\nписьма через запятую: {}; * - все\nпроекты через запятую, * - все",
+        kinds.join(", ")
+    )
+}
+
+/// Parses `/subscribe` arguments: `<адрес>|<имя>|<письма>|<проекты>`, projects may be left out
+pub fn parse_subscription(text: &str) -> std::result::Result<Subscription, String> {
+    let parts: Vec<&str> = text.split('|').map(str::trim).collect();
+    let (email, name, kinds, projects) = match parts[..] {
+        [email, name, kinds] => (email, name, kinds, ANY),
+        [email, name, kinds, projects] => (email, name, kinds, projects),
+        _ => return Err(subscribe_usage()),
+    };
+    if !email.contains('@') || email.contains([',', ' ']) {
+        return Err(format!("Неверный адрес {email}\n{}", subscribe_usage()));
+    }
+    let codes = if kinds == ANY {
+        ANY.to_string()
+    } else {
+        let kinds = kinds
+            .split(',')
+            .map(|code| {
+                MailKind::parse(code)
+                    .ok_or_else(|| format!("Неизвестный тип письма {code}\n{}", subscribe_usage()))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        MailKind::ALL
+            .iter()
+            .filter(|k| kinds.contains(k))
+            .map(MailKind::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    if codes.is_empty() {
+        return Err(subscribe_usage());
+    }
+
+    Ok(Subscription {
+        id: 0,
+        email: email.to_string(),
+        name: if name.is_empty() { email } else { name }.to_string(),
+        kinds: codes,
+        projects: Projects::parse(projects).to_column(),
+    })
+}
+
+pub fn subscriptions_text(subscriptions: &[Subscription]) -> String {
+    if subscriptions.is_empty() {
+        return "Подписок нет, письма получают адреса из RECEIVERS и REPORT_RECEIVERS".to_string();
+    }
+    subscriptions
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Recipients of one kind of email grouped by their project filter,
+/// every group gets its own email
+pub fn receivers_by_projects(
+    subscriptions: &[Subscription],
+    kind: MailKind,
+) -> BTreeMap<Projects, Vec<(String, String)>> {
+    let mut groups: BTreeMap<Projects, Vec<(String, String)>> = BTreeMap::new();
+    for s in subscriptions.iter().filter(|s| s.receives(kind)) {
+        groups
+            .entry(s.projects())
+            .or_default()
+            .push((s.name.clone(), s.email.clone()));
+    }
+    groups
+}
+
+/// Recipients sharing a project filter
+pub struct Audience {
+    pub projects: Projects,
+    pub email: Email,
+}
+
+/// Who gets `kind`, the RECEIVERS lists until the first subscription is added
+pub async fn get_audiences(db: &Db, kind: MailKind) -> Result<Vec<Audience>> {
+    let subscriptions = db.list_subscriptions().await?;
+    if subscriptions.is_empty() {
+        let email = match kind {
+            MailKind::Weekly | MailKind::Monthly => Email::for_reports(),
+            _ => Email::new(),
+        };
+        return Ok(vec![Audience {
+            projects: Projects::all(),
+            email,
+        }]);
+    }
+    let audiences: Vec<Audience> = receivers_by_projects(&subscriptions, kind)
+        .into_iter()
+        .map(|(projects, receivers)| Audience {
+            projects,
+            email: Email::to(receivers),
+        })
+        .collect();
+    info!("[get_audiences] {}: {}", kind.as_str(), audiences.len());
+    Ok(audiences)
+}
+
+impl Db {
+    pub async fn list_subscriptions(&self) -> Result<Vec<Subscription>> {
+        let records =
+            sqlx::query_as("SELECT id, email, name, kinds, projects FROM subscription ORDER BY id")
+                .fetch_all(&self.db)
+                .await?;
+        Ok(records)
+    }
+
+    /// A known address gets its name, emails and projects replaced
+    pub async fn save_subscription(&self, s: &Subscription) -> Result<()> {
+        info!("[save_subscription] {} {} {}", s.email, s.kinds, s.projects);
+        sqlx::query(
+            r#"
+            INSERT INTO subscription (email, name, kinds, projects, created_on)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (email) DO UPDATE SET name = $2, kinds = $3, projects = $4"#,
+        )
+        .bind(s.email.to_lowercase())
+        .bind(&s.name)
+        .bind(&s.kinds)
+        .bind(&s.projects)
+        .bind(clock::now())
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_subscription(&self, email: &str) -> Result<bool> {
+        info!("[delete_subscription] {email}");
+        let res = sqlx::query("DELETE FROM subscription WHERE email = $1")
+            .bind(email.trim().to_lowercase())
+            .execute(&self.db)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_db;
+
+    #[test]
+    fn test_parse_subscription() {
+        let s = parse_subscription("sales@format.ru|Отдел продаж|stat, new|ЖК Формат").unwrap();
+        assert_eq!(s.kinds, "new,stat");
+        assert_eq!(s.kinds(), vec![MailKind::NewDeals, MailKind::Stat]);
+        assert!(s.projects().covers("жк формат"));
+        assert!(!s.projects().covers("DNS Сити"));
+
+        let s = parse_subscription("boss@format.ru||*").unwrap();
+        assert_eq!(s.name, "boss@format.ru");
+        assert_eq!(s.kinds, "*");
+        assert_eq!(s.kinds(), MailKind::ALL.to_vec());
+        assert_eq!(s.projects(), Projects::all());
+        assert_eq!(
+            parse_subscription("boss@format.ru|Директор|weekly|DNS Сити, *")
+                .unwrap()
+                .projects,
+            ""
+        );
+
+        assert!(parse_subscription("boss|Директор|stat").is_err());
+        assert!(parse_subscription("boss@format.ru|Директор|daily").is_err());
+        assert!(parse_subscription("boss@format.ru stat").is_err());
+    }
+
+    #[test]
+    fn test_receivers_by_projects() {
+        let subscriptions: Vec<Subscription> = [
+            "sales@format.ru|Продажи Формат|new,deadline|ЖК Формат",
+            "sales@city.ru|Продажи Сити|new,deadline|DNS Сити",
+            "boss@format.ru|Директор|stat,weekly",
+            "transfer@format.ru|Передача|*|ЖК Формат",
+        ]
+        .iter()
+        .map(|s| parse_subscription(s).unwrap())
+        .collect();
+
+        let groups = receivers_by_projects(&subscriptions, MailKind::NewDeals);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[&Projects::parse("ЖК Формат")],
+            vec![
+                ("Продажи Формат".to_string(), "sales@format.ru".to_string()),
+                ("Передача".to_string(), "transfer@format.ru".to_string())
+            ]
+        );
+
+        let groups = receivers_by_projects(&subscriptions, MailKind::Stat);
+        assert_eq!(groups[&Projects::all()].len(), 1);
+        assert_eq!(groups[&Projects::parse("ЖК Формат")].len(), 1);
+        assert!(
+            receivers_by_projects(&subscriptions, MailKind::Monthly)
+                .keys()
+                .all(|p| p == &Projects::parse("ЖК Формат"))
+        );
+
+        let projects = Projects::parse("DNS Сити");
+        let deals = ["ЖК Формат", "DNS Сити", "dns сити"];
+        assert_eq!(projects.filter(&deals, |d| d).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_save_subscription() {
+        let db = test_db().await;
+        assert!(db.list_subscriptions().await.unwrap().is_empty());
+        assert!(subscriptions_text(&[]).contains("RECEIVERS"));
+
+        let s = parse_subscription("Boss@Format.ru|Директор|stat").unwrap();
+        db.save_subscription(&s).await.unwrap();
+        let s = parse_subscription("boss@format.ru|Директор|stat,monthly|ЖК Формат").unwrap();
+        db.save_subscription(&s).await.unwrap();
+
+        let saved = db.list_subscriptions().await.unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(
+            saved[0].to_string(),
+            "#1 Директор <boss@format.ru>\nписьма: статистика, ежемесячный отчёт\nпроекты: ЖК Формат"
+        );
+
+        assert!(db.delete_subscription("BOSS@format.ru").await.unwrap());
+
+        // saved with `*` expanded before the digest existed
+        sqlx::query(
+            r#"
+            INSERT INTO subscription (email, name, kinds, projects, created_on)
+            VALUES ('old@format.ru', 'Старая', 'new,deadline,stat,weekly,monthly,act', '', $1)"#,
+        )
+        .bind(clock::now())
+        .execute(&db.db)
+        .await
+        .unwrap();
+        let s = parse_subscription("stat@format.ru|Статистика|stat").unwrap();
+        db.save_subscription(&s).await.unwrap();
+        let s = parse_subscription("all@format.ru|Все|*").unwrap();
+        db.save_subscription(&s).await.unwrap();
+        let s = parse_subscription("reports@format.ru|Отчёты|weekly").unwrap();
+        db.save_subscription(&s).await.unwrap();
+
+        let saved = db.list_subscriptions().await.unwrap();
+        let digest = receivers_by_projects(&saved, MailKind::Digest);
+        let emails: Vec<&str> = digest[&Projects::all()]
+            .iter()
+            .map(|(_, email)| email.as_str())
+            .collect();
+        assert_eq!(
+            emails,
+            vec!["old@format.ru", "stat@format.ru", "all@format.ru"]
+        );
+        assert!(!db.delete_subscription("boss@format.ru").await.unwrap());
+    }
+}
@@ -1,28 +1,23 @@
 use crate::Result;
 use crate::adapters::amo::AmoClient;
 use crate::model::Db;
+use crate::model::digest::digest_mode;
+use crate::model::snapshot::DealEvent;
+use crate::model::subscription::{MailKind, get_audiences};
 use log::{debug, error, info};
 
 use crate::adapters::amo::amo_types::Deal;
-use crate::adapters::mailer::Email;
 use crate::config::config;
+use crate::ics;
 use crate::sender::send_msg_to_group;
 use teloxide::Bot;
 
 pub async fn sync(bot: &Bot) -> Result<Vec<Deal>> {
     let results = sync_project(bot).await?;
-    notify_by_email(&results).await?;
+    ics::refresh().await;
     Ok(results)
 }
 
-async fn notify_by_email(data: &[Deal]) -> Result<()> {
-    if !data.is_empty() {
-        let email = Email::new();
-        email.new_objects_notification(data).await?;
-    }
-    Ok(())
-}
-
 async fn sync_project(bot: &Bot) -> Result<Vec<Deal>> {
     let db = Db::new().await;
     let mut saved_ids_limits = db.read_deal_ids().await?;
@@ -36,8 +31,9 @@ async fn sync_project(bot: &Bot) -> Result<Vec<Deal>> {
 async fn sync_funnel(db: &Db, saved_ids_limits: &mut Vec<(u64, i32, bool)>) -> Result<Vec<Deal>> {
     let funnel_id = config().FUNNEL;
     info!("Syncing funnel {}", funnel_id);
+    let rules = db.list_rules().await?;
     let amo_client = AmoClient::new();
-    let leads = amo_client.get_funnel_leads(funnel_id).await?;
+    let leads = amo_client.get_funnel_leads(funnel_id, &rules).await?;
 
     info!(
         "leads: {:?}",
@@ -55,10 +51,19 @@ async fn sync_funnel(db: &Db, saved_ids_limits: &mut Vec<(u64, i32, bool)>) -> R
             if let Some(saved) = saved {
                 saved_ids_limits.retain(|i| i.0 != lead.deal_id);
                 // if saved days_limit not correct
-                if saved.1 != lead.days_limit {
-                    db.set_days_limit(&lead.project, lead.deal_id, lead.days_limit)
-                        .await?;
+                if saved.1 != lead.days_limit || saved.2 != lead.default_limit {
+                    db.set_days_limit(
+                        &lead.project,
+                        lead.deal_id,
+                        lead.days_limit,
+                        lead.default_limit,
+                    )
+                    .await?;
                 }
+                db.set_responsible(&lead.project, lead.deal_id, lead.responsible_id)
+                    .await?;
+                db.set_buyer(&lead.project, lead.deal_id, &lead.buyer)
+                    .await?;
                 continue;
             }
 
@@ -66,15 +71,34 @@ async fn sync_funnel(db: &Db, saved_ids_limits: &mut Vec<(u64, i32, bool)>) -> R
             if db
                 .mark_as_not_transferred(&lead.project, lead.deal_id)
                 .await?
+                || db.restore_from_archive(&lead.project, lead.deal_id).await?
             {
+                db.log_deal_event(&lead.project, lead.deal_id, DealEvent::Returned)
+                    .await?;
                 continue;
             }
 
-            db.create_deal(&lead).await?;
             new_data.push(lead);
         }
     }
 
+    if !new_data.is_empty() {
+        let mut mails = vec![];
+        // the digest lists them as new sales
+        let audiences = if digest_mode() {
+            vec![]
+        } else {
+            get_audiences(db, MailKind::NewDeals).await?
+        };
+        for audience in audiences {
+            let deals = audience.projects.filter(&new_data, |d| &d.project);
+            if !deals.is_empty() {
+                mails.push(audience.email.new_objects_mail(&deals)?);
+            }
+        }
+        db.create_new_deals(&new_data, &mails).await?;
+    }
+
     Ok(new_data)
 }
 
@@ -88,6 +112,12 @@ async fn mark_as_transferred(remain_ids_limits: Vec<(u64, i32, bool)>, bot: &Bot
         match db.mark_as_transferred(&remain_ids).await {
             Ok(rows) => {
                 for r in rows {
+                    if let Err(e) = db
+                        .log_deal_event(&r.project, r.deal_id, DealEvent::Transferred)
+                        .await
+                    {
+                        error!("Failed to log transfer event: {e}");
+                    }
                     let msg = format!(
                         "Проект: {}, Дом №{}, к.{} ({}) передан!",
                         r.project, r.house, r.property_num, r.property_type
@@ -0,0 +1,36 @@
+use crate::adapters::mailer::smtp::Smtp;
+use crate::clock;
+use crate::config::config;
+use crate::model::Db;
+use crate::model::outbox::process_outbox;
+use crate::sender::send_msg_to_admin;
+use log::error;
+use std::time::Duration;
+use teloxide::Bot;
+use tokio::time::sleep;
+
+pub fn do_work(bot: Bot) {
+    tokio::spawn(async move {
+        let interval = Duration::from_secs(config().OUTBOX_INTERVAL_SECS.max(1));
+        loop {
+            let db = Db::new().await;
+            let result = process_outbox(
+                &db,
+                &Smtp::from_config(),
+                clock::now(),
+                config().OUTBOX_MAX_ATTEMPTS,
+                config().OUTBOX_ALERT_AFTER,
+            )
+            .await;
+            match result {
+                Ok(alerts) => {
+                    for alert in alerts {
+                        send_msg_to_admin(&bot, &alert).await;
+                    }
+                }
+                Err(e) => error!("Failed to process outbox: {e}"),
+            }
+            sleep(interval).await;
+        }
+    });
+}
@@ -0,0 +1,442 @@
+//! Minimal PDF writer for the transfer act: text lines only, one embedded TrueType font.
+//!
+//! The act needs Cyrillic text that can be copied back, so the font is embedded as a
+//! CID font with a ToUnicode map. The PDF crates either ship only the 14 standard Latin
+//! fonts or pull in a layout engine and image codecs for features the act never uses;
+//! this file is the few objects the act needs, written on top of `ttf-parser` and
+//! `flate2` that charts already depend on. Anything beyond lines of text (tables,
+//! images, several fonts) should move to a PDF crate instead of growing here.
+
+use crate::Result;
+use crate::error::Error;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use ttf_parser::{Face, GlyphId, name_id};
+
+/// A4 in points
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 56.0;
+const LINE_SPACING: f32 = 1.4;
+/// Catalog, pages, type 0 font, CID font, descriptor, font file, ToUnicode
+const FIXED_OBJECTS: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+}
+
+/// Paragraph, wrapped to the page width
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub text: String,
+    pub size: f32,
+    pub align: Align,
+}
+
+/// Glyphs of one wrapped line placed on a page
+struct Placed {
+    x: f32,
+    y: f32,
+    size: f32,
+    glyphs: Vec<u16>,
+}
+
+struct Font<'a> {
+    face: Face<'a>,
+    units: f32,
+}
+
+impl Font<'_> {
+    fn glyph(&self, c: char) -> u16 {
+        self.face.glyph_index(c).map(|g| g.0).unwrap_or_default()
+    }
+
+    fn advance(&self, glyph: u16) -> f32 {
+        self.face
+            .glyph_hor_advance(GlyphId(glyph))
+            .unwrap_or_default() as f32
+    }
+
+    fn width(&self, text: &str, size: f32) -> f32 {
+        text.chars()
+            .map(|c| self.advance(self.glyph(c)))
+            .sum::<f32>()
+            * size
+            / self.units
+    }
+
+    /// Greedy word wrap, a word wider than the line is split by characters
+    fn wrap(&self, text: &str, size: f32, max_width: f32) -> Vec<String> {
+        let mut lines = vec![];
+        let mut line = String::new();
+        for word in text.split(' ') {
+            let candidate = if line.is_empty() {
+                word.to_string()
+            } else {
+                format!("{line} {word}")
+            };
+            if self.width(&candidate, size) <= max_width {
+                line = candidate;
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            for c in word.chars() {
+                if !line.is_empty() && self.width(&format!("{line}{c}"), size) > max_width {
+                    lines.push(std::mem::take(&mut line));
+                }
+                line.push(c);
+            }
+        }
+        lines.push(line);
+        lines
+    }
+
+    /// PostScript name of the font, PDF names allow no spaces
+    fn name(&self) -> String {
+        let name = self
+            .face
+            .names()
+            .into_iter()
+            .filter(|n| n.name_id == name_id::POST_SCRIPT_NAME)
+            .find_map(|n| n.to_string())
+            .unwrap_or_default();
+        let name: String = name.chars().filter(char::is_ascii_alphanumeric).collect();
+        if name.is_empty() {
+            "Font".to_string()
+        } else {
+            name
+        }
+    }
+
+    fn scaled(&self, value: i16) -> i32 {
+        (value as f32 * 1000.0 / self.units).round() as i32
+    }
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).map_err(pdf_err)?;
+    encoder.finish().map_err(pdf_err)
+}
+
+fn pdf_err(e: impl std::fmt::Display) -> Error {
+    Error::AppErr(format!("Failed to write pdf: {e}"))
+}
+
+/// Objects are written in id order, so offsets line up with the xref table
+struct Writer {
+    buf: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Writer {
+            // binary marker tells tools the file is not plain text
+            buf: b"%PDF-1.7\n%\xE2\xE3\xCF\xD3\n".to_vec(),
+            offsets: vec![],
+        }
+    }
+
+    fn object(&mut self, dict: &str) {
+        self.offsets.push(self.buf.len());
+        let id = self.offsets.len();
+        self.buf
+            .extend_from_slice(format!("{id} 0 obj\n{dict}\nendobj\n").as_bytes());
+    }
+
+    fn stream(&mut self, dict: &str, data: &[u8]) -> Result<()> {
+        let data = compress(data)?;
+        self.offsets.push(self.buf.len());
+        let id = self.offsets.len();
+        self.buf.extend_from_slice(
+            format!(
+                "{id} 0 obj\n<< {dict} /Filter /FlateDecode /Length {} >>\nstream\n",
+                data.len()
+            )
+            .as_bytes(),
+        );
+        self.buf.extend_from_slice(&data);
+        self.buf.extend_from_slice(b"\nendstream\nendobj\n");
+        Ok(())
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let xref = self.buf.len();
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            let _ = writeln!(table, "{offset:010} 00000 n ");
+        }
+        let _ = write!(
+            table,
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            self.offsets.len() + 1
+        );
+        self.buf.extend_from_slice(table.as_bytes());
+        self.buf
+    }
+}
+
+/// Lays `lines` out on A4 pages with the TrueType `font` embedded, so any
+/// script the font covers is rendered and can be copied back as text
+pub fn render(lines: &[Line], font: &[u8]) -> Result<Vec<u8>> {
+    let face = Face::parse(font, 0).map_err(|e| pdf_err(format!("invalid font: {e}")))?;
+    let font = Font {
+        units: face.units_per_em() as f32,
+        face,
+    };
+
+    let text_width = PAGE_WIDTH - 2.0 * MARGIN;
+    let mut pages: Vec<Vec<Placed>> = vec![vec![]];
+    let mut used: BTreeMap<u16, char> = BTreeMap::new();
+    let mut y = PAGE_HEIGHT - MARGIN;
+    for line in lines {
+        for text in font.wrap(&line.text, line.size, text_width) {
+            y -= line.size * LINE_SPACING;
+            if y < MARGIN {
+                pages.push(vec![]);
+                y = PAGE_HEIGHT - MARGIN - line.size * LINE_SPACING;
+            }
+            let x = match line.align {
+                Align::Left => MARGIN,
+                Align::Center => (PAGE_WIDTH - font.width(&text, line.size)) / 2.0,
+            };
+            let glyphs = text
+                .chars()
+                .map(|c| {
+                    let glyph = font.glyph(c);
+                    used.entry(glyph).or_insert(c);
+                    glyph
+                })
+                .collect();
+            if let Some(page) = pages.last_mut() {
+                page.push(Placed {
+                    x,
+                    y,
+                    size: line.size,
+                    glyphs,
+                });
+            }
+        }
+    }
+
+    let name = font.name();
+    let mut writer = Writer::new();
+    writer.object("<< /Type /Catalog /Pages 2 0 R >>");
+    let kids: Vec<String> = (0..pages.len())
+        .map(|idx| format!("{} 0 R", FIXED_OBJECTS + 1 + idx * 2))
+        .collect();
+    writer.object(&format!(
+        "<< /Type /Pages /Kids [{}] /Count {} >>",
+        kids.join(" "),
+        pages.len()
+    ));
+    writer.object(&format!(
+        "<< /Type /Font /Subtype /Type0 /BaseFont /{name} /Encoding /Identity-H \
+         /DescendantFonts [4 0 R] /ToUnicode 7 0 R >>"
+    ));
+    let widths: Vec<String> = used
+        .keys()
+        .map(|&g| format!("{g} [{}]", (font.advance(g) * 1000.0 / font.units).round()))
+        .collect();
+    writer.object(&format!(
+        "<< /Type /Font /Subtype /CIDFontType2 /BaseFont /{name} \
+         /CIDSystemInfo << /Registry (Adobe) /Ordering (Identity) /Supplement 0 >> \
+         /FontDescriptor 5 0 R /CIDToGIDMap /Identity /DW 1000 /W [{}] >>",
+        widths.join(" ")
+    ));
+    let bbox = font.face.global_bounding_box();
+    writer.object(&format!(
+        "<< /Type /FontDescriptor /FontName /{name} /Flags 32 /FontBBox [{} {} {} {}] \
+         /ItalicAngle 0 /Ascent {} /Descent {} /CapHeight {} /StemV 80 /FontFile2 6 0 R >>",
+        font.scaled(bbox.x_min),
+        font.scaled(bbox.y_min),
+        font.scaled(bbox.x_max),
+        font.scaled(bbox.y_max),
+        font.scaled(font.face.ascender()),
+        font.scaled(font.face.descender()),
+        font.scaled(font.face.capital_height().unwrap_or(font.face.ascender())),
+    ));
+    writer.stream(
+        &format!("/Length1 {}", font.face.raw_face().data.len()),
+        font.face.raw_face().data,
+    )?;
+    writer.stream("", to_unicode(&used).as_bytes())?;
+
+    for (idx, page) in pages.iter().enumerate() {
+        writer.object(&format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {PAGE_WIDTH} {PAGE_HEIGHT}] \
+             /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            FIXED_OBJECTS + 2 + idx * 2
+        ));
+        let mut content = String::new();
+        for placed in page {
+            let hex: String = placed.glyphs.iter().map(|g| format!("{g:04X}")).collect();
+            let _ = writeln!(
+                content,
+                "BT /F1 {} Tf {:.2} {:.2} Td <{hex}> Tj ET",
+                placed.size, placed.x, placed.y
+            );
+        }
+        writer.stream("", content.as_bytes())?;
+    }
+    Ok(writer.finish())
+}
+
+/// Glyph to character map, lets viewers copy and search the text
+fn to_unicode(used: &BTreeMap<u16, char>) -> String {
+    let mut cmap = String::from(
+        "/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n\
+         /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+         /CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n\
+         1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n",
+    );
+    let used: Vec<(&u16, &char)> = used.iter().collect();
+    // a bfchar section holds at most 100 entries
+    for chunk in used.chunks(100) {
+        let _ = writeln!(cmap, "{} beginbfchar", chunk.len());
+        for (glyph, c) in chunk {
+            let utf16: String = c
+                .encode_utf16(&mut [0; 2])
+                .iter()
+                .map(|u| format!("{u:04X}"))
+                .collect();
+            let _ = writeln!(cmap, "<{glyph:04X}> <{utf16}>");
+        }
+        cmap.push_str("endbfchar\n");
+    }
+    cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n");
+    cmap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart::test_font;
+
+    fn font() -> Option<Vec<u8>> {
+        test_font().map(|path| std::fs::read(path).unwrap())
+    }
+
+    fn line(text: &str) -> Line {
+        Line {
+            text: text.to_string(),
+            size: 11.0,
+            align: Align::Left,
+        }
+    }
+
+    #[test]
+    fn test_wrap() {
+        let Some(bytes) = font() else {
+            return;
+        };
+        let face = Face::parse(&bytes, 0).unwrap();
+        let font = Font {
+            units: face.units_per_em() as f32,
+            face,
+        };
+        let lines = font.wrap("Покупатель принимает объект недвижимости", 11.0, 120.0);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|l| font.width(l, 11.0) <= 120.0));
+        assert_eq!(lines.join(" "), "Покупатель принимает объект недвижимости");
+        assert_eq!(font.wrap("", 11.0, 120.0), vec![""]);
+    }
+
+    #[test]
+    fn test_xref_offsets() {
+        let mut writer = Writer::new();
+        writer.object("<< /Type /Catalog /Pages 2 0 R >>");
+        writer.stream("", b"BT ET").unwrap();
+        writer.object("<< /Type /Pages /Kids [] /Count 0 >>");
+        let pdf = writer.finish();
+        let text = String::from_utf8_lossy(&pdf);
+
+        let table = text.split("xref\n").nth(1).unwrap();
+        let mut rows = table.lines();
+        assert_eq!(rows.next(), Some("0 4"));
+        assert_eq!(rows.next(), Some("0000000000 65535 f "));
+        for id in 1..=3 {
+            let row = rows.next().unwrap();
+            // every entry is exactly 20 bytes with the line feed
+            assert_eq!(row.len(), 19);
+            let offset: usize = row[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(format!("{id} 0 obj\n").as_bytes()));
+        }
+        assert!(text.contains("trailer\n<< /Size 4 /Root 1 0 R >>"));
+    }
+
+    #[test]
+    fn test_to_unicode() {
+        let used = BTreeMap::from([(0x01A2, 'П'), (0x0003, 'ё'), (0x0010, 'A')]);
+        let cmap = to_unicode(&used);
+        assert!(cmap.contains("3 beginbfchar\n<0003> <0451>\n<0010> <0041>\n<01A2> <041F>\n"));
+
+        let many: BTreeMap<u16, char> = (0..150u16)
+            .map(|g| (g, char::from_u32(0x0410 + g as u32).unwrap()))
+            .collect();
+        let cmap = to_unicode(&many);
+        assert!(cmap.contains("100 beginbfchar"));
+        assert!(cmap.contains("50 beginbfchar"));
+    }
+
+    #[test]
+    fn test_cyrillic_glyphs() {
+        let Some(bytes) = font() else {
+            return;
+        };
+        let pdf = render(&[line("Ёж")], &bytes).unwrap();
+        let face = Face::parse(&bytes, 0).unwrap();
+        let glyphs: Vec<u16> = "Ёж"
+            .chars()
+            .map(|c| face.glyph_index(c).unwrap().0)
+            .collect();
+        assert!(glyphs.iter().all(|&g| g != 0));
+
+        let text = String::from_utf8_lossy(&pdf);
+        // widths are listed for the used glyphs, the ToUnicode stream is compressed
+        let units = face.units_per_em() as f32;
+        for &glyph in &glyphs {
+            let advance = face.glyph_hor_advance(GlyphId(glyph)).unwrap() as f32;
+            let width = (advance * 1000.0 / units).round();
+            assert!(text.contains(&format!("{glyph} [{width}]")));
+        }
+        assert!(text.contains("/FontFile2 6 0 R"));
+        assert!(text.contains("/BaseFont /DejaVuSans"));
+    }
+
+    #[test]
+    fn test_render() {
+        let Some(bytes) = font() else {
+            return;
+        };
+        let mut lines = vec![Line {
+            text: "АКТ ПРИЁМА-ПЕРЕДАЧИ".to_string(),
+            size: 14.0,
+            align: Align::Center,
+        }];
+        lines.extend((0..60).map(|n| line(&format!("Строка {n}"))));
+        let pdf = render(&lines, &bytes).unwrap();
+
+        assert!(pdf.starts_with(b"%PDF-1.7"));
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Encoding /Identity-H"));
+        assert!(text.contains("/Count 2"));
+        // startxref points at the xref table
+        let start: usize = text
+            .rsplit("startxref\n")
+            .next()
+            .and_then(|tail| tail.lines().next())
+            .and_then(|n| n.parse().ok())
+            .unwrap();
+        assert!(pdf[start..].starts_with(b"xref"));
+        assert!(render(&lines, b"not a font").is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use crate::clock::{local_now, tz, until_next};
+use crate::model::report::{ReportKind, send_report};
+use crate::sender::send_msg_to_admin;
+use cron::Schedule;
+use log::{debug, error, info};
+use std::str::FromStr;
+use teloxide::Bot;
+use tokio::time::sleep;
+
+pub fn do_work(bot: Bot, kind: ReportKind) {
+    if kind.schedule().is_empty() {
+        info!("{} is disabled", kind.title());
+        return;
+    }
+    tokio::spawn(async move {
+        let schedule = Schedule::from_str(kind.schedule()).expect("Schedule is not valid");
+        debug!("{} upcoming fire times:", kind.title());
+        for datetime in schedule.upcoming(tz()).take(5) {
+            debug!("-> {}", datetime);
+        }
+
+        loop {
+            if let Some(duration) = until_next(&schedule) {
+                sleep(duration).await;
+                let info = format!(
+                    "{}: {}",
+                    local_now().format("%d.%m.%Y %H:%M:%S"),
+                    kind.title()
+                );
+                debug!("{}", info);
+                send_msg_to_admin(&bot, &info).await;
+
+                if let Err(e) = send_report(kind).await {
+                    let msg = format!("Failed to send {} on email: {}", kind.title(), e);
+                    error!("{msg}");
+                    send_msg_to_admin(&bot, &msg).await;
+                }
+            }
+        }
+    });
+}
@@ -14,6 +14,52 @@ pub async fn send_msg_to_admin(bot: &Bot, msg: &str) {
     }
 }
 
+/// Telegram rejects messages longer than 4096 characters
+const MAX_MSG_LEN: usize = 4096;
+
+/// Splits a long text on line boundaries into telegram sized chunks
+pub fn split_message(msg: &str) -> Vec<String> {
+    let mut chunks: Vec<String> = vec![];
+    let mut current = String::new();
+    for line in msg.lines() {
+        let line: String = line.chars().take(MAX_MSG_LEN).collect();
+        if !current.is_empty() && current.chars().count() + line.chars().count() + 1 > MAX_MSG_LEN {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(&line);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Cuts a text that has to stay one message, e.g. when it is edited in place
+pub fn truncate_message(msg: &str) -> String {
+    let mut chunks = split_message(msg);
+    if chunks.len() <= 1 {
+        return chunks.pop().unwrap_or_default();
+    }
+    let more = "\n…";
+    let mut first: Vec<&str> = chunks[0].lines().collect();
+    while first.join("\n").chars().count() + more.chars().count() > MAX_MSG_LEN {
+        first.pop();
+    }
+    first.join("\n") + more
+}
+
+pub async fn send_msg_to_chat(bot: &Bot, chat_id: i64, msg: &str) {
+    for chunk in split_message(msg) {
+        let res = bot.send_message(ChatId(chat_id), &chunk).await;
+        if let Err(e) = res {
+            error!("Unable to send message: {chunk} to chat {chat_id}: {e}");
+        }
+    }
+}
+
 pub async fn send_msg_to_group(bot: &Bot, msg: &str) {
     let group_id = ChatId(config().TG_GROUP_ID);
     let res = bot.send_message(group_id, msg).await;
@@ -24,3 +70,23 @@ pub async fn send_msg_to_group(bot: &Bot, msg: &str) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_message() {
+        let line = "а".repeat(100);
+        let msg = vec![line.as_str(); 100].join("\n");
+        let chunks = split_message(&msg);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| c.chars().count() <= MAX_MSG_LEN));
+        assert_eq!(chunks.join("\n"), msg);
+
+        let truncated = truncate_message(&msg);
+        assert!(truncated.chars().count() <= MAX_MSG_LEN);
+        assert!(truncated.ends_with("\n…"));
+        assert_eq!(truncate_message("short"), "short");
+    }
+}
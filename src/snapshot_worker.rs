@@ -0,0 +1,31 @@
+use crate::clock::{tz, until_next};
+use crate::config::config;
+use crate::model::snapshot::take_daily_snapshot;
+use crate::sender::send_msg_to_admin;
+use cron::Schedule;
+use log::{debug, error};
+use std::str::FromStr;
+use teloxide::Bot;
+use tokio::time::sleep;
+
+pub fn do_work(bot: Bot) {
+    tokio::spawn(async move {
+        let schedule =
+            Schedule::from_str(&config().SNAPSHOT_SCHEDULE).expect("Schedule is not valid");
+        debug!("Snapshot upcoming fire times:");
+        for datetime in schedule.upcoming(tz()).take(5) {
+            debug!("-> {}", datetime);
+        }
+
+        loop {
+            if let Some(duration) = until_next(&schedule) {
+                sleep(duration).await;
+                if let Err(e) = take_daily_snapshot().await {
+                    let msg = format!("Failed to take the daily snapshot: {}", e);
+                    error!("{msg}");
+                    send_msg_to_admin(&bot, &msg).await;
+                }
+            }
+        }
+    });
+}
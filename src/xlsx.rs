@@ -1,71 +1,511 @@
 use crate::Result;
 use crate::adapters::mailer::data_types::DealInfo;
+use crate::clock::{self, to_local};
+use crate::layout::{Field, Layout};
+use crate::model::deal::DealData;
+use crate::model::digest::Digest;
+use crate::model::forecast::Forecast;
+use crate::model::report::ManagementReport;
+use chrono::{Datelike, NaiveDate};
 use rust_xlsxwriter::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Rows with fewer days left are highlighted in amber
+const NEAR_DEADLINE_DAYS: i64 = 7;
+const NEAR_DEADLINE_TITLE: &str = "До 7 дней";
+/// Excel limit for sheet names
+const SHEET_NAME_LEN: usize = 31;
+/// Sheet of a project whose name has nothing Excel accepts
+const DEFAULT_SHEET_NAME: &str = "Проект";
+const ALL_SHEET_NAME: &str = "Все объекты";
+const SUMMARY_SHEET_NAME: &str = "Сводка";
 
 pub struct Xlsx;
 
+#[derive(Default)]
+struct SummaryRow<'a> {
+    types: BTreeMap<&'a str, u32>,
+    total: u32,
+    overdue: u32,
+    near: u32,
+}
+
+impl<'a> SummaryRow<'a> {
+    fn add(&mut self, deal: &'a DealInfo, today: NaiveDate) {
+        *self.types.entry(&deal.property_type).or_default() += 1;
+        self.total += 1;
+        if deal.transferred_on.is_some() {
+            return;
+        }
+        let days_left = (deal.exp_on - today).num_days();
+        if days_left < 0 {
+            self.overdue += 1;
+        } else if days_left <= NEAR_DEADLINE_DAYS {
+            self.near += 1;
+        }
+    }
+}
+
+fn excel_date(date: NaiveDate) -> Result<ExcelDateTime> {
+    Ok(ExcelDateTime::from_ymd(
+        date.year() as u16,
+        date.month() as u8,
+        date.day() as u8,
+    )?)
+}
+
+/// Project names may contain characters Excel does not allow in sheet names,
+/// and Excel compares names case-insensitively, so a clash gets a numeric suffix
+fn sheet_name(project: &str, taken: &BTreeSet<String>) -> String {
+    let name: String = project
+        .chars()
+        .map(|c| match c {
+            '[' | ']' | ':' | '*' | '?' | '/' | '\\' => ' ',
+            c => c,
+        })
+        .take(SHEET_NAME_LEN)
+        .collect::<String>()
+        .trim_matches(['\'', ' '])
+        .to_string();
+    let name = if name.is_empty() {
+        DEFAULT_SHEET_NAME.to_string()
+    } else {
+        name
+    };
+    let mut candidate = name.clone();
+    let mut n = 1;
+    while taken.contains(&candidate.to_lowercase()) {
+        n += 1;
+        let suffix = format!(" ({n})");
+        let base: String = name
+            .chars()
+            .take(SHEET_NAME_LEN - suffix.chars().count())
+            .collect();
+        candidate = format!("{}{suffix}", base.trim_end());
+    }
+    candidate
+}
+
 impl Xlsx {
-    pub fn create(deals: Vec<DealInfo>) -> Result<Vec<u8>> {
-        // Create a new Excel file object.
+    /// Sheet of all deals first (with the default layout it is the one `/import`
+    /// reads), then a summary, a sheet per project and the forecast
+    pub fn create(
+        deals: Vec<DealInfo>,
+        forecast: Option<&Forecast>,
+        layout: &Layout,
+    ) -> Result<Vec<u8>> {
         let mut workbook = Workbook::new();
 
-        // Create some formats to use in the worksheet.
+        let all: Vec<&DealInfo> = deals.iter().collect();
+        Xlsx::add_deals(&mut workbook, ALL_SHEET_NAME, &all, layout)?;
+        Xlsx::add_summary(&mut workbook, &deals, clock::today())?;
+
+        let mut projects: BTreeMap<&str, Vec<&DealInfo>> = BTreeMap::new();
+        for deal in &deals {
+            projects.entry(&deal.project).or_default().push(deal);
+        }
+        let mut taken: BTreeSet<String> = [ALL_SHEET_NAME, SUMMARY_SHEET_NAME]
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect();
+        for (project, deals) in projects {
+            let name = sheet_name(project, &taken);
+            Xlsx::add_deals(&mut workbook, &name, &deals, layout)?;
+            taken.insert(name.to_lowercase());
+        }
+
+        if let Some(forecast) = forecast {
+            Xlsx::add_forecast(&mut workbook, forecast)?;
+        }
+
+        let buf = workbook.save_to_buffer()?;
+        Ok(buf)
+    }
+
+    /// Columns of `layout`: real dates, a live days remaining formula (blank once
+    /// transferred) and,
+    /// when the layout has one, overdue rows in red and the ones close to
+    /// the deadline in amber
+    fn add_deals(
+        workbook: &mut Workbook,
+        name: &str,
+        deals: &[&DealInfo],
+        layout: &Layout,
+    ) -> Result<()> {
         let header_format = Format::new().set_bold().set_align(FormatAlign::Center);
-        let row_format = Format::new().set_align(FormatAlign::Center);
-        // let date_format = Format::new().set_num_format("dd.mm.yyyy");
+        let formats: Vec<Format> = layout
+            .columns
+            .iter()
+            .map(|column| {
+                let format = Format::new().set_align(FormatAlign::Center);
+                match column.num_format() {
+                    Some(num_format) => format.set_num_format(num_format),
+                    None => format,
+                }
+            })
+            .collect();
+        let exp_column = layout
+            .position(Field::ExpDate)
+            .map(|col| column_number_to_name(col as ColNum));
 
-        // Add a worksheet to the workbook.
         let worksheet = workbook.add_worksheet();
+        worksheet.set_name(name)?;
+        for (col, column) in layout.columns.iter().enumerate() {
+            worksheet.set_column_width(col as ColNum, column.width)?;
+            worksheet.write_with_format(0, col as ColNum, &column.header, &header_format)?;
+        }
+
+        for (idx, deal) in deals.iter().enumerate() {
+            let row = (idx + 1) as RowNum;
+            for (col, (column, format)) in layout.columns.iter().zip(&formats).enumerate() {
+                let col = col as ColNum;
+                match column.field {
+                    Field::DealId => {
+                        worksheet.write_with_format(row, col, deal.deal_id as f64, format)?
+                    }
+                    Field::Buyer => worksheet.write_with_format(row, col, &deal.buyer, format)?,
+                    Field::Project => {
+                        worksheet.write_with_format(row, col, &deal.project, format)?
+                    }
+                    Field::House => worksheet.write_with_format(row, col, &deal.house, format)?,
+                    Field::PropertyType => {
+                        worksheet.write_with_format(row, col, &deal.property_type, format)?
+                    }
+                    Field::PropertyNum => {
+                        worksheet.write_with_format(row, col, deal.property_num, format)?
+                    }
+                    Field::Facing => worksheet.write_with_format(row, col, &deal.facing, format)?,
+                    Field::RegDate => worksheet.write_date_with_format(
+                        row,
+                        col,
+                        excel_date(deal.reg_on)?,
+                        format,
+                    )?,
+                    Field::ExpDate => worksheet.write_date_with_format(
+                        row,
+                        col,
+                        excel_date(deal.exp_on)?,
+                        format,
+                    )?,
+                    // nothing is left to count for a transferred deal
+                    Field::DaysLeft if deal.transferred_on.is_some() => {
+                        worksheet.write_blank(row, col, format)?
+                    }
+                    // Excel rows are 1-based
+                    Field::DaysLeft => {
+                        let formula = match &exp_column {
+                            Some(exp) => format!("={exp}{}-TODAY()", row + 1),
+                            None => format!(
+                                "=DATE({},{},{})-TODAY()",
+                                deal.exp_on.year(),
+                                deal.exp_on.month(),
+                                deal.exp_on.day()
+                            ),
+                        };
+                        worksheet.write_formula_with_format(row, col, formula.as_str(), format)?
+                    }
+                    Field::DaysLimit => {
+                        worksheet.write_with_format(row, col, deal.days_limit, format)?
+                    }
+                    Field::TransferredOn => match deal.transferred_on {
+                        Some(date) => {
+                            worksheet.write_date_with_format(row, col, excel_date(date)?, format)?
+                        }
+                        None => worksheet.write_blank(row, col, format)?,
+                    },
+                    Field::DaysLate => match deal.transferred_on {
+                        Some(date) if date > deal.exp_on => worksheet.write_with_format(
+                            row,
+                            col,
+                            (date - deal.exp_on).num_days() as f64,
+                            format,
+                        )?,
+                        _ => worksheet.write_blank(row, col, format)?,
+                    },
+                };
+            }
+        }
+
+        worksheet.set_freeze_panes(1, 0)?;
+        // a layout built in code may have no columns, there is nothing to filter then
+        let Some(last_col) = layout.columns.len().checked_sub(1) else {
+            return Ok(());
+        };
+        let last_row = deals.len() as RowNum;
+        let last_col = last_col as ColNum;
+        worksheet.autofilter(0, 0, last_row, last_col)?;
+        if let Some(col) = layout.position(Field::DaysLeft)
+            && !deals.is_empty()
+        {
+            let days = column_number_to_name(col as ColNum);
+            let overdue = ConditionalFormatFormula::new()
+                .set_rule(format!("=AND(ISNUMBER(${days}2),${days}2<0)").as_str())
+                .set_format(Format::new().set_background_color(Color::RGB(0xFFC7CE)));
+            let near = ConditionalFormatFormula::new()
+                .set_rule(
+                    format!("=AND(ISNUMBER(${days}2),${days}2>=0,${days}2<={NEAR_DEADLINE_DAYS})")
+                        .as_str(),
+                )
+                .set_format(Format::new().set_background_color(Color::RGB(0xFFEB9C)));
+            worksheet.add_conditional_format(1, 0, last_row, last_col, &overdue)?;
+            worksheet.add_conditional_format(1, 0, last_row, last_col, &near)?;
+        }
+        Ok(())
+    }
+
+    /// Deals per project and property type, overdue and near deadline as of `today`,
+    /// transferred deals are never overdue
+    fn add_summary(workbook: &mut Workbook, deals: &[DealInfo], today: NaiveDate) -> Result<()> {
+        let header_format = Format::new().set_bold().set_align(FormatAlign::Center);
+        let row_format = Format::new().set_align(FormatAlign::Center);
+
+        let types: BTreeSet<&str> = deals.iter().map(|d| d.property_type.as_str()).collect();
+        let mut counts: BTreeMap<&str, SummaryRow> = BTreeMap::new();
+        for deal in deals {
+            let row = counts.entry(&deal.project).or_default();
+            row.add(deal, today);
+        }
+        let mut total = SummaryRow::default();
+        for deal in deals {
+            total.add(deal, today);
+        }
 
-        // Set the column width for clarity.
+        let worksheet = workbook.add_worksheet();
+        worksheet.set_name(SUMMARY_SHEET_NAME)?;
         worksheet.set_column_width(0, 15)?;
-        worksheet.set_column_width(1, 10)?;
-        worksheet.set_column_width(2, 15)?;
-        worksheet.set_column_width(3, 22)?;
-        worksheet.set_column_width(4, 22)?;
-        worksheet.set_column_width(5, 22)?;
-        worksheet.set_column_width(6, 22)?;
-
-        // Write a string without formatting.
-        worksheet.write_with_format(0, 0, "Проект", &header_format)?;
-        worksheet.write_with_format(0, 1, "Дом", &header_format)?;
-        worksheet.write_with_format(0, 2, "Тип объекта", &header_format)?;
-        worksheet.write_with_format(0, 3, "Номер объекта", &header_format)?;
-        worksheet.write_with_format(0, 4, "Тип отделки", &header_format)?;
-        worksheet.write_with_format(0, 5, "Дата регистрации", &header_format)?;
-        worksheet.write_with_format(0, 6, "Передать объект до", &header_format)?;
+        let mut headers: Vec<&str> = vec!["Проект"];
+        headers.extend(types.iter());
+        headers.extend(["Всего", "Просрочено", NEAR_DEADLINE_TITLE]);
+        for (col, title) in headers.iter().enumerate() {
+            if col > 0 {
+                worksheet.set_column_width(col as ColNum, 15)?;
+            }
+            worksheet.write_with_format(0, col as ColNum, *title, &header_format)?;
+        }
+
+        let rows = counts
+            .iter()
+            .map(|(project, row)| (*project, row))
+            .chain(std::iter::once(("Итого", &total)));
+        for (idx, (project, summary)) in rows.enumerate() {
+            let row = (idx + 1) as RowNum;
+            let format = if project == "Итого" {
+                &header_format
+            } else {
+                &row_format
+            };
+            worksheet.write_with_format(row, 0, project, format)?;
+            let mut values: Vec<u32> = types
+                .iter()
+                .map(|t| summary.types.get(t).copied().unwrap_or_default())
+                .collect();
+            values.extend([summary.total, summary.overdue, summary.near]);
+            for (col, value) in values.into_iter().enumerate() {
+                worksheet.write_with_format(row, (col + 1) as ColNum, value, format)?;
+            }
+        }
+        worksheet.set_freeze_panes(1, 1)?;
+        Ok(())
+    }
+
+    /// One sheet per report category
+    pub fn create_report(report: &ManagementReport, layout: &Layout) -> Result<Vec<u8>> {
+        let mut workbook = Workbook::new();
+        for category in report.categories() {
+            let deals: Vec<DealInfo> = category.deals.iter().cloned().map(Into::into).collect();
+            let deals: Vec<&DealInfo> = deals.iter().collect();
+            Xlsx::add_deals(&mut workbook, category.title, &deals, layout)?;
+        }
 
-        for (idx, deal) in deals.iter().enumerate() {
-            worksheet.write_with_format((idx + 1) as RowNum, 0, &deal.project, &row_format)?;
-            worksheet.write_with_format((idx + 1) as RowNum, 1, &deal.house, &row_format)?;
-            worksheet.write_with_format(
-                (idx + 1) as RowNum,
-                2,
-                &deal.property_type,
-                &row_format,
-            )?;
-            worksheet.write_with_format((idx + 1) as RowNum, 3, deal.property_num, &row_format)?;
-            worksheet.write_with_format((idx + 1) as RowNum, 4, &deal.facing, &row_format)?;
-            worksheet.write_with_format((idx + 1) as RowNum, 5, &deal.reg_date, &row_format)?;
-            worksheet.write_with_format((idx + 1) as RowNum, 6, &deal.exp_date, &row_format)?;
-        }
-
-        // // Write a date.
-        // let date = ExcelDateTime::parse_from_str("2023-1-25")?;
-        // worksheet.write_with_format(0, 7, &date, &date_format)?;
-
-        // Save the file to disk.
         let buf = workbook.save_to_buffer()?;
+        Ok(buf)
+    }
 
+    /// A sheet per digest section, the summary of the deals in work last
+    pub fn create_digest(digest: &Digest, layout: &Layout) -> Result<Vec<u8>> {
+        let mut workbook = Workbook::new();
+        let info = |deals: &[DealData]| -> Vec<DealInfo> {
+            deals.iter().cloned().map(Into::into).collect()
+        };
+        let in_work = info(&digest.in_work);
+        let sheets = [
+            ("Новые продажи", info(&digest.new_sales)),
+            ("Передано", info(&digest.transferred)),
+            ("Сроки передачи", info(&digest.deadline_deals())),
+            ("В работе", in_work.clone()),
+        ];
+        for (name, deals) in &sheets {
+            let deals: Vec<&DealInfo> = deals.iter().collect();
+            Xlsx::add_deals(&mut workbook, name, &deals, layout)?;
+        }
+        Xlsx::add_summary(&mut workbook, &in_work, to_local(&digest.until).date())?;
+
+        let buf = workbook.save_to_buffer()?;
         Ok(buf)
     }
+
+    /// One row per day, one column per house, days over capacity are highlighted
+    fn add_forecast(workbook: &mut Workbook, forecast: &Forecast) -> Result<()> {
+        let header_format = Format::new().set_bold().set_align(FormatAlign::Center);
+        let row_format = Format::new().set_align(FormatAlign::Center);
+        let overload_format = Format::new()
+            .set_align(FormatAlign::Center)
+            .set_background_color(Color::RGB(0xFFC7CE));
+
+        let worksheet = workbook.add_worksheet();
+        worksheet.set_name("Прогноз")?;
+        worksheet.set_column_width(0, 15)?;
+        worksheet.set_column_width(1, 10)?;
+
+        let houses = forecast.houses();
+        worksheet.write_with_format(0, 0, "Дата", &header_format)?;
+        worksheet.write_with_format(0, 1, "Всего", &header_format)?;
+        for (idx, (project, house)) in houses.iter().enumerate() {
+            let col = (idx + 2) as ColNum;
+            worksheet.set_column_width(col, 22)?;
+            worksheet.write_with_format(0, col, format!("{project}, {house}"), &header_format)?;
+        }
+
+        for (idx, day) in forecast.days.iter().enumerate() {
+            let row = (idx + 1) as RowNum;
+            let format = if forecast.is_overloaded(day) {
+                &overload_format
+            } else {
+                &row_format
+            };
+            worksheet.write_with_format(row, 0, day.date.format("%d.%m.%Y").to_string(), format)?;
+            worksheet.write_with_format(row, 1, day.total() as u32, format)?;
+            for (col, key) in houses.iter().enumerate() {
+                let count = day.houses.get(key).copied().unwrap_or_default();
+                worksheet.write_with_format(row, (col + 2) as ColNum, count as u32, format)?;
+            }
+        }
+
+        let row = (forecast.days.len() + 1) as RowNum;
+        worksheet.write_with_format(row, 0, "Просрочено", &header_format)?;
+        worksheet.write_with_format(row, 1, forecast.overdue as u32, &row_format)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::layout::{DEFAULT_LAYOUT, REPORT_LAYOUT, layouts};
+    use crate::model::report::ReportKind;
+    use calamine::{Data, Reader};
+
+    fn default_layout() -> &'static Layout {
+        layouts().get("", DEFAULT_LAYOUT).unwrap()
+    }
+
     #[test]
     fn test_create_worksheet() {
-        Xlsx::create(vec![]).unwrap();
+        Xlsx::create(vec![], None, default_layout()).unwrap();
+    }
+
+    #[test]
+    fn test_report_workbook() {
+        let today = chrono::NaiveDate::from_ymd_opt(2025, 11, 5).unwrap();
+        let report = ManagementReport::build(ReportKind::Weekly, today, today, &[], vec![]);
+        Xlsx::create_report(&report, layouts().get("", REPORT_LAYOUT).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_rich_workbook() {
+        let deal = |project: &str, property_num: i32| -> DealInfo {
+            (&DealData::builder()
+                .project(project)
+                .property_num(property_num)
+                .build_lead())
+                .into()
+        };
+        let deals = vec![
+            deal("ЖК Формат", 1),
+            deal("DNS Сити", 2),
+            deal("DNS Сити", 3),
+        ];
+        let buf = Xlsx::create(deals.clone(), None, default_layout()).unwrap();
+
+        let mut workbook: calamine::Xlsx<_> =
+            calamine::open_workbook_from_rs(std::io::Cursor::new(buf)).unwrap();
+        assert_eq!(
+            workbook.sheet_names(),
+            vec!["Все объекты", "Сводка", "DNS Сити", "ЖК Формат"]
+        );
+        let all = workbook.worksheet_range("Все объекты").unwrap();
+        assert_eq!(all.get_size(), (4, 8));
+        assert!(matches!(all.get((1, 5)), Some(Data::DateTime(_))));
+        let summary = workbook.worksheet_range("Сводка").unwrap();
+        assert_eq!(
+            summary.get((3, 0)),
+            Some(&Data::String("Итого".to_string()))
+        );
+        assert_eq!(summary.get((3, 2)), Some(&Data::Float(3.0)));
+        let dns = workbook.worksheet_range("DNS Сити").unwrap();
+        assert_eq!(dns.get_size().0, 3);
+
+        let layout: Layout = serde_json::from_str(
+            r#"{"columns": [
+                {"header": "ID сделки", "field": "deal_id", "width": 12},
+                {"header": "Номер", "field": "property_num"},
+                {"header": "Срок", "field": "days_left"}
+            ]}"#,
+        )
+        .unwrap();
+        let buf = Xlsx::create(deals, None, &layout).unwrap();
+        let mut workbook: calamine::Xlsx<_> =
+            calamine::open_workbook_from_rs(std::io::Cursor::new(buf)).unwrap();
+        let all = workbook.worksheet_range("Все объекты").unwrap();
+        assert_eq!(all.get_size(), (4, 3));
+        assert_eq!(
+            all.get((0, 0)),
+            Some(&Data::String("ID сделки".to_string()))
+        );
+        assert_eq!(all.get((1, 1)), Some(&Data::Float(1.0)));
+    }
+
+    #[test]
+    fn test_sheet_name() {
+        let taken = BTreeSet::new();
+        assert_eq!(sheet_name("ЖК [Формат]/2", &taken), "ЖК  Формат  2");
+        assert_eq!(
+            sheet_name(&"а".repeat(40), &taken).chars().count(),
+            SHEET_NAME_LEN
+        );
+        assert_eq!(sheet_name("[?]", &taken), DEFAULT_SHEET_NAME);
+
+        let taken: BTreeSet<String> = ["сводка".to_string(), "а".repeat(SHEET_NAME_LEN)].into();
+        assert_eq!(sheet_name("СВОДКА", &taken), "СВОДКА (2)");
+        let name = sheet_name(&"а".repeat(40), &taken);
+        assert_eq!(name, format!("{} (2)", "а".repeat(SHEET_NAME_LEN - 4)));
+    }
+
+    #[test]
+    fn test_clashing_sheet_names() {
+        let deal = |project: &str| -> DealInfo {
+            (&DealData::builder().project(project).build_lead()).into()
+        };
+        let deals = vec![deal("ЖК: Формат"), deal("ЖК/ Формат"), deal("???")];
+        let buf = Xlsx::create(deals, None, &Layout { columns: vec![] }).unwrap();
+        let workbook: calamine::Xlsx<_> =
+            calamine::open_workbook_from_rs(std::io::Cursor::new(buf)).unwrap();
+        assert_eq!(
+            workbook.sheet_names(),
+            vec![
+                "Все объекты",
+                "Сводка",
+                "Проект",
+                "ЖК  Формат",
+                "ЖК  Формат (2)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forecast_worksheet() {
+        let today = chrono::NaiveDate::from_ymd_opt(2025, 11, 5).unwrap();
+        let forecast = Forecast::build(&[], today, 2, 5);
+        Xlsx::create(vec![], Some(&forecast), default_layout()).unwrap();
     }
 }